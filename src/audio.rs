@@ -1,65 +1,123 @@
+pub mod eq;
+mod flac;
 pub mod post_process;
-use crate::consts::SAMPLE_RATE;
+use crate::consts::{BitDepth, HIFI_CONFIG, ResampleQuality, SAMPLE_RATE};
 use anyhow::{anyhow, Result};
 use hound::{SampleFormat, WavSpec, WavWriter};
 use rubato::{Resampler, SincFixedIn, WindowFunction, SincInterpolationParameters, SincInterpolationType};
-use std::{fs::File, path::{Path, PathBuf}};
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use tracing::warn;
+use std::{
+    borrow::Cow, fs::{self, File}, io::{self, BufWriter, Cursor, Seek, SeekFrom, Write}, num::NonZeroUsize, ops::Range,
+    path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime,
+};
 use symphonia::{
     core::{
         audio::{SampleBuffer, SignalSpec},
-        io::MediaSourceStream,
-        probe::Hint,
+        io::{MediaSource, MediaSourceStream},
+        probe::{Hint, ProbeResult},
     },
     default::{get_codecs, get_probe},
 };
 const I16_MAX: f64 = i16::MAX as f64;
-fn resample_audio(audio: &[f64], in_sr: u32, out_sr: u32) -> Result<Vec<f64>> {
+const I24_MAX: f64 = 8_388_607.0;
+fn sinc_parameters(quality: ResampleQuality) -> SincInterpolationParameters {
+    let (sinc_len, f_cutoff, oversampling_factor, interpolation, window) = match quality {
+        ResampleQuality::Fast => (32, 0.9, 256, SincInterpolationType::Linear, WindowFunction::Hann),
+        ResampleQuality::Balanced => (64, 0.925, 128, SincInterpolationType::Quadratic, WindowFunction::BlackmanHarris2),
+        ResampleQuality::High => (128, 0.95, 64, SincInterpolationType::Cubic, WindowFunction::Hann),
+    };
+    SincInterpolationParameters { sinc_len, f_cutoff, oversampling_factor, interpolation, window }
+}
+fn resample_audio(audio: &[f64], in_sr: u32, out_sr: u32, quality: ResampleQuality) -> Result<Vec<f64>> {
     let ratio = out_sr as f64 / in_sr as f64;
-    let mut res = Vec::with_capacity((audio.len() as f64 * ratio).ceil() as usize);
+    let expected = (audio.len() as f64 * ratio).round() as usize;
     let mut resampler = SincFixedIn::<f64>::new(
         ratio,
         2.0,
-        SincInterpolationParameters {
-            sinc_len: 128,
-            f_cutoff: 0.95,
-            oversampling_factor: 64,
-            interpolation: SincInterpolationType::Cubic,
-            window: WindowFunction::Hann,
-        },
+        sinc_parameters(quality),
         256,
         1,
     )?;
-    for chunk in audio.chunks(256) {
-        let mut input = Vec::from(chunk);
-        input.resize(256, 0.0);
-        let proc_res = resampler.process(&[&input], None)?;
-        let output = proc_res.get(0).unwrap();
-        res.extend_from_slice(output);
-    }
-    let final_proc_res = resampler.process(&[&[]], None)?;
-    let final_output = final_proc_res.get(0).unwrap();
-    res.extend_from_slice(final_output);
+    let mut res = Vec::with_capacity(expected + resampler.output_frames_max());
+    let mut pos = 0;
+    while audio.len() - pos >= resampler.input_frames_next() {
+        let frames = resampler.input_frames_next();
+        let proc_res = resampler.process(&[&audio[pos..pos + frames]], None)?;
+        res.extend_from_slice(&proc_res[0]);
+        pos += frames;
+    }
+    if pos < audio.len() {
+        let proc_res = resampler.process_partial(Some(&[&audio[pos..]]), None)?;
+        res.extend_from_slice(&proc_res[0]);
+    }
+    // SincFixedIn starts reading half a filter before the first input frame, so its output is
+    // already centred on the input and the `output_delay()` it reports must not be dropped again.
+    // Flush with zeros until the filter tail has covered the last input frame, then drop the padding.
+    while res.len() < expected {
+        let proc_res = resampler.process_partial(None::<&[&[f64]]>, None)?;
+        res.extend_from_slice(&proc_res[0]);
+    }
+    res.truncate(expected);
     Ok(res)
 }
-pub fn read_audio<P: AsRef<Path>>(path: P) -> Result<Vec<f64>> {
-    let mut path = PathBuf::from(path.as_ref());
-    if !path.exists() {
-        let common_extensions = ["wav", "flac", "ogg", "mp3", "aac"];
-        let found = common_extensions.iter().find(|&&ext| {
-            path.set_extension(ext);
-            path.exists()
-        });
-        if found.is_none() {
-            return Err(anyhow!(
-                "No supported audio file found (tried extensions: {:?})",
-                common_extensions
-            ));
-        }
+fn resolve_input(path: &Path) -> Result<PathBuf> {
+    find_input(path, &HIFI_CONFIG.input_extension_priority)
+}
+/// Returns `path` if it exists, otherwise the first `path.with_extension(ext)` that does.
+fn find_input(path: &Path, extensions: &[String]) -> Result<PathBuf> {
+    if path.exists() {
+        return Ok(path.to_path_buf());
+    }
+    extensions.iter()
+        .map(|ext| path.with_extension(ext))
+        .find(|candidate| candidate.exists())
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No supported audio file found (tried extensions: {:?})", extensions),
+        ).into())
+}
+fn probe_source(source: Box<dyn MediaSource>) -> Result<ProbeResult> {
+    let mss = MediaSourceStream::new(source, Default::default());
+    Ok(get_probe().format(&Hint::new(), mss, &Default::default(), &Default::default())?)
+}
+fn probe(path: &Path) -> Result<ProbeResult> {
+    probe_source(Box::new(File::open(resolve_input(path)?)?))
+}
+pub fn audio_channel_count<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let probed = probe(path.as_ref())?;
+    let track = probed
+        .format
+        .default_track()
+        .ok_or_else(|| anyhow!("No audio track found"))?;
+    Ok(track.codec_params.channels.map_or(1, |c| c.count()))
+}
+type DecodedKey = (PathBuf, SystemTime, bool);
+type DecodedCache = LruCache<DecodedKey, Arc<Vec<Vec<f64>>>>;
+/// Decoded, resampled input keyed by path and mtime, so flag sweeps that miss the feature
+/// cache skip the symphonia decode and resample.
+static DECODED_AUDIO: Lazy<Mutex<DecodedCache>> = Lazy::new(|| {
+    Mutex::new(LruCache::new(NonZeroUsize::new(HIFI_CONFIG.decoded_cache_entries).unwrap_or(NonZeroUsize::MIN)))
+});
+/// Returns one buffer per channel at `SAMPLE_RATE`, or a single mono mix when `downmix` is set.
+fn decode_audio(path: &Path, downmix: bool) -> Result<Vec<Vec<f64>>> {
+    if HIFI_CONFIG.decoded_cache_entries == 0 {
+        return decode_audio_uncached(path, downmix);
+    }
+    let path = resolve_input(path)?;
+    let key = (path.clone(), fs::metadata(&path)?.modified()?, downmix);
+    if let Some(audio) = DECODED_AUDIO.lock().unwrap().get(&key) {
+        return Ok(audio.as_ref().clone());
     }
-    let source = File::open(&path)?;
-    let mss = MediaSourceStream::new(Box::new(source), Default::default());
-    let mut probed = get_probe()
-        .format(&Hint::new(), mss, &Default::default(), &Default::default())?;
+    let audio = decode_audio_uncached(&path, downmix)?;
+    DECODED_AUDIO.lock().unwrap().put(key, Arc::new(audio.clone()));
+    Ok(audio)
+}
+fn decode_audio_uncached(path: &Path, downmix: bool) -> Result<Vec<Vec<f64>>> {
+    decode_probed(probe(path)?, downmix)
+}
+fn decode_probed(mut probed: ProbeResult, downmix: bool) -> Result<Vec<Vec<f64>>> {
     let track = probed
         .format
         .default_track()
@@ -71,7 +129,7 @@ pub fn read_audio<P: AsRef<Path>>(path: P) -> Result<Vec<f64>> {
     let channels = spec.channels.count();
     let mut decoder = get_codecs()
         .make(&track.codec_params, &Default::default())?;
-    let mut audio = Vec::with_capacity(409600);
+    let mut audio = vec![Vec::with_capacity(409600); if downmix { 1 } else { channels }];
     let mut sample_buf = SampleBuffer::<f64>::new(4096, spec);
     let track_id = track.id;
     while let Ok(packet) = probed.format.next_packet() {
@@ -82,40 +140,227 @@ pub fn read_audio<P: AsRef<Path>>(path: P) -> Result<Vec<f64>> {
             sample_buf.copy_interleaved_ref(decoded);
             let samples = sample_buf.samples();
             if channels == 1 {
-                audio.extend_from_slice(samples);
+                audio[0].extend_from_slice(samples);
+            } else if downmix {
+                audio[0].extend(downmix_frames(samples, channels));
             } else {
-                audio.extend(samples.chunks(channels).map(|frame| {
-                    frame.iter().sum::<f64>() / channels as f64
-                }));
+                samples.chunks(channels).for_each(|frame| {
+                    audio.iter_mut().zip(frame).for_each(|(ch, &s)| ch.push(s));
+                });
             }
         }
     }
-    if spec.rate == SAMPLE_RATE {
-        Ok(audio)
+    let mut audio = if spec.rate == SAMPLE_RATE {
+        audio
     } else {
-        resample_audio(&audio, spec.rate, SAMPLE_RATE)
+        audio.iter()
+            .map(|ch| resample_audio(ch, spec.rate, SAMPLE_RATE, HIFI_CONFIG.resample_quality))
+            .collect::<Result<Vec<_>>>()?
+    };
+    if downmix {
+        check_clipping(&mut audio[0], HIFI_CONFIG.limit_input_peak);
     }
+    Ok(audio)
 }
-pub fn write_audio<P: AsRef<Path>>(path: P, audio: &[f64]) -> Result<()> {
+fn downmix_frames(interleaved: &[f64], channels: usize) -> impl Iterator<Item = f64> + '_ {
+    interleaved.chunks(channels).map(move |frame| frame.iter().sum::<f64>() / channels as f64)
+}
+/// Later stages assume roughly [-1, 1]. Returns the peak when the mono input exceeds it,
+/// scaling the buffer back to full scale when `limit` is set.
+fn check_clipping(audio: &mut [f64], limit: bool) -> Option<f64> {
+    let peak = audio.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+    if peak <= 1.0 {
+        return None;
+    }
+    warn!("Input audio clips after downmix (peak {:.3}){}", peak, if limit { ", scaling to 1.0" } else { "" });
+    if limit {
+        audio.iter_mut().for_each(|x| *x /= peak);
+    }
+    Some(peak)
+}
+pub fn read_audio<P: AsRef<Path>>(path: P) -> Result<Vec<f64>> {
+    Ok(decode_audio(path.as_ref(), true)?.swap_remove(0))
+}
+pub fn read_audio_channels<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<f64>>> {
+    decode_audio(path.as_ref(), false)
+}
+/// Like [`read_audio`], for encoded audio already in memory (e.g. an uploaded file).
+#[allow(dead_code)] // No upload endpoint uses this yet.
+pub fn read_audio_from_bytes(bytes: &[u8]) -> Result<Vec<f64>> {
+    let probed = probe_source(Box::new(Cursor::new(bytes.to_vec())))?;
+    Ok(decode_probed(probed, true)?.swap_remove(0))
+}
+#[derive(Debug, Clone, Copy)]
+struct OutputSpec {
+    sample_rate: u32,
+    depth: BitDepth,
+    dither: bool,
+}
+impl OutputSpec {
+    fn from_config() -> Self {
+        Self {
+            sample_rate: HIFI_CONFIG.output_sample_rate,
+            depth: HIFI_CONFIG.output_bit_depth,
+            dither: HIFI_CONFIG.dither,
+        }
+    }
+}
+/// Triangular (TPDF) dither in (-1, 1) LSB. Uses a fixed-seed xorshift so renders stay reproducible.
+struct TpdfDither(u64);
+impl TpdfDither {
+    fn new() -> Self {
+        Self(0x9E37_79B9_7F4A_7C15)
+    }
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn next(&mut self) -> f64 {
+        self.uniform() - self.uniform()
+    }
+}
+fn quantize_i16(audio: &[f64], dither: bool) -> Vec<i16> {
+    if !dither {
+        return audio.iter().map(|&s| (s * I16_MAX) as i16).collect();
+    }
+    let mut tpdf = TpdfDither::new();
+    audio.iter().map(|&s| (s * I16_MAX + tpdf.next()) as i16).collect()
+}
+fn quantize_i24(audio: &[f64]) -> Vec<i32> {
+    audio.iter().map(|&s| (s.clamp(-1.0, 1.0) * I24_MAX) as i32).collect()
+}
+/// `audio` is interleaved when `channels > 1`.
+fn write_wav<W: Write + Seek>(writer: W, audio: &[f64], channels: u16, spec: OutputSpec) -> Result<()> {
+    let (bits_per_sample, sample_format) = match spec.depth {
+        BitDepth::Int16 => (16, SampleFormat::Int),
+        BitDepth::Int24 => (24, SampleFormat::Int),
+        BitDepth::Float32 => (32, SampleFormat::Float),
+    };
     let mut writer = WavWriter::new(
-        File::create(path.as_ref())?,
+        writer,
         WavSpec {
-            channels: 1,
-            sample_rate: SAMPLE_RATE,
-            bits_per_sample: 16,
-            sample_format: SampleFormat::Int
+            channels,
+            sample_rate: spec.sample_rate,
+            bits_per_sample,
+            sample_format
         },
     )?;
-    audio.iter()
-        .map(|&s| (s * I16_MAX) as i16)
-        .try_for_each(|sample| writer.write_sample(sample))?;
+    match spec.depth {
+        BitDepth::Int16 => quantize_i16(audio, spec.dither)
+            .into_iter()
+            .try_for_each(|sample| writer.write_sample(sample))?,
+        BitDepth::Int24 => quantize_i24(audio)
+            .into_iter()
+            .try_for_each(|sample| writer.write_sample(sample))?,
+        BitDepth::Float32 => audio.iter()
+            .try_for_each(|&s| writer.write_sample(s as f32))?,
+    }
     writer.finalize()?;
     Ok(())
 }
+/// Appends a `smpl` chunk with one forward loop over `region` (inclusive end, as the spec
+/// requires) and patches the RIFF size. hound has no API for this, so it is written by hand.
+fn append_smpl_chunk<W: Write + Seek>(mut writer: W, sample_rate: u32, region: Range<usize>) -> Result<()> {
+    let fields: [u32; 15] = [
+        0, 0, 1_000_000_000 / sample_rate, 60, 0, 0, 0, 1, 0,
+        0, 0, region.start as u32, region.end.saturating_sub(1) as u32, 0, 0,
+    ];
+    writer.seek(SeekFrom::End(0))?;
+    writer.write_all(b"smpl")?;
+    writer.write_all(&(fields.len() as u32 * 4).to_le_bytes())?;
+    fields.iter().try_for_each(|f| writer.write_all(&f.to_le_bytes()))?;
+    let riff_len = writer.stream_position()? - 8;
+    writer.seek(SeekFrom::Start(4))?;
+    writer.write_all(&(riff_len as u32).to_le_bytes())?;
+    writer.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+type OutputAudio<'a> = (Cow<'a, [Vec<f64>]>, Option<Range<usize>>);
+/// Rendering always runs at `SAMPLE_RATE`; only the written file is converted.
+fn to_output_rate(
+    channels: &[Vec<f64>],
+    sample_rate: u32,
+    loop_region: Option<Range<usize>>,
+) -> Result<OutputAudio<'_>> {
+    if sample_rate == SAMPLE_RATE {
+        return Ok((Cow::Borrowed(channels), loop_region));
+    }
+    let ratio = sample_rate as f64 / SAMPLE_RATE as f64;
+    let resampled = channels.iter()
+        .map(|ch| resample_audio(ch, SAMPLE_RATE, sample_rate, HIFI_CONFIG.resample_quality))
+        .collect::<Result<Vec<_>>>()?;
+    let len = resampled.first().map_or(0, Vec::len);
+    let scale = |i: usize| ((i as f64 * ratio).round() as usize).min(len);
+    let loop_region = loop_region.map(|r| scale(r.start)..scale(r.end));
+    Ok((Cow::Owned(resampled), loop_region))
+}
+fn interleave(channels: &[Vec<f64>]) -> Cow<'_, [f64]> {
+    match channels {
+        [mono] => Cow::Borrowed(mono),
+        _ => {
+            let len = channels.iter().map(Vec::len).min().unwrap_or(0);
+            Cow::Owned((0..len).flat_map(|i| channels.iter().map(move |ch| ch[i])).collect())
+        }
+    }
+}
+fn write_output<W: Write + Seek>(
+    mut writer: W,
+    channels: &[Vec<f64>],
+    spec: OutputSpec,
+    loop_region: Option<Range<usize>>,
+) -> Result<()> {
+    let (channels, loop_region) = to_output_rate(channels, spec.sample_rate, loop_region)?;
+    write_wav(&mut writer, &interleave(&channels), channels.len() as u16, spec)?;
+    match loop_region {
+        Some(region) if region.start < region.end => append_smpl_chunk(&mut writer, spec.sample_rate, region),
+        _ => Ok(()),
+    }
+}
+/// FLAC is integer-only, so 32-bit float output is stored as 24-bit.
+fn write_flac_output<W: Write>(
+    writer: W,
+    channels: &[Vec<f64>],
+    spec: OutputSpec,
+    loop_region: Option<Range<usize>>,
+) -> Result<()> {
+    let (channels, loop_region) = to_output_rate(channels, spec.sample_rate, loop_region)?;
+    let bits = if spec.depth == BitDepth::Int16 { 16 } else { 24 };
+    let samples: Vec<Vec<i32>> = channels.iter()
+        .map(|ch| match spec.depth {
+            BitDepth::Int16 => quantize_i16(ch, spec.dither).into_iter().map(i32::from).collect(),
+            BitDepth::Int24 | BitDepth::Float32 => quantize_i24(ch),
+        })
+        .collect();
+    flac::write_flac(writer, &samples, spec.sample_rate, bits, loop_region)
+}
+/// Output extensions [`write_audio`] knows how to encode.
+pub const OUTPUT_EXTENSIONS: &[&str] = &["wav", "flac"];
+/// Writes one buffer per channel. The container follows the output extension:
+/// `.flac` writes FLAC, anything else WAV.
+pub fn write_audio<P: AsRef<Path>>(path: P, channels: &[Vec<f64>], loop_region: Option<Range<usize>>) -> Result<()> {
+    let path = path.as_ref();
+    let file = File::create(path)?;
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("flac")) {
+        let mut writer = BufWriter::new(file);
+        write_flac_output(&mut writer, channels, OutputSpec::from_config(), loop_region)?;
+        writer.flush()?;
+        return Ok(());
+    }
+    write_output(file, channels, OutputSpec::from_config(), loop_region)
+}
+pub fn encode_wav(channels: &[Vec<f64>]) -> Result<Vec<u8>> {
+    let mut cursor = Cursor::new(Vec::new());
+    write_output(&mut cursor, channels, OutputSpec::from_config(), None)?;
+    Ok(cursor.into_inner())
+}
 #[cfg(test)]
 mod tests {
-    use super::{read_audio, write_audio};
-    use std::{path::Path, time::Instant};
+    use super::{check_clipping, downmix_frames, find_input, quantize_i16, read_audio_from_bytes, resample_audio, DECODED_AUDIO, read_audio, read_audio_channels, write_audio, write_flac_output, write_output, write_wav, OutputSpec};
+    use crate::consts::{ResampleQuality, SAMPLE_RATE};
+    use crate::consts::BitDepth;
+    use std::{io::Cursor, path::Path, time::Instant};
     #[test]
     fn test_read_write() {
         let test_paths = ["test/01.wav", "test/pjs001.wav"]
@@ -129,11 +374,219 @@ mod tests {
             if path.exists() {
                 let audio = read_audio(path).expect("Read failed");
                 println!("Read time: {:.2?}", now.elapsed());
-                write_audio(&out_path, &audio).expect("Write failed");
+                write_audio(&out_path, &[audio], None).expect("Write failed");
                 println!("Write time: {:.2?}", now.elapsed());
             } else {
                 println!("File not found: {:?} (skipped)", path.as_os_str());
             }
         }
     }
+    #[test]
+    fn test_input_extension_priority() {
+        let dir = std::env::temp_dir().join("hifi_ext_priority");
+        std::fs::create_dir_all(&dir).unwrap();
+        for ext in ["flac", "ogg"] {
+            std::fs::write(dir.join(format!("a.{}", ext)), b"").unwrap();
+        }
+        let order = |exts: &[&str]| exts.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let requested = dir.join("a.wav");
+        assert_eq!(find_input(&requested, &order(&["wav", "ogg", "flac"])).unwrap(), dir.join("a.ogg"));
+        assert_eq!(find_input(&requested, &order(&["flac", "ogg"])).unwrap(), dir.join("a.flac"));
+        assert_eq!(find_input(&dir.join("a"), &order(&["ogg"])).unwrap(), dir.join("a.ogg"));
+        assert_eq!(find_input(&dir.join("a.ogg"), &order(&["flac"])).unwrap(), dir.join("a.ogg"));
+        assert!(find_input(&requested, &order(&["mp3"])).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_read_from_bytes() {
+        let audio: Vec<f64> = (0..3000).map(|i| 0.3 * (i as f64 * 0.05).sin()).collect();
+        let mut cursor = Cursor::new(Vec::new());
+        write_wav(&mut cursor, &audio, 1, spec(22050, BitDepth::Float32)).unwrap();
+        let decoded = read_audio_from_bytes(&cursor.into_inner()).unwrap();
+        assert_eq!(decoded.len(), 6000);
+        assert!(read_audio_from_bytes(b"not audio").is_err());
+    }
+    #[test]
+    fn test_downmix_clipping() {
+        let stereo = [1.6, 1.2, 0.2, 0.4, -1.5, -1.1];
+        let mut mono: Vec<f64> = downmix_frames(&stereo, 2).collect();
+        assert!((check_clipping(&mut mono.clone(), false).unwrap() - 1.4).abs() < 1e-12);
+        assert!(check_clipping(&mut mono, true).is_some());
+        assert!((mono[0] - 1.0).abs() < 1e-12);
+        assert!(check_clipping(&mut mono, true).is_none());
+    }
+    #[test]
+    fn test_decoded_audio_cache() {
+        let path = std::env::temp_dir().join("hifi_decoded_cache.wav");
+        write_audio(&path, &[vec![0.25; 1000]], None).unwrap();
+        let first = read_audio(&path).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        assert!(DECODED_AUDIO.lock().unwrap().contains(&(path.clone(), modified, true)));
+        assert_eq!(read_audio(&path).unwrap(), first);
+        write_audio(&path, &[vec![0.25; 500]], None).unwrap();
+        // Pin a distinct mtime instead of relying on the filesystem clock ticking between writes.
+        let rewritten = modified + std::time::Duration::from_secs(1);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(rewritten).unwrap();
+        assert_eq!(read_audio(&path).unwrap().len(), 500);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_resample_sweep_tail() {
+        let rms = |x: &[f64]| (x.iter().map(|v| v * v).sum::<f64>() / x.len() as f64).sqrt();
+        for (in_sr, out_sr, len) in [(48000, 44100, 48_013), (22050, 44100, 22_077), (44100, 48000, 44_100)] {
+            let sweep: Vec<f64> = (0..len)
+                .map(|i| {
+                    let t = i as f64 / in_sr as f64;
+                    0.5 * (2.0 * std::f64::consts::PI * (100.0 * t + 2000.0 * t * t)).sin()
+                })
+                .collect();
+            let out = resample_audio(&sweep, in_sr, out_sr, ResampleQuality::High).unwrap();
+            let expected = (len as f64 * out_sr as f64 / in_sr as f64).round() as usize;
+            assert_eq!(out.len(), expected);
+            let tail_in = rms(&sweep[len - 2048..]);
+            let tail_out = rms(&out[expected - 2048 * out_sr as usize / in_sr as usize..]);
+            assert!((tail_out / tail_in - 1.0).abs() < 0.05, "{} -> {}: {} vs {}", in_sr, out_sr, tail_out, tail_in);
+        }
+    }
+    #[test]
+    fn test_resample_impulse_alignment() {
+        for (in_sr, out_sr) in [(48000, 44100), (22050, 44100), (44100, 48000)] {
+            for quality in [ResampleQuality::Fast, ResampleQuality::Balanced, ResampleQuality::High] {
+                let k = 10000;
+                let mut impulse = vec![0.0; 30000];
+                impulse[k] = 1.0;
+                let out = resample_audio(&impulse, in_sr, out_sr, quality).unwrap();
+                let peak = (0..out.len()).max_by(|&a, &b| out[a].total_cmp(&out[b])).unwrap();
+                let expected = k as f64 * out_sr as f64 / in_sr as f64;
+                assert!((peak as f64 - expected).abs() <= 1.0, "{} -> {} {:?}: impulse at {} instead of {}", in_sr, out_sr, quality, peak, expected);
+            }
+        }
+    }
+    #[test]
+    fn test_resample_onset_alignment() {
+        // Silence, then a tone starting at 0.1 s: the onset must land at 0.1 s in the output too.
+        for (in_sr, out_sr) in [(48000, 44100), (22050, 44100), (44100, 48000)] {
+            let onset = in_sr as usize / 10;
+            let audio: Vec<f64> = (0..in_sr as usize / 2)
+                .map(|i| if i < onset { 0.0 } else { 0.5 * (i as f64 * 0.05).sin() })
+                .collect();
+            let out = resample_audio(&audio, in_sr, out_sr, ResampleQuality::High).unwrap();
+            let first = out.iter().position(|x| x.abs() > 0.05).unwrap();
+            let expected = out_sr as usize / 10;
+            assert!(first.abs_diff(expected) <= 4, "{} -> {}: onset at {} instead of {}", in_sr, out_sr, first, expected);
+        }
+    }
+    #[test]
+    fn test_resample_quality_lengths() {
+        let audio: Vec<f64> = (0..48000 * 10).map(|i| 0.5 * (i as f64 * 0.013).sin()).collect();
+        for quality in [ResampleQuality::Fast, ResampleQuality::Balanced, ResampleQuality::High] {
+            let out = resample_audio(&audio, 48000, SAMPLE_RATE, quality).unwrap();
+            assert_eq!(out.len(), 44100 * 10, "{:?}", quality);
+        }
+    }
+    #[test]
+    fn test_bit_depth_round_trip() {
+        let audio: Vec<f64> = (0..512).map(|i| 0.8 * (i as f64 * 0.05).sin()).collect();
+        for (depth, tolerance) in [(BitDepth::Int16, 1e-4), (BitDepth::Int24, 1e-6), (BitDepth::Float32, 1e-7)] {
+            let mut cursor = Cursor::new(Vec::new());
+            write_wav(&mut cursor, &audio, 1, spec(SAMPLE_RATE, depth)).unwrap();
+            let path = std::env::temp_dir().join(format!("hifi_depth_{:?}.wav", depth));
+            std::fs::write(&path, cursor.into_inner()).unwrap();
+            let decoded = read_audio(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(decoded.len(), audio.len());
+            let max_err = audio.iter().zip(&decoded).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max);
+            assert!(max_err < tolerance, "{:?}: max error {}", depth, max_err);
+        }
+    }
+    #[test]
+    fn test_output_sample_rate() {
+        let audio = vec![0.25; 44100];
+        for rate in [48000, 22050] {
+            let mut cursor = Cursor::new(Vec::new());
+            write_output(&mut cursor, std::slice::from_ref(&audio), spec(rate, BitDepth::Int16), None).unwrap();
+            let bytes = cursor.into_inner();
+            let reader = hound::WavReader::new(bytes.as_slice()).unwrap();
+            assert_eq!(reader.spec().sample_rate, rate);
+            assert_eq!(reader.duration(), rate);
+        }
+    }
+    #[test]
+    fn test_smpl_loop_chunk() {
+        let audio = vec![0.1; 1000];
+        let mut cursor = Cursor::new(Vec::new());
+        write_output(&mut cursor, &[audio], spec(SAMPLE_RATE, BitDepth::Int16), Some(200..1000)).unwrap();
+        let bytes = cursor.into_inner();
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        let pos = bytes.windows(4).position(|w| w == b"smpl").unwrap();
+        let field = |i: usize| u32::from_le_bytes(bytes[pos + 8 + i * 4..pos + 12 + i * 4].try_into().unwrap());
+        assert_eq!(field(7), 1);
+        assert_eq!((field(11), field(12)), (200, 999));
+        let reader = hound::WavReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.duration(), 1000);
+    }
+    #[test]
+    fn test_flac_round_trip() {
+        let audio: Vec<f64> = (0..20000).map(|i| 0.5 * (i as f64 * 0.03).sin() * (i as f64 * 0.0007).cos()).collect();
+        let dir = std::env::temp_dir();
+        for depth in [BitDepth::Int16, BitDepth::Int24] {
+            let path = dir.join(format!("hifi_flac_{:?}.flac", depth));
+            write_flac_output(std::fs::File::create(&path).unwrap(), std::slice::from_ref(&audio), spec(SAMPLE_RATE, depth), None).unwrap();
+            let flac_len = std::fs::metadata(&path).unwrap().len();
+            let decoded = read_audio(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(decoded.len(), audio.len());
+            let max_err = audio.iter().zip(&decoded).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max);
+            assert!(max_err < 1e-4, "{:?}: max error {}", depth, max_err);
+            let mut wav = Cursor::new(Vec::new());
+            write_wav(&mut wav, &audio, 1, spec(SAMPLE_RATE, depth)).unwrap();
+            assert!(flac_len < wav.into_inner().len() as u64);
+        }
+    }
+    fn spec(sample_rate: u32, depth: BitDepth) -> OutputSpec {
+        OutputSpec { sample_rate, depth, dither: false }
+    }
+    #[test]
+    fn test_wav_samples() {
+        let audio = [0.0, 0.5, -0.5, 1.0];
+        let mut cursor = Cursor::new(Vec::new());
+        write_wav(&mut cursor, &audio, 1, spec(SAMPLE_RATE, BitDepth::Int16)).unwrap();
+        let bytes = cursor.into_inner();
+        let mut reader = hound::WavReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.spec().bits_per_sample, 16);
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, vec![0, 16383, -16383, i16::MAX]);
+    }
+    #[test]
+    fn test_stereo_round_trip() {
+        let left: Vec<f64> = (0..6000).map(|i| 0.4 * (i as f64 * 0.02).sin()).collect();
+        let right: Vec<f64> = left.iter().map(|x| -0.5 * x).collect();
+        let channels = vec![left, right];
+        for ext in ["wav", "flac"] {
+            let path = std::env::temp_dir().join(format!("hifi_stereo.{}", ext));
+            if ext == "wav" {
+                write_output(std::fs::File::create(&path).unwrap(), &channels, spec(SAMPLE_RATE, BitDepth::Int24), None).unwrap();
+            } else {
+                write_flac_output(std::fs::File::create(&path).unwrap(), &channels, spec(SAMPLE_RATE, BitDepth::Int24), None).unwrap();
+            }
+            let decoded = read_audio_channels(&path).unwrap();
+            let mixed = read_audio(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(decoded.len(), 2);
+            for (orig, dec) in channels.iter().zip(&decoded) {
+                assert_eq!(orig.len(), dec.len());
+                assert!(orig.iter().zip(dec).all(|(a, b)| (a - b).abs() < 1e-6));
+            }
+            assert!(mixed.iter().zip(&channels[0]).all(|(m, l)| (m - 0.25 * l).abs() < 1e-6));
+        }
+    }
+    #[test]
+    fn test_tpdf_dither() {
+        let audio: Vec<f64> = (0..4096).map(|i| 1e-3 * (i as f64 * 0.01).sin()).collect();
+        let plain = quantize_i16(&audio, false);
+        let dithered = quantize_i16(&audio, true);
+        assert_ne!(plain, dithered);
+        assert!(plain.iter().zip(&dithered).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 1));
+        assert_eq!(dithered, quantize_i16(&audio, true));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use biquad::{Coefficients, DirectForm1, Type};
+use crate::utils::growl::forward_backward_filter;
+const LOW_SHELF_HZ: f64 = 250.0;
+const HIGH_SHELF_HZ: f64 = 4000.0;
+const SHELF_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+/// Shelving EQ flags, applied zero-phase to the render:
+/// - `HL`: low shelf gain below 250 Hz in dB, -12~12
+/// - `HH`: high shelf gain above 4 kHz in dB, -12~12
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShelfEq {
+    pub low_db: f64,
+    pub high_db: f64,
+}
+impl ShelfEq {
+    pub fn from_flags(flags: &HashMap<String, Option<f64>>) -> Self {
+        let get = |key: &str| flags.get(key).and_then(|o| o.as_ref()).copied().unwrap_or(0.).clamp(-12., 12.);
+        Self { low_db: get("HL"), high_db: get("HH") }
+    }
+    pub fn is_neutral(&self) -> bool {
+        self.low_db == 0. && self.high_db == 0.
+    }
+    pub fn apply(&self, audio: &mut [f64], sample_rate: f64) {
+        for (gain_db, shelf, corner) in [
+            (self.low_db, Type::LowShelf as fn(f64) -> Type<f64>, LOW_SHELF_HZ),
+            (self.high_db, Type::HighShelf, HIGH_SHELF_HZ),
+        ] {
+            if gain_db == 0. {
+                continue;
+            }
+            // The forward and backward passes each contribute half of the gain.
+            let coeffs = Coefficients::<f64>::from_normalized_params(shelf(gain_db / 2.), 2. * corner / sample_rate, SHELF_Q)
+                .expect("Failed to create shelf coefficients: invalid sample rate");
+            forward_backward_filter(audio, &mut DirectForm1::new(coeffs), 1);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::SAMPLE_RATE;
+    fn sine_gain(eq: ShelfEq, hz: f64) -> f64 {
+        let sr = SAMPLE_RATE as f64;
+        let source: Vec<f64> = (0..44100).map(|n| (std::f64::consts::TAU * hz * n as f64 / sr).sin()).collect();
+        let mut out = source.clone();
+        eq.apply(&mut out, sr);
+        // Skip the edges, where the filters are still settling.
+        let rms = |x: &[f64]| (x[11025..33075].iter().map(|v| v * v).sum::<f64>() / 22050.).sqrt();
+        20. * (rms(&out) / rms(&source)).log10()
+    }
+    #[test]
+    fn test_shelf_gains() {
+        let flags = HashMap::from([("HL".to_string(), Some(6.)), ("HH".to_string(), Some(-6.))]);
+        let eq = ShelfEq::from_flags(&flags);
+        assert!(ShelfEq::from_flags(&HashMap::new()).is_neutral());
+        assert!((sine_gain(eq, 50.) - 6.).abs() < 0.5);
+        assert!((sine_gain(eq, 12000.) + 6.).abs() < 0.5);
+        assert!(sine_gain(eq, 1000.).abs() < 1.5);
+        let boost_highs = ShelfEq { low_db: -6., high_db: 6. };
+        assert!(sine_gain(boost_highs, 50.) < -5.5);
+        assert!(sine_gain(boost_highs, 12000.) > 5.5);
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{anyhow, Result};
+use flacenc::{bitsink::ByteSink, component::{BitRepr, MetadataBlockData}, error::Verify, source::MemSource};
+use std::{io::Write, ops::Range};
+const VORBIS_COMMENT: u8 = 4;
+/// FLAC has no `smpl` chunk, so loop points go into the `LOOPSTART`/`LOOPLENGTH` Vorbis
+/// comments most samplers and game engines read.
+fn loop_comment_block(region: &Range<usize>) -> Vec<u8> {
+    let vendor = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+    let comments = [
+        format!("LOOPSTART={}", region.start),
+        format!("LOOPLENGTH={}", region.end - region.start),
+    ];
+    let mut block = Vec::new();
+    block.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    block.extend_from_slice(vendor.as_bytes());
+    block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in &comments {
+        block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        block.extend_from_slice(comment.as_bytes());
+    }
+    block
+}
+/// Writes integer samples (one buffer per channel) as a FLAC stream with flacenc, which also
+/// fills in the STREAMINFO MD5. A non-empty `loop_region` is stored as Vorbis comments.
+pub fn write_flac<W: Write>(
+    mut writer: W,
+    channels: &[Vec<i32>],
+    sample_rate: u32,
+    bits_per_sample: u32,
+    loop_region: Option<Range<usize>>,
+) -> Result<()> {
+    let total = channels.iter().map(Vec::len).min().unwrap_or(0);
+    let mut samples = Vec::with_capacity(total * channels.len());
+    for i in 0..total {
+        samples.extend(channels.iter().map(|channel| channel[i]));
+    }
+    let config = flacenc::config::Encoder::default()
+        .into_verified()
+        .map_err(|(_, e)| anyhow!("Invalid FLAC encoder config: {}", e))?;
+    let source = MemSource::from_samples(&samples, channels.len(), bits_per_sample as usize, sample_rate as usize);
+    let mut stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|e| anyhow!("FLAC encoding failed ({} Hz, {} bits, {} channels): {}", sample_rate, bits_per_sample, channels.len(), e))?;
+    // flacenc lowers the minimum to the short last block, which STREAMINFO excludes; decoders
+    // then take the stream for a variable-blocksize one and misparse every frame header.
+    stream.stream_info_mut().set_block_sizes(config.block_size, config.block_size)?;
+    if let Some(region) = loop_region.filter(|r| r.start < r.end) {
+        stream.add_metadata_block(MetadataBlockData::new_unknown(VORBIS_COMMENT, &loop_comment_block(&region))?);
+    }
+    let mut sink = ByteSink::new();
+    stream.write(&mut sink).map_err(|e| anyhow!("FLAC encoding failed: {}", e))?;
+    writer.write_all(sink.as_slice())?;
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_streaminfo_md5_and_loop_comments() {
+        use symphonia::core::{codecs::DecoderOptions, formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint};
+        let channels: Vec<Vec<i32>> = (0..2)
+            .map(|c| (0..10000).map(|i| ((i as f64 * 0.01 * (c + 1) as f64).sin() * 8_000_000.0) as i32).collect())
+            .collect();
+        let mut bytes = Vec::new();
+        write_flac(&mut bytes, &channels, 44100, 24, Some(1000..9000)).unwrap();
+        let stream = MediaSourceStream::new(Box::new(std::io::Cursor::new(bytes)), Default::default());
+        let mut probed = symphonia::default::get_probe()
+            .format(&Hint::new(), stream, &FormatOptions::default(), &MetadataOptions::default())
+            .unwrap();
+        let tags: Vec<(String, String)> = probed.format.metadata().current().unwrap().tags().iter()
+            .map(|t| (t.key.clone(), t.value.to_string()))
+            .collect();
+        assert!(tags.contains(&("LOOPSTART".into(), "1000".into())));
+        assert!(tags.contains(&("LOOPLENGTH".into(), "8000".into())));
+        let params = probed.format.default_track().unwrap().codec_params.clone();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions { verify: true })
+            .unwrap();
+        while let Ok(packet) = probed.format.next_packet() {
+            decoder.decode(&packet).unwrap();
+        }
+        assert_eq!(decoder.finalize().verify_ok, Some(true));
+    }
+}
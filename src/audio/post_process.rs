@@ -1,27 +1,38 @@
+use anyhow::{ensure, Result};
 use bs1770::{ChannelLoudnessMeter, gated_mean};
-use ndarray::{Array2, Axis, azip};
-use oxifft::Complex;
+use biquad::{Coefficients, DirectForm1, Type};
+use ndarray::{Array2, Axis};
 use crate::{
-    consts::{FFT_SIZE, HOP_SIZE, HIFI_CONFIG, SAMPLE_RATE},
-    utils::{stft::{stft_core, istft_core}, reflect_pad_1d}, 
+    consts::{FFT_SIZE, HOP_SIZE, HIFI_CONFIG, SAMPLE_RATE, WindowKind},
+    utils::{growl::forward_backward_filter, stft::{istft_from_mag_phase, stft_magnitude, stft_magnitude_phase}, reflect_pad_1d}, 
 };
-pub fn pre_emphasis_base_tension(wave: &mut Vec<f64>, b: f64) {
+/// The `B` flag (-100~100) scales the HNSEP noise residual on top of `Hb`: -100 removes it
+/// and 100 doubles it.
+pub fn breathiness_scale(b: f64) -> f64 {
+    1.0 + b.clamp(-100., 100.) / 100.
+}
+/// Rebuilds `wave` from its noise residual (`wave - harmonic`) and harmonic part, each with its own gain.
+pub fn mix_hnsep(wave: &mut [f64], harmonic: &[f64], noise_scale: f64, harmonic_scale: f64) {
+    wave.iter_mut()
+        .zip(harmonic)
+        .for_each(|(w, &s)| *w = noise_scale * (*w - s) + harmonic_scale * s);
+}
+/// Tilts the spectrum of `wave` by `b` and restores its peak. Waves too short to fill one
+/// FFT window, or silent ones, come back unchanged.
+pub fn pre_emphasis_base_tension(wave: &[f64], b: f64) -> Result<Vec<f64>> {
     let orig_len = wave.len();
     let orig_max = wave.iter()
         .map(|x| x.abs())
         .max_by(|a, b| a.total_cmp(b))
         .unwrap_or(1.0); 
+    if orig_len < FFT_SIZE || orig_max == 0.0 {
+        return Ok(wave.to_vec());
+    }
+    let mut wave = wave.to_vec();
     let padded_len = ((orig_len + HOP_SIZE - 1) / HOP_SIZE) * HOP_SIZE;
     wave.resize(padded_len, 0.0);
-    let comp_spec = stft_core(&*wave, FFT_SIZE, HOP_SIZE);
-    let mut spec_amp = Array2::zeros(comp_spec.dim());
-    let mut spec_phase = Array2::zeros(comp_spec.dim());
-    azip!((amp_val in &mut spec_amp, &c in &comp_spec) {
-        *amp_val = c.norm();
-    });
-    azip!((phase_val in &mut spec_phase, &c in &comp_spec) {
-        *phase_val = c.arg();
-    });
+    let (mut spec_amp, spec_phase) = stft_magnitude_phase(&wave, FFT_SIZE, HOP_SIZE, WindowKind::Hann);
+    ensure!(spec_amp.ncols() > 0, "STFT of {} samples produced no frames", padded_len);
     spec_amp.mapv_inplace(|x| x.max(1e-9).ln());
     spec_amp.axis_iter_mut(Axis(0))
         .enumerate()
@@ -29,21 +40,351 @@ pub fn pre_emphasis_base_tension(wave: &mut Vec<f64>, b: f64) {
             let filter = (b * (1.0 - (SAMPLE_RATE as f64 * j as f64) / (FFT_SIZE / 1500 + 3000) as f64)).clamp(-2.0, 2.0);
             bin.iter_mut().for_each(|amp_db| *amp_db += filter);
         });
-    let mut comp_spec_istft = Array2::from_elem((FFT_SIZE / 2 + 1, comp_spec.ncols()), Complex::zero());
-    azip!((comp_val in &mut comp_spec_istft, &phase in &spec_phase, &amp_db in &spec_amp) {
-        let amp = amp_db.exp(); 
-        *comp_val = Complex::new(amp * phase.cos(), amp * phase.sin());
-    });
-    let mut filtered_wave = istft_core(&comp_spec_istft, wave.len(), FFT_SIZE, HOP_SIZE);
+    spec_amp.mapv_inplace(f64::exp);
+    let mut filtered_wave = istft_from_mag_phase(&spec_amp, &spec_phase, wave.len(), FFT_SIZE, HOP_SIZE, WindowKind::Hann);
     let filtered_max = filtered_wave.iter()
         .map(|x| x.abs())
         .max_by(|a, b| a.total_cmp(b))
         .unwrap_or(1.0);
     let gain = (orig_max / filtered_max) * ((b / -15.0).max(0.0) + 1.0);
-    wave.truncate(orig_len);
-    wave.iter_mut()
-        .zip(filtered_wave.drain(0..orig_len)) 
-        .for_each(|(w, fw)| *w = fw * gain);
+    ensure!(gain.is_finite(), "Tension filter produced an unusable gain ({})", gain);
+    filtered_wave.truncate(orig_len);
+    filtered_wave.iter_mut().for_each(|fw| *fw *= gain);
+    Ok(filtered_wave)
+}
+/// Sibilance band used by [`de_ess`], in Hz.
+const SIBILANT_BAND: (f64, f64) = (5000.0, 10000.0);
+/// Share of a frame's energy in the sibilance band above which it gets turned down.
+const SIBILANT_THRESHOLD: f64 = 0.35;
+/// Share of each frame's energy that falls between `lo_hz` and `hi_hz`.
+fn band_share(magnitude: &Array2<f64>, sample_rate: f64, (lo_hz, hi_hz): (f64, f64)) -> Vec<f64> {
+    let bin_hz = sample_rate / FFT_SIZE as f64;
+    let band = (lo_hz / bin_hz).ceil() as usize..=((hi_hz / bin_hz).floor() as usize).min(magnitude.nrows() - 1);
+    magnitude.axis_iter(Axis(1))
+        .map(|frame| {
+            let total: f64 = frame.iter().map(|m| m * m).sum();
+            let in_band: f64 = frame.slice(ndarray::s![band.clone()]).iter().map(|m| m * m).sum();
+            if total > 1e-20 { in_band / total } else { 0.0 }
+        })
+        .collect()
+}
+/// The de-esser flag (0~100). Frames whose energy leans on the 5–10 kHz band past
+/// `SIBILANT_THRESHOLD` get that band, split off with a zero-phase band-pass, turned down;
+/// at 100 the band's share is pulled back to the threshold.
+pub fn de_ess(wave: &mut [f64], sample_rate: f64, strength: f64) {
+    let strength = strength.clamp(0.0, 100.0) / 100.0;
+    if strength == 0.0 || wave.len() < FFT_SIZE {
+        return;
+    }
+    let magnitude = stft_magnitude(wave, FFT_SIZE, HOP_SIZE, WindowKind::Hann, false);
+    let gains: Vec<f64> = band_share(&magnitude, sample_rate, SIBILANT_BAND).iter()
+        .map(|&share| if share > SIBILANT_THRESHOLD { (SIBILANT_THRESHOLD / share).sqrt().powf(strength) } else { 1.0 })
+        .collect();
+    if gains.iter().all(|&g| g == 1.0) {
+        return;
+    }
+    // Unity gain at the band's geometric centre; frequencies are normalized to Nyquist.
+    let (lo, hi) = SIBILANT_BAND;
+    let coeffs = Coefficients::<f64>::band_0db_from_cutting_frequencies(Type::BandPass, 2.0 * lo / sample_rate, 2.0 * hi / sample_rate)
+        .expect("Failed to create de-esser band-pass coefficients");
+    let mut band = wave.to_vec();
+    forward_backward_filter(&mut band, &mut DirectForm1::new(coeffs), 1);
+    // Frame `f` is centred on sample `f * HOP_SIZE + FFT_SIZE / 2`; gains are interpolated between centres.
+    let last = gains.len() - 1;
+    for (i, (w, b)) in wave.iter_mut().zip(&band).enumerate() {
+        let pos = (i as f64 - (FFT_SIZE / 2) as f64) / HOP_SIZE as f64;
+        let gain = if pos <= 0.0 {
+            gains[0]
+        } else if pos >= last as f64 {
+            gains[last]
+        } else {
+            let f = pos.floor() as usize;
+            crate::utils::lerp(gains[f], gains[f + 1], pos.fract())
+        };
+        *w += (gain - 1.0) * b;
+    }
+}
+/// Hop of the pitch shifter's phase vocoder; 8x overlap keeps the phase estimates clean.
+const PITCH_SHIFT_HOP: usize = FFT_SIZE / 8;
+/// Width of the moving average that estimates a frame's spectral envelope, in Hz.
+const FORMANT_SMOOTHING_HZ: f64 = 500.0;
+/// Bound on the envelope correction, so a sparse spectrum (a lone sine) isn't reshaped into
+/// its own leakage.
+const FORMANT_MAX_GAIN: f64 = 16.0;
+/// Moving average of `frame` over `radius` bins on either side.
+fn spectral_envelope(frame: &[f64], radius: usize) -> Vec<f64> {
+    let mut prefix = vec![0.0; frame.len() + 1];
+    for (i, &m) in frame.iter().enumerate() {
+        prefix[i + 1] = prefix[i] + m;
+    }
+    (0..frame.len())
+        .map(|k| {
+            let (lo, hi) = (k.saturating_sub(radius), (k + radius + 1).min(frame.len()));
+            (prefix[hi] - prefix[lo]) / (hi - lo) as f64
+        })
+        .collect()
+}
+/// Index of the spectral peak whose region each bin falls in, splitting halfway between peaks;
+/// a frame without peaks leaves every bin on its own.
+fn peak_regions(frame: &[f64]) -> Vec<usize> {
+    let peaks: Vec<usize> = (1..frame.len().saturating_sub(1))
+        .filter(|&k| frame[k] > frame[k - 1] && frame[k] >= frame[k + 1])
+        .collect();
+    if peaks.is_empty() {
+        return (0..frame.len()).collect();
+    }
+    let mut owner = Vec::with_capacity(frame.len());
+    let mut p = 0;
+    for k in 0..frame.len() {
+        while p + 1 < peaks.len() && k.abs_diff(peaks[p + 1]) < k.abs_diff(peaks[p]) {
+            p += 1;
+        }
+        owner.push(peaks[p]);
+    }
+    owner
+}
+/// The `Hp` flag (-2400~2400 cents). A phase vocoder moves bin `k` to `k * ratio` at the
+/// same hop, so the duration is kept; the shifted spectrum is then reshaped to the input
+/// frame's envelope and energy, which keeps the formants and the loudness contour in place.
+pub fn pitch_shift(wave: &mut [f64], sample_rate: f64, cents: f64) {
+    use std::f64::consts::{PI, TAU};
+    let ratio = 2f64.powf(cents.clamp(-2400.0, 2400.0) / 1200.0);
+    if ratio == 1.0 || wave.is_empty() {
+        return;
+    }
+    // Zero-pad half a window on both sides so the edges sit in fully overlapped frames.
+    let (len, pad) = (wave.len(), FFT_SIZE / 2);
+    let mut padded = vec![0.0; pad];
+    padded.extend_from_slice(wave);
+    padded.resize(pad + len.div_ceil(PITCH_SHIFT_HOP) * PITCH_SHIFT_HOP + pad, 0.0);
+    let (magnitude, phase) = stft_magnitude_phase(&padded, FFT_SIZE, PITCH_SHIFT_HOP, WindowKind::Hann);
+    let bins = magnitude.nrows();
+    let radius = (FORMANT_SMOOTHING_HZ * FFT_SIZE as f64 / sample_rate / 2.0).round() as usize;
+    let target = |k: usize| Some((k as f64 * ratio).round() as usize).filter(|&j| j < bins);
+    // Phase advance over one hop of a component at (fractional) bin `k`.
+    let advance = |k: f64| TAU * k * PITCH_SHIFT_HOP as f64 / FFT_SIZE as f64;
+    let mut out_magnitude = Array2::zeros(magnitude.raw_dim());
+    let mut out_phase = Array2::zeros(phase.raw_dim());
+    // Phases are kept relative to the frame centre, where the bins of a steady component agree.
+    let mut prev_phase = vec![0.0; bins];
+    let mut synth_phase = vec![0.0; bins];
+    for t in 0..magnitude.ncols() {
+        let frame = magnitude.column(t).to_vec();
+        let centred: Vec<f64> = (0..bins).map(|k| phase[[k, t]] - PI * k as f64).collect();
+        let owner = peak_regions(&frame);
+        // Each peak carries its instantaneous frequency to the shifted bin; the rest of its
+        // region follows with the analysis phase offsets (identity phase locking).
+        let mut peak_phase = vec![0.0; bins];
+        for p in (0..bins).filter(|&k| owner[k] == k) {
+            if let Some(j) = target(p) {
+                let delta = centred[p] - prev_phase[p] - advance(p as f64);
+                let deviation = delta - TAU * (delta / TAU).round();
+                peak_phase[p] = synth_phase[j] + advance(p as f64 + deviation / advance(1.0)) * ratio;
+            }
+        }
+        prev_phase.copy_from_slice(&centred);
+        // Each output bin reads the input at `j / ratio`, so upward shifts don't leave holes.
+        let mut shifted: Vec<f64> = (0..bins)
+            .map(|j| {
+                let source = j as f64 / ratio;
+                let k = source.round() as usize;
+                if k >= bins {
+                    return 0.0;
+                }
+                synth_phase[j] = peak_phase[owner[k]] + centred[k] - centred[owner[k]];
+                let lo = (source.floor() as usize).min(bins - 1);
+                crate::utils::lerp(frame[lo], frame[(lo + 1).min(bins - 1)], source.fract())
+            })
+            .collect();
+        let (env_in, env_shifted) = (spectral_envelope(&frame, radius), spectral_envelope(&shifted, radius));
+        shifted.iter_mut()
+            .zip(env_in.iter().zip(&env_shifted))
+            .for_each(|(m, (&e_in, &e_shifted))| if e_shifted > 0.0 {
+                *m *= (e_in / e_shifted).clamp(1.0 / FORMANT_MAX_GAIN, FORMANT_MAX_GAIN);
+            });
+        let energy_in: f64 = frame.iter().map(|m| m * m).sum();
+        let energy_out: f64 = shifted.iter().map(|m| m * m).sum();
+        let gain = if energy_out > 1e-20 { (energy_in / energy_out).sqrt() } else { 0.0 };
+        for j in 0..bins {
+            out_magnitude[[j, t]] = shifted[j] * gain;
+            out_phase[[j, t]] = synth_phase[j] + PI * j as f64;
+        }
+    }
+    let shifted = istft_from_mag_phase(&out_magnitude, &out_phase, padded.len(), FFT_SIZE, PITCH_SHIFT_HOP, WindowKind::Hann);
+    wave.copy_from_slice(&shifted[pad..pad + len]);
+}
+/// Feed-forward soft-knee compressor with a peak detector, configured in `hificonfig.ini`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compressor {
+    pub threshold_db: f64,
+    pub ratio: f64,
+    pub knee_db: f64,
+    pub attack_ms: f64,
+    pub release_ms: f64,
+}
+impl Compressor {
+    pub fn from_config() -> Option<Self> {
+        HIFI_CONFIG.compressor.then(|| Self {
+            threshold_db: HIFI_CONFIG.compressor_threshold_db,
+            ratio: HIFI_CONFIG.compressor_ratio,
+            knee_db: HIFI_CONFIG.compressor_knee_db,
+            attack_ms: HIFI_CONFIG.compressor_attack_ms,
+            release_ms: HIFI_CONFIG.compressor_release_ms,
+        })
+    }
+    /// Static gain change in dB (zero or negative) for an input level in dBFS.
+    fn gain_db(&self, level_db: f64) -> f64 {
+        let over = level_db - self.threshold_db;
+        let slope = 1.0 / self.ratio - 1.0;
+        if 2.0 * over <= -self.knee_db {
+            0.0
+        } else if 2.0 * over.abs() < self.knee_db {
+            slope * (over + self.knee_db / 2.0).powi(2) / (2.0 * self.knee_db)
+        } else {
+            slope * over
+        }
+    }
+    pub fn apply(&self, wave: &mut [f64], sample_rate: f64) {
+        let coeff = |ms: f64| if ms > 0.0 { (-1000.0 / (ms * sample_rate)).exp() } else { 0.0 };
+        let (attack, release) = (coeff(self.attack_ms), coeff(self.release_ms));
+        let mut gain = 0.0;
+        for sample in wave.iter_mut() {
+            let target = self.gain_db(20.0 * sample.abs().max(1e-10).log10());
+            let smoothing = if target < gain { attack } else { release };
+            gain = smoothing * gain + (1.0 - smoothing) * target;
+            *sample *= 10f64.powf(gain / 20.0);
+        }
+    }
+}
+/// The `P` flag when `p_flag = peak` (0~100): moves the peak of `wave` towards `limit`, all the
+/// way at 100. Silent waves are left alone.
+pub fn peak_normalize(wave: &mut [f64], limit: f64, amount: f64) {
+    let peak = wave.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+    let amount = amount.clamp(0.0, 100.0) / 100.0;
+    if peak == 0.0 || amount == 0.0 {
+        return;
+    }
+    let gain = 1.0 + amount * (limit / peak - 1.0);
+    wave.iter_mut().for_each(|x| *x *= gain);
+}
+const TRUE_PEAK_OVERSAMPLE: usize = 4;
+/// Half-length, in input samples, of the windowed-sinc interpolator behind [`true_peaks`].
+const TRUE_PEAK_TAPS: isize = 8;
+const TRUE_PEAK_LOOKAHEAD_SECS: f64 = 0.0015;
+const TRUE_PEAK_PASSES: usize = 4;
+/// Each sample's true peak: the largest magnitude among the sample and the 4x-oversampled
+/// points between it and the next one.
+pub fn true_peaks(wave: &[f64]) -> Vec<f64> {
+    let kernels: Vec<Vec<f64>> = (1..TRUE_PEAK_OVERSAMPLE)
+        .map(|p| {
+            let frac = p as f64 / TRUE_PEAK_OVERSAMPLE as f64;
+            (1 - TRUE_PEAK_TAPS..=TRUE_PEAK_TAPS)
+                .map(|k| {
+                    let x = frac - k as f64;
+                    let sinc = (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x);
+                    sinc * (0.5 + 0.5 * (std::f64::consts::PI * x / TRUE_PEAK_TAPS as f64).cos())
+                })
+                .collect()
+        })
+        .collect();
+    (0..wave.len())
+        .map(|n| {
+            kernels.iter()
+                .map(|kernel| {
+                    (1 - TRUE_PEAK_TAPS..=TRUE_PEAK_TAPS)
+                        .zip(kernel)
+                        .filter_map(|(k, c)| wave.get(usize::try_from(n as isize + k).ok()?).map(|x| x * c))
+                        .sum::<f64>()
+                        .abs()
+                })
+                .fold(wave[n].abs(), f64::max)
+        })
+        .collect()
+}
+/// Keeps the oversampled peak of the final render under `ceiling_db` (dBTP), configured in
+/// `hificonfig.ini`. The gain is linked across channels and ramps down 1.5 ms ahead of a peak.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TruePeakLimiter {
+    pub ceiling_db: f64,
+}
+impl TruePeakLimiter {
+    pub fn from_config() -> Option<Self> {
+        HIFI_CONFIG.true_peak_limiter.then(|| Self { ceiling_db: HIFI_CONFIG.true_peak_ceiling_db })
+    }
+    pub fn apply(&self, channels: &mut [Vec<f64>], sample_rate: f64) {
+        // Ramping the gain can itself nudge an inter-sample peak over, so check again.
+        for _ in 0..TRUE_PEAK_PASSES {
+            if !self.limit_pass(channels, sample_rate) {
+                break;
+            }
+        }
+    }
+    /// One gain-reduction pass; returns whether anything was over the ceiling.
+    fn limit_pass(&self, channels: &mut [Vec<f64>], sample_rate: f64) -> bool {
+        let len = channels.iter().map(Vec::len).max().unwrap_or(0);
+        let ceiling = 10f64.powf(self.ceiling_db / 20.0);
+        let mut required = vec![1.0f64; len];
+        for channel in channels.iter() {
+            for (r, peak) in required.iter_mut().zip(true_peaks(channel)) {
+                if peak > ceiling {
+                    *r = r.min(ceiling / peak);
+                }
+            }
+        }
+        if required.iter().all(|&r| r == 1.0) {
+            return false;
+        }
+        // A minimum over +-L followed by a mean over +-L never exceeds the gain a sample needs,
+        // and ramps over 2L samples instead of stepping.
+        let radius = (TRUE_PEAK_LOOKAHEAD_SECS * sample_rate).round() as usize;
+        let window = |n: usize| n.saturating_sub(radius)..(n + radius + 1).min(len);
+        let floor: Vec<f64> = (0..len).map(|n| required[window(n)].iter().cloned().fold(1.0, f64::min)).collect();
+        let gain: Vec<f64> = (0..len).map(|n| floor[window(n)].iter().sum::<f64>() / window(n).len() as f64).collect();
+        for channel in channels.iter_mut() {
+            channel.iter_mut().zip(&gain).for_each(|(x, g)| *x *= g);
+        }
+        true
+    }
+}
+const GATE_WINDOW_SECS: f64 = 0.01;
+const GATE_ATTACK_SECS: f64 = 0.001;
+const GATE_RELEASE_SECS: f64 = 0.05;
+const GATE_BLOCK: usize = 32;
+/// Closes wherever the RMS of the 10 ms window around a sample is below `threshold_db`. The gain
+/// opens within about a millisecond and fades out over 50 ms, so it neither chatters nor clips
+/// the onset of a loud passage.
+pub fn noise_gate(wave: &mut [f64], sample_rate: f64, threshold_db: f64) {
+    let half = ((GATE_WINDOW_SECS * sample_rate) as usize / 2).max(1);
+    // The detector is re-evaluated every `GATE_BLOCK` samples; the smoothing hides the steps.
+    let open: Vec<bool> = (0..wave.len())
+        .step_by(GATE_BLOCK)
+        .flat_map(|start| {
+            let centre = start + GATE_BLOCK / 2;
+            let level = rms_db(&wave[centre.saturating_sub(half).min(start)..(centre + half).min(wave.len())]);
+            std::iter::repeat_n(level >= threshold_db, GATE_BLOCK)
+        })
+        .take(wave.len())
+        .collect();
+    let (attack, release) = ((-1.0 / (GATE_ATTACK_SECS * sample_rate)).exp(), (-1.0 / (GATE_RELEASE_SECS * sample_rate)).exp());
+    let mut gain = 1.0;
+    for (sample, open) in wave.iter_mut().zip(open) {
+        if open {
+            gain = attack * gain + (1.0 - attack);
+        } else {
+            gain *= release;
+        }
+        *sample *= gain;
+    }
+}
+/// Equal-power crossfade of the last `fade` samples into the ones just before `loop_start`, so
+/// a player wrapping from the end back to `loop_start` continues without a jump.
+pub fn declick_loop(audio: &mut [f64], loop_start: usize, fade: usize) {
+    let fade = fade.min(loop_start).min(audio.len().saturating_sub(loop_start));
+    let tail = audio.len() - fade;
+    for k in 0..fade {
+        let theta = std::f64::consts::FRAC_PI_2 * (k + 1) as f64 / fade as f64;
+        audio[tail + k] = theta.cos() * audio[tail + k] + theta.sin() * audio[loop_start - fade + k];
+    }
 }
 fn rms_db(audio: &[f64]) -> f64 {
     let sum_sq: f64 = audio.iter()
@@ -56,23 +397,35 @@ fn rms_db(audio: &[f64]) -> f64 {
         20.0 * rms.log10()
     }
 }
-fn linear_fade(length: usize, fade_in: bool, sample_rate: f64) -> Vec<f64> {
-    let fade_len = ((0.2 * sample_rate) as usize).min(length / 4);
-    let mut fade = Vec::with_capacity(length); 
+/// Gain curve over `length` samples that ramps linearly from 0 over the first `fade_secs`
+/// (`fade_in`) or down to 0 over the last, capped at a quarter of `length`.
+pub fn linear_fade(length: usize, fade_in: bool, fade_secs: f64, sample_rate: f64) -> Vec<f64> {
+    let fade_len = ((fade_secs * sample_rate) as usize).min(length / 4);
+    let ramp = (0..fade_len).map(|i| i as f64 / (fade_len - 1).max(1) as f64);
+    let hold = std::iter::repeat_n(1.0, length - fade_len);
     if fade_in {
-        fade.extend(std::iter::repeat(1.0).take(length));
-        for i in 0..fade_len {
-            let val = (i as f64) / ((fade_len - 1).max(1)) as f64;
-            fade[i] = val;
-        }
+        ramp.chain(hold).collect()
     } else {
-        fade.extend(std::iter::repeat(1.0).take(length - fade_len));
-        for i in 0..fade_len {
-            let val = (i as f64) / ((fade_len - 1).max(1)) as f64;
-            fade.push(val);
+        hold.chain(ramp.rev()).collect()
+    }
+}
+/// Magnitude above which a render starting or ending on that sample clicks in the DAW.
+const EDGE_CLICK_THRESHOLD: f64 = 1e-3;
+/// Fades in the first and (with `fade_out`) out the last `fade_ms` of every channel, each only
+/// when that edge sits away from zero in some channel, so all channels get the same fades.
+pub fn fade_edges(channels: &mut [Vec<f64>], sample_rate: f64, fade_ms: f64, fade_out: bool) {
+    let len = channels.iter().map(Vec::len).min().unwrap_or(0);
+    if len == 0 {
+        return;
+    }
+    let clicks = |i: usize| channels.iter().any(|ch| ch[i].abs() > EDGE_CLICK_THRESHOLD);
+    let edges = [(true, clicks(0)), (false, fade_out && clicks(len - 1))];
+    for (fade_in, _) in edges.into_iter().filter(|&(_, click)| click) {
+        let fade = linear_fade(len, fade_in, fade_ms / 1000.0, sample_rate);
+        for wave in channels.iter_mut() {
+            wave.iter_mut().zip(&fade).for_each(|(w, f)| *w *= f);
         }
     }
-    fade
 }
 pub fn loudness_norm(
     wave: &mut Vec<f64>,
@@ -132,7 +485,7 @@ pub fn loudness_norm(
     if need_restore {
         wave[0..val_start].iter_mut().for_each(|x| *x = 0.0);
         wave[val_end..orig_len].iter_mut().for_each(|x| *x = 0.0);
-        let fade_out = linear_fade(val_len, false, sample_rate);
+        let fade_out = linear_fade(val_len, false, 0.2, sample_rate);
         wave[val_start..val_end]
             .iter_mut()
             .zip(fade_out.iter())
@@ -141,4 +494,265 @@ pub fn loudness_norm(
     wave.truncate(orig_len);
     wave.iter_mut()
     .for_each(|x| *x = x.clamp(-1.0, 1.0));
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    /// Energy of the first difference, which weights the spectrum towards high frequencies.
+    fn high_band_energy(wave: &[f64]) -> f64 {
+        wave.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum()
+    }
+    #[test]
+    fn test_breathiness_raises_noise() {
+        let mut seed = 1u64;
+        let noise: Vec<f64> = (0..4096).map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as f64 / (1u64 << 31) as f64 - 0.5
+        }).collect();
+        let harmonic: Vec<f64> = (0..4096).map(|i| 0.5 * (i as f64 * 0.02).sin()).collect();
+        let source: Vec<f64> = harmonic.iter().zip(&noise).map(|(h, n)| h + 0.1 * n).collect();
+        let render = |b: f64| {
+            let mut wave = source.clone();
+            mix_hnsep(&mut wave, &harmonic, breathiness_scale(b), 1.0);
+            high_band_energy(&wave)
+        };
+        assert!((render(0.) - high_band_energy(&source)).abs() < 1e-9);
+        assert!(render(50.) > 2.0 * render(0.));
+        assert!(render(-50.) < 0.5 * render(0.));
+        assert_eq!(breathiness_scale(-300.), 0.0);
+        assert_eq!(breathiness_scale(300.), 2.0);
+    }
+    fn integrated_lufs(wave: &[f64]) -> f64 {
+        let mut meter = ChannelLoudnessMeter::new(SAMPLE_RATE);
+        meter.push(wave.iter().map(|&x| x as f32));
+        gated_mean(meter.into_100ms_windows().as_ref()).loudness_lkfs() as f64
+    }
+    #[test]
+    fn test_loudness_targets() {
+        let source: Vec<f64> = (0..2 * SAMPLE_RATE as usize)
+            .map(|n| 0.1 * (std::f64::consts::TAU * 440.0 * n as f64 / SAMPLE_RATE as f64).sin())
+            .collect();
+        let normalized = |target: f64| {
+            let mut wave = source.clone();
+            loudness_norm(&mut wave, SAMPLE_RATE as f64, target, 100);
+            // The trimmed tail gets faded out, so only measure ahead of it.
+            integrated_lufs(&wave[..SAMPLE_RATE as usize])
+        };
+        let (streaming, broadcast) = (normalized(-14.0), normalized(-23.0));
+        assert!((streaming + 14.0).abs() < 0.5, "{:.2} LUFS", streaming);
+        assert!((broadcast + 23.0).abs() < 0.5, "{:.2} LUFS", broadcast);
+    }
+    #[test]
+    fn test_loudness_norm_fades_trimmed_tail() {
+        let sr = SAMPLE_RATE as f64;
+        let mut wave: Vec<f64> = (0..SAMPLE_RATE as usize)
+            .map(|n| 0.1 * (std::f64::consts::TAU * 440.0 * n as f64 / sr).sin())
+            .collect();
+        loudness_norm(&mut wave, sr, -16.0, 100);
+        let peak = |x: &[f64]| x.iter().fold(0.0f64, |m, v| m.max(v.abs()));
+        let body = peak(&wave[..SAMPLE_RATE as usize / 2]);
+        // The last 0.2 s decays to silence instead of ramping back up to full and cutting off.
+        let tail: Vec<f64> = wave[wave.len() - (0.2 * sr) as usize..].chunks((0.02 * sr) as usize).map(peak).collect();
+        assert!(tail.windows(2).all(|w| w[1] <= w[0]), "{:?}", tail);
+        assert!(peak(&wave[wave.len() - 100..]) < 0.01 * body);
+    }
+    #[test]
+    fn test_tension_short_input() -> Result<()> {
+        let short: Vec<f64> = (0..FFT_SIZE - 1).map(|n| (n as f64 * 0.05).sin()).collect();
+        assert_eq!(pre_emphasis_base_tension(&short, 1.0)?, short);
+        assert!(pre_emphasis_base_tension(&[], 1.0)?.is_empty());
+        let long: Vec<f64> = (0..4 * FFT_SIZE + 100).map(|n| 0.5 * (n as f64 * 0.05).sin()).collect();
+        let tensed = pre_emphasis_base_tension(&long, -1.0)?;
+        assert_eq!(tensed.len(), long.len());
+        assert!(tensed.iter().all(|x| x.is_finite()));
+        Ok(())
+    }
+    #[test]
+    fn test_compressor_tames_transient() {
+        let sr = SAMPLE_RATE as f64;
+        let compressor = Compressor { threshold_db: -18.0, ratio: 4.0, knee_db: 6.0, attack_ms: 1.0, release_ms: 50.0 };
+        // A quiet body at -30 dBFS with a 0 dBFS transient from 0.5 s to 0.55 s.
+        let source: Vec<f64> = (0..44100).map(|n| {
+            let level = if (22050..24255).contains(&n) { 1.0 } else { 10f64.powf(-30.0 / 20.0) };
+            level * (std::f64::consts::TAU * 220.0 * n as f64 / sr).sin()
+        }).collect();
+        let mut out = source.clone();
+        compressor.apply(&mut out, sr);
+        assert_eq!(out[..22050], source[..22050]);
+        let peak = |x: &[f64]| x.iter().fold(0.0, |m: f64, v| m.max(v.abs()));
+        let transient_db = 20.0 * (peak(&out[23000..24255]) / peak(&source[23000..24255])).log10();
+        assert!(transient_db < -9.0, "transient only reduced by {} dB", transient_db);
+        let tail_db = 20.0 * (peak(&out[40000..]) / peak(&source[40000..])).log10();
+        assert!(tail_db.abs() < 0.1, "body changed by {} dB after release", tail_db);
+        assert_eq!(compressor.gain_db(-40.0), 0.0);
+        assert!((compressor.gain_db(-6.0) + 9.0).abs() < 1e-9);
+    }
+    #[test]
+    fn test_fade_edges() {
+        let sr = SAMPLE_RATE as f64;
+        let mut channels = vec![vec![0.5; 4410]];
+        fade_edges(&mut channels, sr, 10.0, true);
+        let dc = &channels[0];
+        assert_eq!((dc[0], dc[4409]), (0.0, 0.0));
+        assert!((dc[220] - 0.25).abs() < 0.01 && (dc[4189] - 0.25).abs() < 0.01);
+        assert!(dc[441..3969].iter().all(|&x| x == 0.5));
+        assert!(dc[..441].windows(2).all(|w| w[1] >= w[0]) && dc[3969..].windows(2).all(|w| w[1] <= w[0]));
+        // Only the edge that sits away from zero is touched.
+        let rising: Vec<f64> = (0..4410).map(|n| n as f64 / 4410.0).collect();
+        let mut channels = vec![rising.clone()];
+        fade_edges(&mut channels, sr, 10.0, true);
+        assert_eq!(channels[0][..2000], rising[..2000]);
+        assert_eq!(channels[0][4409], 0.0);
+        // Looped renders keep their tail; the fade-in still applies.
+        let mut channels = vec![vec![0.5; 4410]];
+        fade_edges(&mut channels, sr, 10.0, false);
+        assert_eq!((channels[0][0], channels[0][4409]), (0.0, 0.5));
+        // One channel away from zero fades both, so the image stays put.
+        let mut channels = vec![vec![0.0; 4410], rising.iter().rev().copied().collect()];
+        fade_edges(&mut channels, sr, 10.0, true);
+        assert_eq!(channels[1][0], 0.0);
+        assert!(channels.iter().all(|ch| ch[4409] == 0.0));
+        fade_edges(&mut [], sr, 10.0, true);
+    }
+    #[test]
+    fn test_peak_normalize() {
+        let source: Vec<f64> = (0..1000).map(|n| 0.25 * (n as f64 * 0.01).sin()).collect();
+        let peak = |x: &[f64]| x.iter().fold(0.0f64, |m, v| m.max(v.abs()));
+        let normalized = |amount: f64| {
+            let mut wave = source.clone();
+            peak_normalize(&mut wave, 0.9, amount);
+            peak(&wave)
+        };
+        assert!((normalized(100.0) - 0.9).abs() < 1e-12);
+        assert_eq!(normalized(0.0), peak(&source));
+        assert!((normalized(50.0) - (peak(&source) + 0.9) / 2.0).abs() < 1e-12);
+        let mut silent = vec![0.0; 16];
+        peak_normalize(&mut silent, 0.9, 100.0);
+        assert!(silent.iter().all(|&x| x == 0.0));
+    }
+    #[test]
+    fn test_true_peak_limiter() {
+        let sr = SAMPLE_RATE as f64;
+        // A quarter-rate sine sampled 45° off its crests: the samples reach 0.71 but the
+        // waveform between them reaches 1.0.
+        let wave: Vec<f64> = (0..8820)
+            .map(|n| (std::f64::consts::FRAC_PI_2 * n as f64 + std::f64::consts::FRAC_PI_4).sin())
+            .collect();
+        let ceiling = 10f64.powf(-1.0 / 20.0);
+        let peak = |x: &[f64]| true_peaks(x)[100..x.len() - 100].iter().cloned().fold(0.0, f64::max);
+        assert!(wave.iter().all(|x| x.abs() < ceiling));
+        assert!(peak(&wave) > 0.99);
+        let mut channels = vec![wave.clone(), wave.iter().map(|x| 0.5 * x).collect()];
+        TruePeakLimiter { ceiling_db: -1.0 }.apply(&mut channels, sr);
+        assert!(peak(&channels[0]) <= ceiling + 1e-9, "{}", peak(&channels[0]));
+        assert!(peak(&channels[0]) > ceiling - 0.01);
+        // The quieter channel gets the same gain.
+        assert!(channels[0].iter().zip(&channels[1]).all(|(a, b)| (0.5 * a - b).abs() < 1e-12));
+        let mut quiet = vec![wave.iter().map(|x| 0.5 * x).collect::<Vec<f64>>()];
+        TruePeakLimiter { ceiling_db: -1.0 }.apply(&mut quiet, sr);
+        assert!(quiet[0].iter().zip(&wave).all(|(q, w)| *q == 0.5 * w));
+    }
+    #[test]
+    fn test_noise_gate_closes_on_tail() {
+        let sr = SAMPLE_RATE as f64;
+        let mut seed = 3u64;
+        let source: Vec<f64> = (0..44100).map(|n| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let hiss = 0.002 * ((seed >> 33) as f64 / (1u64 << 31) as f64 - 0.5);
+            if n < 22050 { 0.5 * (std::f64::consts::TAU * 220.0 * n as f64 / sr).sin() } else { hiss }
+        }).collect();
+        let mut out = source.clone();
+        noise_gate(&mut out, sr, -60.0);
+        assert!(out[..21800].iter().zip(&source).all(|(o, s)| (o - s).abs() < 1e-12));
+        let energy = |x: &[f64]| x.iter().map(|v| v * v).sum::<f64>();
+        assert!(energy(&out[30000..]) < 1e-4 * energy(&source[30000..]));
+        // Release is gradual: shortly after the body the tail is reduced but not yet silent.
+        assert!(out[22050 + 441..22050 + 882].iter().any(|&v| v != 0.0));
+    }
+    #[test]
+    fn test_declick_loop_seam() {
+        // A 220 Hz tone whose length doesn't hold a whole number of cycles after the loop start.
+        let source: Vec<f64> = (0..10000).map(|n| (std::f64::consts::TAU * 220.0 * n as f64 / 44100.0).sin()).collect();
+        let loop_start = 3000;
+        let seam = |x: &[f64]| (x[loop_start] - x[x.len() - 1]).abs();
+        let step = (std::f64::consts::TAU * 220.0 / 44100.0).sin();
+        assert!(seam(&source) > 5.0 * step);
+        let mut declicked = source.clone();
+        declick_loop(&mut declicked, loop_start, 220);
+        assert!(seam(&declicked) <= step + 1e-9);
+        assert_eq!(declicked[..10000 - 220], source[..10000 - 220]);
+        let mut short = vec![1.0, 2.0, 3.0];
+        declick_loop(&mut short, 0, 16);
+        assert_eq!(short, [1.0, 2.0, 3.0]);
+    }
+    /// Frequency of the strongest bin of `wave`'s spectrum, in Hz.
+    fn dominant_hz(wave: &[f64], sample_rate: f64) -> f64 {
+        let magnitude = stft_magnitude(wave, FFT_SIZE, HOP_SIZE, WindowKind::Hann, false);
+        let mut energy = vec![0.0; magnitude.nrows()];
+        magnitude.axis_iter(Axis(1)).for_each(|frame| energy.iter_mut().zip(frame).for_each(|(e, m)| *e += m * m));
+        let peak = (1..energy.len()).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap();
+        peak as f64 * sample_rate / FFT_SIZE as f64
+    }
+    #[test]
+    fn test_pitch_shift_octave() {
+        let sr = SAMPLE_RATE as f64;
+        let bin_hz = sr / FFT_SIZE as f64;
+        // A 440 Hz sine swelling in over the first half second.
+        let source: Vec<f64> = (0..44100)
+            .map(|n| (n as f64 / 22050.0).min(1.0) * 0.5 * (std::f64::consts::TAU * 440.0 * n as f64 / sr).sin())
+            .collect();
+        let mut shifted = source.clone();
+        pitch_shift(&mut shifted, sr, 1200.0);
+        assert_eq!(shifted.len(), source.len());
+        let f = dominant_hz(&shifted, sr); assert!((f - 880.0).abs() <= bin_hz, "{f}");
+        let rms = |x: &[f64]| (x.iter().map(|v| v * v).sum::<f64>() / x.len() as f64).sqrt();
+        // The swell is followed block by block, give or take the vocoder's small level loss.
+        let ratios_db: Vec<f64> = (4096..40000).step_by(4096)
+            .map(|start| 20.0 * (rms(&shifted[start..start + 4096]) / rms(&source[start..start + 4096])).log10())
+            .collect();
+        assert!(ratios_db.iter().all(|r| (r - ratios_db[0]).abs() < 0.5 && r.abs() < 2.0), "{:?}", ratios_db);
+        // Harmonics of 150 Hz under a formant at 1200 Hz: shifted up an octave, the strongest
+        // harmonic should stay by the formant instead of moving to 2400 Hz.
+        let formant = |f: f64| (-((f - 1200.0) / 300.0).powi(2)).exp() + 0.05;
+        let mut voice: Vec<f64> = (0..44100)
+            .map(|n| (1..=40).map(|h| {
+                let f = 150.0 * h as f64;
+                0.05 * formant(f) * (std::f64::consts::TAU * f * n as f64 / sr).sin()
+            }).sum())
+            .collect();
+        assert!((dominant_hz(&voice, sr) - 1200.0).abs() <= bin_hz);
+        pitch_shift(&mut voice, sr, 1200.0);
+        let peak = dominant_hz(&voice, sr);
+        assert!((peak - 1200.0).abs() <= 300.0 + bin_hz, "formant peak moved to {:.0} Hz", peak);
+        assert!(((peak / 300.0) - (peak / 300.0).round()).abs() * 300.0 <= bin_hz, "{:.0} Hz is not a harmonic of 300 Hz", peak);
+    }
+    #[test]
+    fn test_de_ess_reduces_high_band() {
+        let sr = SAMPLE_RATE as f64;
+        let mut seed = 7u64;
+        let mut noise = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as f64 / (1u64 << 31) as f64 - 0.5
+        };
+        let broadband: Vec<f64> = (0..44100).map(|_| 0.05 * noise()).collect();
+        let mut hiss: Vec<f64> = (0..44100).map(|_| noise()).collect();
+        let coeffs = Coefficients::<f64>::from_normalized_params(Type::BandPass, 2.0 * 7000.0 / sr, 2.0).unwrap();
+        forward_backward_filter(&mut hiss, &mut DirectForm1::new(coeffs), 2);
+        let source: Vec<f64> = broadband.iter().zip(&hiss).map(|(b, h)| b + 0.5 * h).collect();
+        let energy = |wave: &[f64], band: (f64, f64)| {
+            let magnitude = stft_magnitude(wave, FFT_SIZE, HOP_SIZE, WindowKind::Hann, false);
+            let bin_hz = sr / FFT_SIZE as f64;
+            magnitude.slice(ndarray::s![(band.0 / bin_hz) as usize..(band.1 / bin_hz) as usize, ..]).mapv(|m| m * m).sum()
+        };
+        assert!(band_share(&stft_magnitude_phase(&source, FFT_SIZE, HOP_SIZE, WindowKind::Hann).0, sr, SIBILANT_BAND)[10] > SIBILANT_THRESHOLD);
+        let mut processed = source.clone();
+        de_ess(&mut processed, sr, 100.0);
+        assert!(energy(&processed, SIBILANT_BAND) < 0.6 * energy(&source, SIBILANT_BAND));
+        let low = (100.0, 2000.0);
+        assert!((energy(&processed, low) / energy(&source, low) - 1.0).abs() < 0.1);
+        // Plain broadband noise has no sibilant excess and passes through untouched.
+        let mut plain = broadband.clone();
+        de_ess(&mut plain, sr, 100.0);
+        assert_eq!(plain, broadband);
+    }
+}
@@ -2,11 +2,221 @@ pub const SAMPLE_RATE: u32 = 44100;
 pub const FFT_SIZE: usize = 2048;
 pub const HOP_SIZE: usize = 512;
 pub const ORIGIN_HOP_SIZE: usize = 128;
+pub const N_MELS: usize = 128;
+/// Frequency range of the baked mel filterbank, used when generating other bin counts.
+pub const MEL_FMIN: f64 = 40.0;
+pub const MEL_FMAX: f64 = 16000.0;
 pub const FEATURE_EXT: &str = "hifi.npz";
+use clap::Parser;
 use ini::Ini;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
+use std::str::FromStr;
+use tracing::warn;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(format!("unknown log format: {}", other)),
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Float32,
+}
+impl FromStr for BitDepth {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "16" => Ok(Self::Int16),
+            "24" => Ok(Self::Int24),
+            "32f" => Ok(Self::Float32),
+            other => Err(format!("unknown output bit depth: {}", other)),
+        }
+    }
+}
+/// Sinc resampler profile for input decoding and output conversion. `High` is the most
+/// accurate; `Balanced` (~2.5x faster) and `Fast` (~7x faster) use shorter filters and cheaper
+/// interpolation, trading a wider transition band and more aliasing near Nyquist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResampleQuality {
+    Fast,
+    Balanced,
+    High,
+}
+impl FromStr for ResampleQuality {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fast" => Ok(Self::Fast),
+            "balanced" => Ok(Self::Balanced),
+            "high" => Ok(Self::High),
+            other => Err(format!("unknown resample quality: {}", other)),
+        }
+    }
+}
+/// Interpolator for the pitch curve between pitchbend points. `Akima` avoids most overshoot;
+/// `Cubic` is a C2-continuous natural spline that reads smoother on slides; `Pchip` never
+/// leaves the range of the two surrounding points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PitchInterp {
+    Akima,
+    Cubic,
+    Pchip,
+}
+impl FromStr for PitchInterp {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "akima" => Ok(Self::Akima),
+            "cubic" => Ok(Self::Cubic),
+            "pchip" => Ok(Self::Pchip),
+            other => Err(format!("unknown pitch interpolation: {}", other)),
+        }
+    }
+}
+/// Analysis window for the STFT behind the mel features. Pick the one the vocoder was trained
+/// with; ISTFT uses the same window so overlap-add still reconstructs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WindowKind {
+    Hann,
+    Hamming,
+    BlackmanHarris,
+}
+impl FromStr for WindowKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "hann" => Ok(Self::Hann),
+            "hamming" => Ok(Self::Hamming),
+            "blackman-harris" | "blackman_harris" | "blackmanharris" => Ok(Self::BlackmanHarris),
+            other => Err(format!("unknown window: {}", other)),
+        }
+    }
+}
+/// Scale of the mel the vocoder was trained on. Features are analysed and edited as natural-log
+/// magnitudes and only converted right before vocoding: `Db` is `20·log10`, `Linear` undoes the
+/// log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MelScale {
+    NaturalLog,
+    Db,
+    Linear,
+}
+impl MelScale {
+    /// Converts a natural-log magnitude to this scale.
+    pub fn encode(self, x: f64) -> f64 {
+        match self {
+            Self::NaturalLog => x,
+            Self::Db => x * 20.0 / std::f64::consts::LN_10,
+            Self::Linear => x.exp(),
+        }
+    }
+}
+impl FromStr for MelScale {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ln" | "natural_log" | "naturallog" => Ok(Self::NaturalLog),
+            "db" => Ok(Self::Db),
+            "linear" => Ok(Self::Linear),
+            other => Err(format!("unknown mel scale: {}", other)),
+        }
+    }
+}
+/// LFO waveform of the `HG` growl. `Square` is the original hard buzz; `Sine` and `Triangle`
+/// sweep the pitch smoothly and sound closer to a natural growl on some voices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoShape {
+    Square,
+    Sine,
+    Triangle,
+}
+impl FromStr for LfoShape {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "square" => Ok(Self::Square),
+            "sine" => Ok(Self::Sine),
+            "triangle" => Ok(Self::Triangle),
+            other => Err(format!("unknown LFO shape: {}", other)),
+        }
+    }
+}
+/// Meaning of the `P` flag. `Peak` normalizes the note's peak towards `peak_limit` by the
+/// flag's amount; `Loudness` is the old behaviour, where it scales the LUFS normalization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PFlagMode {
+    Peak,
+    Loudness,
+}
+impl FromStr for PFlagMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "peak" => Ok(Self::Peak),
+            "loudness" => Ok(Self::Loudness),
+            other => Err(format!("unknown P flag mode: {}", other)),
+        }
+    }
+}
+/// How loop mode extends the sustained region. `Reflect` mirrors the mel back from its end;
+/// `Crossfade` blends the region's tail into its head so the repeat has no fold; `PingPong`
+/// plays it forward and backward in turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopStyle {
+    Reflect,
+    Crossfade,
+    PingPong,
+}
+impl FromStr for LoopStyle {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reflect" => Ok(Self::Reflect),
+            "crossfade" => Ok(Self::Crossfade),
+            "pingpong" | "ping-pong" => Ok(Self::PingPong),
+            other => Err(format!("unknown loop style: {}", other)),
+        }
+    }
+}
+/// ONNX Runtime backend for the vocoder and HNSEP sessions. Providers that fail to initialize
+/// fall back to CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionProvider {
+    Cpu,
+    Cuda,
+    TensorRt,
+    /// Windows GPUs of any vendor; needs the `directml` cargo feature.
+    DirectMl,
+    /// Apple GPU/Neural Engine; needs the `coreml` cargo feature.
+    CoreMl,
+}
+impl FromStr for ExecutionProvider {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "cpu" => Ok(Self::Cpu),
+            "cuda" => Ok(Self::Cuda),
+            "tensorrt" => Ok(Self::TensorRt),
+            "directml" => Ok(Self::DirectMl),
+            "coreml" => Ok(Self::CoreMl),
+            other => Err(format!("unknown execution provider: {}", other)),
+        }
+    }
+}
 #[derive(Debug, Clone, PartialEq)]
 pub struct HifiConfig {
     pub vocoder_path: PathBuf,
@@ -14,21 +224,139 @@ pub struct HifiConfig {
     pub wave_norm: bool,
     pub trim_silence: bool,
     pub silence_threshold: f64,
+    pub loudness_target_lufs: f64,
     pub loop_mode: bool,
     pub peak_limit: f64,
+    pub p_flag: PFlagMode,
     pub fill: usize,
     pub max_workers: usize,
+    pub port: u16,
+    pub bind_address: IpAddr,
+    pub cache_clear_root: PathBuf,
+    pub priority_workers: usize,
+    pub render_timeout_secs: u64,
+    pub uds_path: PathBuf,
+    pub api_token: String,
+    pub log_format: LogFormat,
+    pub max_body_bytes: usize,
+    pub max_batch_body_bytes: usize,
+    pub rate_limit_per_sec: u32,
+    pub output_bit_depth: BitDepth,
+    pub output_sample_rate: u32,
+    pub loop_markers: bool,
+    pub strict_flags: bool,
+    pub pitch_interp: PitchInterp,
+    pub window: WindowKind,
+    pub stft_center: bool,
+    pub fft_cache_entries: usize,
+    pub analysis_speed: f64,
+    pub mel_scale: MelScale,
+    pub compressor: bool,
+    pub compressor_threshold_db: f64,
+    pub compressor_ratio: f64,
+    pub compressor_knee_db: f64,
+    pub compressor_attack_ms: f64,
+    pub compressor_release_ms: f64,
+    pub noise_gate: bool,
+    pub noise_gate_threshold: f64,
+    pub true_peak_limiter: bool,
+    pub true_peak_ceiling_db: f64,
+    pub dither: bool,
+    pub preserve_channels: bool,
+    pub resample_quality: ResampleQuality,
+    pub decoded_cache_entries: usize,
+    pub limit_input_peak: bool,
+    pub input_extension_priority: Vec<String>,
+    pub cache_max_bytes: u64,
+    pub cache_ttl_secs: u64,
+    pub cache_dir: PathBuf,
+    pub feature_memory_entries: usize,
+    pub cache_lock_timeout_ms: u64,
+    pub cache_enabled: bool,
+    pub execution_provider: ExecutionProvider,
+    pub device_id: i32,
+    pub coreml_cpu_fallback: bool,
+    pub ort_intra_threads: usize,
+    pub ort_inter_threads: usize,
+    pub mel_bins: usize,
+    pub mel_fmin: Option<f64>,
+    pub mel_fmax: Option<f64>,
+    pub mel_htk: bool,
+    pub vocoder_mel_name: String,
+    pub vocoder_f0_name: String,
+    pub vocoder_output_name: String,
+    pub hnsep_input_name: String,
+    pub hnsep_output_name: String,
+    pub extra_vocoder_paths: Vec<PathBuf>,
+    pub vocoder_batch_size: usize,
+    pub vocoder_batch_window_ms: u64,
+    pub gender_warp: bool,
+    pub loop_style: LoopStyle,
+    pub loop_crossfade_frames: usize,
+    pub loop_declick_ms: f64,
+    pub edge_fade_ms: f64,
+    pub growl_lfo: LfoShape,
+}
+/// Command-line overrides, applied last (CLI > env > ini > defaults).
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct CliArgs {
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[arg(long)]
+    pub max_workers: Option<usize>,
+    #[arg(long)]
+    pub vocoder_path: Option<PathBuf>,
+    #[arg(long)]
+    pub hnsep_path: Option<PathBuf>,
+    #[arg(long)]
+    pub wave_norm: Option<bool>,
+    #[arg(long)]
+    pub loop_mode: Option<bool>,
+}
+impl CliArgs {
+    fn apply(&self, config: &mut HifiConfig) {
+        if let Some(port) = self.port { config.port = port; }
+        if let Some(max_workers) = self.max_workers { config.max_workers = max_workers; }
+        if let Some(path) = &self.vocoder_path { config.vocoder_path = path.clone(); }
+        if let Some(path) = &self.hnsep_path { config.hnsep_path = path.clone(); }
+        if let Some(wave_norm) = self.wave_norm { config.wave_norm = wave_norm; }
+        if let Some(loop_mode) = self.loop_mode { config.loop_mode = loop_mode; }
+    }
 }
-pub static HIFI_CONFIG: Lazy<HifiConfig> = Lazy::new(|| load_hifi_config());
+/// Must be set before `HIFI_CONFIG` is first touched.
+pub static CLI_ARGS: OnceCell<CliArgs> = OnceCell::new();
+pub static HIFI_CONFIG: Lazy<HifiConfig> = Lazy::new(|| {
+    let mut config = load_hifi_config();
+    if let Some(cli) = CLI_ARGS.get() {
+        cli.apply(&mut config);
+    }
+    config.sanitize();
+    config
+});
+/// Precedence for every field: `HIFI_<KEY>` env var > `hificonfig.ini` > default.
+/// CLI flags are applied afterwards in `HIFI_CONFIG`.
 fn load_hifi_config() -> HifiConfig {
-    let ini = match Ini::load_from_file("hificonfig.ini") {
-        Ok(ini) => ini,
-        Err(_) => return HifiConfig::default(),
-    };
-    let def_sec: HashMap<String, String> = ini
-        .section(None::<String>)
-        .map(|props| props.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+    let mut def_sec = Ini::load_from_file("hificonfig.ini")
+        .map(|ini| ini_values(&ini))
         .unwrap_or_default();
+    def_sec.extend(env_overrides(std::env::vars()));
+    parse_hifi_config(&def_sec)
+}
+/// rust-ini keeps trailing `; comment` text in values, so strip it here.
+fn ini_values(ini: &Ini) -> HashMap<String, String> {
+    ini.section(None::<String>)
+        .map(|props| {
+            props.iter()
+                .map(|(k, v)| {
+                    let v = v.find(" ;").or_else(|| v.find("\t;")).map_or(v, |i| &v[..i]);
+                    (k.to_string(), v.trim().to_string())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+fn parse_hifi_config(def_sec: &HashMap<String, String>) -> HifiConfig {
     HifiConfig {
         vocoder_path: def_sec
             .get("vocoder_path")
@@ -56,10 +384,18 @@ fn load_hifi_config() -> HifiConfig {
             .get("silence_threshold")
             .and_then(|s| s.parse().ok())
             .unwrap_or(-52.0),
+        loudness_target_lufs: def_sec
+            .get("loudness_target_lufs")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(-16.0),
         peak_limit: def_sec
             .get("peak_limit")
             .and_then(|s| s.parse().ok())
             .unwrap_or(1.0),
+        p_flag: def_sec
+            .get("p_flag")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(PFlagMode::Peak),
         fill: def_sec
             .get("fill")
             .and_then(|s| s.parse().ok())
@@ -68,8 +404,343 @@ fn load_hifi_config() -> HifiConfig {
             .get("max_workers")
             .and_then(|s| s.parse().ok())
             .unwrap_or(2),
+        port: def_sec
+            .get("port")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(8572),
+        bind_address: def_sec
+            .get("bind_address")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        cache_clear_root: def_sec
+            .get("cache_clear_root")
+            .cloned()
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+        priority_workers: def_sec
+            .get("priority_workers")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+        render_timeout_secs: def_sec
+            .get("render_timeout_secs")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(120),
+        uds_path: def_sec
+            .get("uds_path")
+            .cloned()
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+        api_token: def_sec
+            .get("api_token")
+            .cloned()
+            .unwrap_or_default(),
+        log_format: def_sec
+            .get("log_format")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(LogFormat::Text),
+        max_body_bytes: def_sec
+            .get("max_body_bytes")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(8192),
+        max_batch_body_bytes: def_sec
+            .get("max_batch_body_bytes")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1 << 20),
+        rate_limit_per_sec: def_sec
+            .get("rate_limit_per_sec")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+        output_bit_depth: def_sec
+            .get("output_bit_depth")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(BitDepth::Int16),
+        output_sample_rate: def_sec
+            .get("output_sample_rate")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(SAMPLE_RATE),
+        loop_markers: def_sec
+            .get("loop_markers")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        strict_flags: def_sec
+            .get("strict_flags")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        pitch_interp: def_sec
+            .get("pitch_interp")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(PitchInterp::Akima),
+        window: def_sec
+            .get("window")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(WindowKind::Hann),
+        stft_center: def_sec
+            .get("stft_center")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        fft_cache_entries: def_sec
+            .get("fft_cache_entries")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(16),
+        analysis_speed: def_sec
+            .get("analysis_speed")
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|s| s.is_finite())
+            .map_or(1.0, |s| s.clamp(0.25, 4.0)),
+        mel_scale: def_sec
+            .get("mel_scale")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(MelScale::NaturalLog),
+        compressor: def_sec
+            .get("compressor")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        compressor_threshold_db: def_sec
+            .get("compressor_threshold_db")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(-18.0),
+        compressor_ratio: def_sec
+            .get("compressor_ratio")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(4.0),
+        compressor_knee_db: def_sec
+            .get("compressor_knee_db")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(6.0),
+        compressor_attack_ms: def_sec
+            .get("compressor_attack_ms")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(5.0),
+        compressor_release_ms: def_sec
+            .get("compressor_release_ms")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(80.0),
+        noise_gate: def_sec
+            .get("noise_gate")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        noise_gate_threshold: def_sec
+            .get("noise_gate_threshold")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(-60.0),
+        true_peak_limiter: def_sec
+            .get("true_peak_limiter")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        true_peak_ceiling_db: def_sec
+            .get("true_peak_ceiling_db")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(-1.0),
+        dither: def_sec
+            .get("dither")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(true),
+        preserve_channels: def_sec
+            .get("preserve_channels")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        resample_quality: def_sec
+            .get("resample_quality")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(ResampleQuality::High),
+        decoded_cache_entries: def_sec
+            .get("decoded_cache_entries")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(32),
+        limit_input_peak: def_sec
+            .get("limit_input_peak")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        input_extension_priority: def_sec
+            .get("input_extension_priority")
+            .map(|s| parse_extension_list(s))
+            .filter(|list| !list.is_empty())
+            .unwrap_or_else(default_extensions),
+        cache_max_bytes: def_sec
+            .get("cache_max_bytes")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+        cache_ttl_secs: def_sec
+            .get("cache_ttl_secs")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+        cache_dir: def_sec
+            .get("cache_dir")
+            .cloned()
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+        feature_memory_entries: def_sec
+            .get("feature_memory_entries")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(32),
+        cache_lock_timeout_ms: def_sec
+            .get("cache_lock_timeout_ms")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(5000),
+        cache_enabled: def_sec
+            .get("cache_enabled")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(true),
+        execution_provider: def_sec
+            .get("execution_provider")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(ExecutionProvider::Cpu),
+        device_id: def_sec
+            .get("device_id")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+        coreml_cpu_fallback: def_sec
+            .get("coreml_cpu_fallback")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(true),
+        ort_intra_threads: def_sec
+            .get("ort_intra_threads")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+        ort_inter_threads: def_sec
+            .get("ort_inter_threads")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+        mel_bins: def_sec
+            .get("mel_bins")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(N_MELS),
+        mel_fmin: def_sec
+            .get("mel_fmin")
+            .and_then(|s| s.parse().ok()),
+        mel_fmax: def_sec
+            .get("mel_fmax")
+            .and_then(|s| s.parse().ok()),
+        mel_htk: def_sec
+            .get("mel_htk")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(false),
+        vocoder_mel_name: def_sec
+            .get("vocoder_mel_name")
+            .cloned()
+            .unwrap_or_else(|| "mel".to_string()),
+        vocoder_f0_name: def_sec
+            .get("vocoder_f0_name")
+            .cloned()
+            .unwrap_or_else(|| "f0".to_string()),
+        vocoder_output_name: def_sec
+            .get("vocoder_output_name")
+            .cloned()
+            .unwrap_or_else(|| "waveform".to_string()),
+        hnsep_input_name: def_sec
+            .get("hnsep_input_name")
+            .cloned()
+            .unwrap_or_else(|| "input".to_string()),
+        hnsep_output_name: def_sec
+            .get("hnsep_output_name")
+            .cloned()
+            .unwrap_or_else(|| "output".to_string()),
+        extra_vocoder_paths: def_sec
+            .get("extra_vocoder_paths")
+            .map(|s| s.split(',').map(str::trim).filter(|p| !p.is_empty()).map(PathBuf::from).collect())
+            .unwrap_or_default(),
+        vocoder_batch_size: def_sec
+            .get("vocoder_batch_size")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1),
+        vocoder_batch_window_ms: def_sec
+            .get("vocoder_batch_window_ms")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(5),
+        gender_warp: def_sec
+            .get("gender_warp")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(true),
+        loop_style: def_sec
+            .get("loop_style")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(LoopStyle::Reflect),
+        loop_crossfade_frames: def_sec
+            .get("loop_crossfade_frames")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(16),
+        loop_declick_ms: def_sec
+            .get("loop_declick_ms")
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|ms| ms.is_finite())
+            .map_or(0.0, |ms| ms.clamp(0.0, 50.0)),
+        edge_fade_ms: def_sec
+            .get("edge_fade_ms")
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|ms| ms.is_finite())
+            .map_or(0.0, |ms| ms.clamp(0.0, 50.0)),
+        growl_lfo: def_sec
+            .get("growl_lfo")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(LfoShape::Square),
     }
 }
+fn parse_extension_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|ext| ext.trim().trim_start_matches('.'))
+        .filter(|ext| !ext.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+fn default_extensions() -> Vec<String> {
+    parse_extension_list("wav,flac,ogg,mp3,aac")
+}
+impl HifiConfig {
+    /// Replaces values that would break rendering or the server, warning for each.
+    fn sanitize(&mut self) {
+        if self.max_workers == 0 {
+            warn!("max_workers must be at least 1, using 1");
+            self.max_workers = 1;
+        }
+        if self.silence_threshold.is_nan() || self.silence_threshold > 0.0 {
+            warn!("silence_threshold {} dB is above 0, using -52.0", self.silence_threshold);
+            self.silence_threshold = -52.0;
+        }
+        if self.loudness_target_lufs.is_nan() || self.loudness_target_lufs > 0.0 {
+            warn!("loudness_target_lufs {} is above 0, using -16.0", self.loudness_target_lufs);
+            self.loudness_target_lufs = -16.0;
+        }
+        if self.output_sample_rate == 0 {
+            warn!("output_sample_rate must be positive, using {}", SAMPLE_RATE);
+            self.output_sample_rate = SAMPLE_RATE;
+        }
+        if self.peak_limit.is_nan() || self.peak_limit <= 0.0 {
+            warn!("peak_limit {} must be positive, using 1.0", self.peak_limit);
+            self.peak_limit = 1.0;
+        }
+        if self.compressor_ratio.is_nan() || self.compressor_ratio < 1.0 {
+            warn!("compressor_ratio {} must be at least 1, using 4.0", self.compressor_ratio);
+            self.compressor_ratio = 4.0;
+        }
+        for (name, value) in [
+            ("compressor_knee_db", &mut self.compressor_knee_db),
+            ("compressor_attack_ms", &mut self.compressor_attack_ms),
+            ("compressor_release_ms", &mut self.compressor_release_ms),
+        ] {
+            if value.is_nan() || *value < 0.0 {
+                warn!("{} {} must not be negative, using 0", name, value);
+                *value = 0.0;
+            }
+        }
+        if self.true_peak_ceiling_db.is_nan() || self.true_peak_ceiling_db > 0.0 {
+            warn!("true_peak_ceiling_db {} is above 0, using -1.0", self.true_peak_ceiling_db);
+            self.true_peak_ceiling_db = -1.0;
+        }
+        if self.mel_bins == 0 {
+            warn!("mel_bins must be positive, using {}", N_MELS);
+            self.mel_bins = N_MELS;
+        }
+        let (fmin, fmax) = (self.mel_fmin.unwrap_or(MEL_FMIN), self.mel_fmax.unwrap_or(MEL_FMAX));
+        if !(0.0..fmax).contains(&fmin) || fmax > SAMPLE_RATE as f64 / 2.0 {
+            warn!("mel range {}-{} Hz is invalid, using {}-{}", fmin, fmax, MEL_FMIN, MEL_FMAX);
+            (self.mel_fmin, self.mel_fmax) = (None, None);
+        }
+    }
+}
+fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> HashMap<String, String> {
+    vars.filter_map(|(k, v)| k.strip_prefix("HIFI_").map(|key| (key.to_ascii_lowercase(), v)))
+        .collect()
+}
 impl Default for HifiConfig {
     fn default() -> Self {
         Self {
@@ -78,10 +749,78 @@ impl Default for HifiConfig {
             wave_norm: true,
             trim_silence: true,
             silence_threshold: -52.0,
+            loudness_target_lufs: -16.0,
             loop_mode: true,
             peak_limit: 1.0,
+            p_flag: PFlagMode::Peak,
             fill: 6,
             max_workers: 2,
+            port: 8572,
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            cache_clear_root: PathBuf::new(),
+            priority_workers: 0,
+            render_timeout_secs: 120,
+            uds_path: PathBuf::new(),
+            api_token: String::new(),
+            log_format: LogFormat::Text,
+            max_body_bytes: 8192,
+            max_batch_body_bytes: 1 << 20,
+            rate_limit_per_sec: 0,
+            output_bit_depth: BitDepth::Int16,
+            output_sample_rate: SAMPLE_RATE,
+            loop_markers: false,
+            strict_flags: false,
+            pitch_interp: PitchInterp::Akima,
+            window: WindowKind::Hann,
+            stft_center: false,
+            fft_cache_entries: 16,
+            analysis_speed: 1.0,
+            mel_scale: MelScale::NaturalLog,
+            compressor: false,
+            compressor_threshold_db: -18.0,
+            compressor_ratio: 4.0,
+            compressor_knee_db: 6.0,
+            compressor_attack_ms: 5.0,
+            compressor_release_ms: 80.0,
+            noise_gate: false,
+            noise_gate_threshold: -60.0,
+            true_peak_limiter: false,
+            true_peak_ceiling_db: -1.0,
+            dither: true,
+            preserve_channels: false,
+            resample_quality: ResampleQuality::High,
+            decoded_cache_entries: 32,
+            limit_input_peak: false,
+            input_extension_priority: default_extensions(),
+            cache_max_bytes: 0,
+            cache_ttl_secs: 0,
+            cache_dir: PathBuf::new(),
+            feature_memory_entries: 32,
+            cache_lock_timeout_ms: 5000,
+            cache_enabled: true,
+            execution_provider: ExecutionProvider::Cpu,
+            device_id: 0,
+            coreml_cpu_fallback: true,
+            ort_intra_threads: 0,
+            ort_inter_threads: 0,
+            mel_bins: N_MELS,
+            mel_fmin: None,
+            mel_fmax: None,
+            mel_htk: false,
+            vocoder_mel_name: "mel".to_string(),
+            vocoder_f0_name: "f0".to_string(),
+            vocoder_output_name: "waveform".to_string(),
+            hnsep_input_name: "input".to_string(),
+            hnsep_output_name: "output".to_string(),
+            extra_vocoder_paths: Vec::new(),
+            vocoder_batch_size: 1,
+            vocoder_batch_window_ms: 5,
+            gender_warp: true,
+            loop_style: LoopStyle::Reflect,
+            loop_crossfade_frames: 16,
+            loop_declick_ms: 0.0,
+            edge_fade_ms: 0.0,
+            growl_lfo: LfoShape::Square,
         }
     }
 }
@@ -103,10 +842,182 @@ mod tests {
         assert_eq!(default.wave_norm, true);
         assert_eq!(default.trim_silence, true);
         assert_eq!(default.silence_threshold, -52.0);
+        assert_eq!(default.loudness_target_lufs, -16.0);
         assert_eq!(default.loop_mode, true);
         assert_eq!(default.peak_limit, 1.0);
+        assert_eq!(default.p_flag, PFlagMode::Peak);
         assert_eq!(default.fill, 6);
         assert_eq!(default.max_workers, 2);
+        assert_eq!(default.port, 8572);
+        assert_eq!(default.bind_address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(default.cache_clear_root.as_os_str().is_empty());
+        assert_eq!(default.priority_workers, 0);
+        assert_eq!(default.render_timeout_secs, 120);
+        assert!(default.uds_path.as_os_str().is_empty());
+        assert!(default.api_token.is_empty());
+        assert_eq!(default.log_format, LogFormat::Text);
+        assert_eq!(default.max_body_bytes, 8192);
+        assert_eq!(default.max_batch_body_bytes, 1 << 20);
+        assert_eq!(default.rate_limit_per_sec, 0);
+        assert_eq!(default.output_bit_depth, BitDepth::Int16);
+        assert_eq!(default.output_sample_rate, 44100);
+        assert!(!default.loop_markers);
+        assert!(!default.strict_flags);
+        assert_eq!(default.pitch_interp, PitchInterp::Akima);
+        assert_eq!(default.window, WindowKind::Hann);
+        assert!(!default.stft_center);
+        assert_eq!(default.fft_cache_entries, 16);
+        assert_eq!(default.analysis_speed, 1.0);
+        assert_eq!(default.mel_scale, MelScale::NaturalLog);
+        assert!(!default.compressor);
+        assert_eq!((default.compressor_threshold_db, default.compressor_ratio, default.compressor_knee_db), (-18.0, 4.0, 6.0));
+        assert_eq!((default.compressor_attack_ms, default.compressor_release_ms), (5.0, 80.0));
+        assert!(!default.noise_gate);
+        assert_eq!(default.noise_gate_threshold, -60.0);
+        assert_eq!((default.true_peak_limiter, default.true_peak_ceiling_db), (false, -1.0));
+        assert!(default.dither);
+        assert!(!default.preserve_channels);
+        assert_eq!(default.resample_quality, ResampleQuality::High);
+        assert_eq!(default.decoded_cache_entries, 32);
+        assert!(!default.limit_input_peak);
+        assert_eq!(default.input_extension_priority, ["wav", "flac", "ogg", "mp3", "aac"]);
+        assert_eq!(default.cache_max_bytes, 0);
+        assert_eq!(default.cache_ttl_secs, 0);
+        assert!(default.cache_dir.as_os_str().is_empty());
+        assert_eq!(default.feature_memory_entries, 32);
+        assert_eq!(default.cache_lock_timeout_ms, 5000);
+        assert!(default.cache_enabled);
+        assert_eq!(default.execution_provider, ExecutionProvider::Cpu);
+        assert_eq!(default.device_id, 0);
+        assert!(default.coreml_cpu_fallback);
+        assert_eq!((default.ort_intra_threads, default.ort_inter_threads), (0, 0));
+        assert_eq!(default.mel_bins, N_MELS);
+        assert_eq!((default.mel_fmin, default.mel_fmax, default.mel_htk), (None, None, false));
+        assert_eq!([&default.vocoder_mel_name, &default.vocoder_f0_name, &default.vocoder_output_name], ["mel", "f0", "waveform"]);
+        assert_eq!([&default.hnsep_input_name, &default.hnsep_output_name], ["input", "output"]);
+        assert!(default.extra_vocoder_paths.is_empty());
+        assert_eq!((default.vocoder_batch_size, default.vocoder_batch_window_ms), (1, 5));
+        assert!(default.gender_warp);
+        assert_eq!((default.loop_style, default.loop_crossfade_frames), (LoopStyle::Reflect, 16));
+        assert_eq!(default.loop_declick_ms, 0.0);
+        assert_eq!(default.edge_fade_ms, 0.0);
+        assert_eq!(default.growl_lfo, LfoShape::Square);
+        assert_eq!(parse_extension_list(" .FLAC, wav,,ogg "), ["FLAC", "wav", "ogg"]);
+    }
+    #[test]
+    fn test_sanitize_bad_values() {
+        let ini = Ini::load_from_str(
+            "max_workers = 0 ; 工作线程数\nsilence_threshold = 12.0\nloudness_target_lufs = 3.0\npeak_limit = -1.0 ; 峰值限制\nfill = 3 ; 填充帧数\nmel_bins = 0\nmel_fmin = 9000\nmel_fmax = 8000\n"
+        ).unwrap();
+        let mut config = parse_hifi_config(&ini_values(&ini));
+        assert_eq!(config.fill, 3);
+        assert_eq!(config.max_workers, 0);
+        config.sanitize();
+        assert_eq!(config.max_workers, 1);
+        assert_eq!(config.silence_threshold, -52.0);
+        assert_eq!(config.loudness_target_lufs, -16.0);
+        assert_eq!(config.peak_limit, 1.0);
+        assert_eq!(config.mel_bins, N_MELS);
+        assert_eq!((config.mel_fmin, config.mel_fmax), (None, None));
+        let mut default = HifiConfig::default();
+        default.sanitize();
+        assert_eq!(default, HifiConfig::default());
+    }
+    #[test]
+    fn test_env_overrides() {
+        let vars = [
+            ("HIFI_MAX_WORKERS", "4"),
+            ("HIFI_VOCODER_PATH", "/models/vocoder.onnx"),
+            ("PATH", "/usr/bin"),
+        ].map(|(k, v)| (k.to_string(), v.to_string()));
+        let overrides = env_overrides(vars.into_iter());
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides["max_workers"], "4");
+        assert_eq!(overrides["vocoder_path"], "/models/vocoder.onnx");
+    }
+    #[test]
+    fn test_cli_overrides() {
+        let cli = CliArgs::try_parse_from(["hifiserver", "--port", "9000", "--wave-norm", "false"]).unwrap();
+        let mut config = HifiConfig::default();
+        cli.apply(&mut config);
+        assert_eq!(config.port, 9000);
+        assert!(!config.wave_norm);
+        assert_eq!(config.max_workers, HifiConfig::default().max_workers);
+        assert!(CliArgs::try_parse_from(["hifiserver", "--port", "abc"]).is_err());
+    }
+    #[test]
+    fn test_log_format_parse() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!(" Text ".parse(), Ok(LogFormat::Text));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+    #[test]
+    fn test_bit_depth_parse() {
+        assert_eq!("16".parse(), Ok(BitDepth::Int16));
+        assert_eq!("24".parse(), Ok(BitDepth::Int24));
+        assert_eq!("32F".parse(), Ok(BitDepth::Float32));
+        assert!("32".parse::<BitDepth>().is_err());
+    }
+    #[test]
+    fn test_resample_quality_parse() {
+        assert_eq!("Fast".parse(), Ok(ResampleQuality::Fast));
+        assert_eq!("balanced".parse(), Ok(ResampleQuality::Balanced));
+        assert!("ultra".parse::<ResampleQuality>().is_err());
+    }
+    #[test]
+    fn test_pitch_interp_parse() {
+        assert_eq!("Cubic".parse(), Ok(PitchInterp::Cubic));
+        assert_eq!(" akima ".parse(), Ok(PitchInterp::Akima));
+        assert_eq!("PCHIP".parse(), Ok(PitchInterp::Pchip));
+        assert!("linear".parse::<PitchInterp>().is_err());
+    }
+    #[test]
+    fn test_window_kind_parse() {
+        assert_eq!("Hamming".parse(), Ok(WindowKind::Hamming));
+        assert_eq!(" hann".parse(), Ok(WindowKind::Hann));
+        assert_eq!("Blackman-Harris".parse(), Ok(WindowKind::BlackmanHarris));
+        assert!("kaiser".parse::<WindowKind>().is_err());
+    }
+    #[test]
+    fn test_mel_scale() {
+        assert_eq!("dB".parse(), Ok(MelScale::Db));
+        assert_eq!("ln".parse(), Ok(MelScale::NaturalLog));
+        assert_eq!(" Linear".parse(), Ok(MelScale::Linear));
+        assert!("log2".parse::<MelScale>().is_err());
+        for magnitude in [1e-9, 0.01, 1.0, 3.5] {
+            let log = f64::ln(magnitude);
+            assert_eq!(MelScale::NaturalLog.encode(log), log);
+            assert!((MelScale::Db.encode(log) - 20.0 * magnitude.log10()).abs() < 1e-9);
+            assert!((MelScale::Linear.encode(log) - magnitude).abs() < 1e-12);
+        }
+    }
+    #[test]
+    fn test_lfo_shape_parse() {
+        assert_eq!("Sine".parse(), Ok(LfoShape::Sine));
+        assert_eq!(" triangle".parse(), Ok(LfoShape::Triangle));
+        assert_eq!("SQUARE".parse(), Ok(LfoShape::Square));
+        assert!("saw".parse::<LfoShape>().is_err());
+    }
+    #[test]
+    fn test_p_flag_mode_parse() {
+        assert_eq!(" Peak".parse(), Ok(PFlagMode::Peak));
+        assert_eq!("LOUDNESS".parse(), Ok(PFlagMode::Loudness));
+        assert!("rms".parse::<PFlagMode>().is_err());
+    }
+    #[test]
+    fn test_loop_style_parse() {
+        assert_eq!(" Crossfade".parse(), Ok(LoopStyle::Crossfade));
+        assert_eq!("reflect".parse(), Ok(LoopStyle::Reflect));
+        assert_eq!("PingPong".parse(), Ok(LoopStyle::PingPong));
+        assert!("bounce".parse::<LoopStyle>().is_err());
+    }
+    #[test]
+    fn test_execution_provider_parse() {
+        assert_eq!("CUDA".parse(), Ok(ExecutionProvider::Cuda));
+        assert_eq!("tensorrt".parse(), Ok(ExecutionProvider::TensorRt));
+        assert_eq!("DirectML".parse(), Ok(ExecutionProvider::DirectMl));
+        assert_eq!("coreml".parse(), Ok(ExecutionProvider::CoreMl));
+        assert!("opencl".parse::<ExecutionProvider>().is_err());
     }
     #[test]
     fn test_global_config_init() {
@@ -5,27 +5,52 @@ mod utils;
 mod model;
 mod server;
 use anyhow::Result;
+use std::net::SocketAddr;
 use tokio;
 use tracing_subscriber::{fmt, prelude::*};
-use crate::consts::HIFI_CONFIG;
+use clap::Parser;
+use crate::consts::{CliArgs, CLI_ARGS, HIFI_CONFIG, LogFormat};
 use mimalloc::MiMalloc;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 fn init_logging() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(tracing::level_filters::LevelFilter::INFO)
-        .with(fmt::layer()
-            .without_time() 
-            .with_target(false) 
-            .with_thread_names(false)) 
-        .init();
+    // Loading the config may warn about sanitized values, before the real subscriber exists.
+    let fallback = fmt().without_time().with_target(false).finish();
+    let log_format = tracing::subscriber::with_default(fallback, || HIFI_CONFIG.log_format);
+    let registry = tracing_subscriber::registry()
+        .with(tracing::level_filters::LevelFilter::INFO);
+    match log_format {
+        LogFormat::Text => registry
+            .with(fmt::layer()
+                .without_time() 
+                .with_target(false) 
+                .with_thread_names(false)) 
+            .init(),
+        LogFormat::Json => registry
+            .with(fmt::layer()
+                .json()
+                .with_target(false)
+                .with_current_span(true))
+            .init(),
+    }
     Ok(())
 }
 #[tokio::main]
 async fn main() -> Result<()> {
+    CLI_ARGS.set(CliArgs::parse()).expect("CLI arguments already set");
     init_logging()?;
+    let mut resolved = HIFI_CONFIG.clone();
+    if !resolved.api_token.is_empty() {
+        resolved.api_token = "<redacted>".to_string();
+    }
+    tracing::info!("Resolved config: {:?}", resolved);
+    if !HIFI_CONFIG.cache_dir.as_os_str().is_empty() {
+        utils::cache::CACHE_MANAGER.cleanup_stale_locks(&HIFI_CONFIG.cache_dir);
+    }
     model::initialize_models();
-    tracing::info!("starting_server_on_0.0.0.0:{}",8572);
-    server::run(8572, HIFI_CONFIG.max_workers).await;
+    model::warmup();
+    let addr = SocketAddr::new(HIFI_CONFIG.bind_address, HIFI_CONFIG.port);
+    tracing::info!("starting_server_on_{}", addr);
+    server::run(addr, HIFI_CONFIG.max_workers).await;
     Ok(())
 }
\ No newline at end of file
@@ -1,33 +1,252 @@
+pub mod batch;
 pub mod hnsep;
 pub mod hifigan;
-use std::sync::{Arc, Mutex};
-use once_cell::sync::OnceCell;
-use crate::consts::HIFI_CONFIG;
-use crate::model::{hifigan::HiFiGANLoader, hnsep::HNSEPLoader};
-pub static VOCODER: OnceCell<Arc<Mutex<HiFiGANLoader>>> = OnceCell::new();
-pub static REMOVER: OnceCell<Arc<Mutex<HNSEPLoader>>> = OnceCell::new();
+pub mod pool;
+use std::{fs::File, io, path::Path, time::{Duration, Instant}};
+use ndarray::Array2;
+use once_cell::sync::{Lazy, OnceCell};
+use ort::{ep::{self, ExecutionProviderDispatch}, session::{Session, builder::GraphOptimizationLevel}, value::Outlet};
+use sha2::{Digest, Sha256};
+use crate::consts::{ExecutionProvider, FFT_SIZE, HIFI_CONFIG, HOP_SIZE, ORIGIN_HOP_SIZE, SAMPLE_RATE};
+use crate::utils::mel::MEL_BASIS;
+use crate::model::{batch::Batcher, hifigan::HiFiGANLoader, hnsep::HNSEPLoader, pool::{Pool, Pooled}};
+/// Index 0 is `vocoder_path`; the rest follow `extra_vocoder_paths` in order.
+pub static VOCODERS: OnceCell<Vec<Pool<HiFiGANLoader>>> = OnceCell::new();
+type VocoderBatcher = Batcher<(Array2<f64>, Vec<f64>), Result<Vec<f64>, String>>;
+/// One per vocoder; only set when `vocoder_batch_size` > 1.
+static BATCHERS: OnceCell<Vec<VocoderBatcher>> = OnceCell::new();
+pub static REMOVER: OnceCell<Pool<HNSEPLoader>> = OnceCell::new();
+/// Bumped whenever the layout or meaning of the feature cache changes.
+const FEATURE_CACHE_VERSION: u32 = 2;
+/// Identifies the mel configuration and vocoder that feature caches were produced for.
+pub static MODEL_SIGNATURE: Lazy<Vec<u8>> = Lazy::new(|| model_signature(&HIFI_CONFIG.vocoder_path, &MEL_BASIS));
+fn model_signature(vocoder_path: &Path, mel_basis: &[Vec<(usize, f64)>]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for param in [FEATURE_CACHE_VERSION, SAMPLE_RATE, FFT_SIZE as u32, HOP_SIZE as u32, ORIGIN_HOP_SIZE as u32, HIFI_CONFIG.window as u32, HIFI_CONFIG.stft_center as u32] {
+        hasher.update(param.to_le_bytes());
+    }
+    hasher.update(HIFI_CONFIG.analysis_speed.to_le_bytes());
+    for &(bin, weight) in mel_basis.iter().flatten() {
+        hasher.update((bin as u64).to_le_bytes());
+        hasher.update(weight.to_le_bytes());
+    }
+    if let Err(e) = File::open(vocoder_path).and_then(|mut file| io::copy(&mut file, &mut hasher)) {
+        tracing::warn!("Hash vocoder {} failed: {}", vocoder_path.display(), e);
+    }
+    hasher.finalize().to_vec()
+}
+fn provider_dispatch(provider: ExecutionProvider, device_id: i32) -> Option<ExecutionProviderDispatch> {
+    match provider {
+        ExecutionProvider::Cpu => None,
+        ExecutionProvider::Cuda => Some(ep::CUDA::default().with_device_id(device_id).build()),
+        ExecutionProvider::TensorRt => Some(ep::TensorRT::default().with_device_id(device_id).build()),
+        #[cfg(feature = "directml")]
+        ExecutionProvider::DirectMl => Some(ep::DirectML::default().with_device_id(device_id).build()),
+        #[cfg(not(feature = "directml"))]
+        ExecutionProvider::DirectMl => {
+            tracing::warn!("DirectML support not compiled in (build with `--features directml`)");
+            None
+        }
+        // Both models take a dynamic time axis (HNSEP pads it to a multiple of 16 but it still
+        // varies per note), so static-shape mode stays off and the MLProgram format is used,
+        // which handles flexible shapes; nodes CoreML still rejects run on the CPU EP.
+        #[cfg(feature = "coreml")]
+        ExecutionProvider::CoreMl => Some(ep::CoreML::default()
+            .with_model_format(ep::coreml::ModelFormat::MLProgram)
+            .with_static_input_shapes(false)
+            .build()),
+        #[cfg(not(feature = "coreml"))]
+        ExecutionProvider::CoreMl => {
+            tracing::warn!("CoreML support not compiled in (build with `--features coreml`)");
+            None
+        }
+    }
+}
+/// ORT sizes its intra-op pool to every core by default, which oversubscribes the CPU once
+/// `max_workers` renders run at once, so unless configured each session gets its share. STFTs
+/// run on oxifft's global rayon pool, which is sized separately and still spans every core.
+fn intra_threads(configured: usize, max_workers: usize, cores: usize) -> usize {
+    match configured {
+        0 => (cores / max_workers.max(1)).max(1),
+        n => n,
+    }
+}
+/// Opens `model_path` on the configured execution provider, falling back to CPU if the provider
+/// isn't compiled in or can't load the model. Returns the provider actually in use.
+pub fn load_session(model_path: &Path) -> (Session, ExecutionProvider) {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let intra = intra_threads(HIFI_CONFIG.ort_intra_threads, HIFI_CONFIG.max_workers, cores);
+    let builder = || {
+        let builder = Session::builder().unwrap()
+            .with_optimization_level(GraphOptimizationLevel::Level3).unwrap()
+            .with_intra_threads(intra).unwrap();
+        match HIFI_CONFIG.ort_inter_threads {
+            0 => builder,
+            inter => builder.with_inter_threads(inter).unwrap(),
+        }
+    };
+    let provider = HIFI_CONFIG.execution_provider;
+    if let Some(dispatch) = provider_dispatch(provider, HIFI_CONFIG.device_id) {
+        // DirectML can't run with memory patterns enabled (parallel execution is already off).
+        // Without CoreML's CPU fallback a partially supported model fails here and loads on CPU.
+        let disable_cpu_fallback = provider == ExecutionProvider::CoreMl && !HIFI_CONFIG.coreml_cpu_fallback;
+        let session = builder()
+            .with_memory_pattern(provider != ExecutionProvider::DirectMl)
+            .and_then(|builder| builder.with_config_entry("session.disable_cpu_ep_fallback", if disable_cpu_fallback { "1" } else { "0" }))
+            .and_then(|builder| builder.with_execution_providers([dispatch.error_on_failure()]))
+            .and_then(|builder| builder.commit_from_file(model_path));
+        match session {
+            Ok(session) => return (session, provider),
+            Err(e) => tracing::warn!(
+                "{:?} execution provider unavailable for {}, falling back to CPU: {}",
+                provider, model_path.display(), e
+            ),
+        }
+    }
+    (builder().commit_from_file(model_path).unwrap(), ExecutionProvider::Cpu)
+}
+fn verify_names(kind: &str, expected: &[&str], actual: &[Outlet]) -> Result<(), String> {
+    match expected.iter().find(|name| !actual.iter().any(|outlet| outlet.name() == **name)) {
+        Some(name) => {
+            let available: Vec<_> = actual.iter().map(Outlet::name).collect();
+            Err(format!("no {} named {:?} (model has: {})", kind, name, available.join(", ")))
+        }
+        None => Ok(()),
+    }
+}
+/// Fails at load time, listing the model's real tensor names, rather than at the first render.
+pub fn check_tensor_names(model_path: &Path, session: &Session, inputs: &[&str], outputs: &[&str]) {
+    let checked = verify_names("input", inputs, session.inputs())
+        .and_then(|_| verify_names("output", outputs, session.outputs()));
+    if let Err(e) = checked {
+        panic!("{}: {}; set the matching *_name option in hificonfig.ini", model_path.display(), e);
+    }
+}
 pub fn initialize_models() {
-    if !HIFI_CONFIG.vocoder_path.exists() {
-        panic!("HiFiGAN model not found at: {}", HIFI_CONFIG.vocoder_path.display());
+    let vocoder_paths: Vec<_> = std::iter::once(&HIFI_CONFIG.vocoder_path)
+        .chain(&HIFI_CONFIG.extra_vocoder_paths)
+        .collect();
+    if let Some(missing) = vocoder_paths.iter().find(|path| !path.exists()) {
+        panic!("HiFiGAN model not found at: {}", missing.display());
     }
     if !HIFI_CONFIG.hnsep_path.exists() {
         panic!("HNSEP model not found at: {}", HIFI_CONFIG.hnsep_path.display());
     }
-    let hifigan = Arc::new(Mutex::new(HiFiGANLoader::new(&HIFI_CONFIG.vocoder_path)));
-    VOCODER.set(hifigan).unwrap();
-    tracing::info!("HiFiGAN model loaded successfully vocoder_path={}",
-        HIFI_CONFIG.vocoder_path.display(),
-    );
-    let hnsep = Arc::new(Mutex::new(HNSEPLoader::new(&HIFI_CONFIG.hnsep_path)));
-    REMOVER.set(hnsep).unwrap();
-    tracing::info!("HNSEP model loaded successfully hnsep_path={}",
-        HIFI_CONFIG.hnsep_path.display(),
+    // One instance per render that can run at once, so workers don't queue on a single session.
+    let instances = HIFI_CONFIG.max_workers + HIFI_CONFIG.priority_workers;
+    let pools = vocoder_paths.iter().enumerate().map(|(index, path)| {
+        let vocoders: Vec<_> = (0..instances).map(|_| HiFiGANLoader::new(path)).collect();
+        tracing::info!("HiFiGAN model loaded successfully index={} vocoder_path={} provider={:?} instances={}",
+            index, path.display(), vocoders[0].provider, instances,
+        );
+        if let Some(bins) = vocoders[0].mel_bins() && bins != HIFI_CONFIG.mel_bins {
+            panic!("Vocoder {} expects {} mel bins but mel_bins = {}; set mel_bins to match the model",
+                path.display(), bins, HIFI_CONFIG.mel_bins);
+        }
+        Pool::new(vocoders)
+    }).collect::<Vec<_>>();
+    if HIFI_CONFIG.vocoder_batch_size > 1 {
+        let window = Duration::from_millis(HIFI_CONFIG.vocoder_batch_window_ms);
+        BATCHERS.set(pools.iter().map(|_| Batcher::new(HIFI_CONFIG.vocoder_batch_size, window)).collect()).ok();
+        tracing::info!("Vocoder batching enabled batch_size={} window={:?}", HIFI_CONFIG.vocoder_batch_size, window);
+    }
+    VOCODERS.set(pools).unwrap();
+    let removers: Vec<_> = (0..instances).map(|_| HNSEPLoader::new(&HIFI_CONFIG.hnsep_path)).collect();
+    tracing::info!("HNSEP model loaded successfully hnsep_path={} provider={:?} instances={}",
+        HIFI_CONFIG.hnsep_path.display(), removers[0].provider, instances,
     );
+    REMOVER.set(Pool::new(removers)).unwrap();
     tracing::info!("All models initialized successfully.");
 }
-pub fn get_vocoder() -> Arc<Mutex<HiFiGANLoader>> {
-    VOCODER.get().cloned().unwrap()
+pub fn warmup() {
+    const WARMUP_FRAMES: usize = 8;
+    let start = Instant::now();
+    let (vocoders, removers) = (VOCODERS.get().unwrap(), REMOVER.get().unwrap());
+    for pool in vocoders {
+        let mut held: Vec<_> = (0..pool.size()).map(|_| pool.get()).collect();
+        for vocoder in held.iter_mut() {
+            if let Err(e) = vocoder.run(Array2::zeros((HIFI_CONFIG.mel_bins, WARMUP_FRAMES)), &[0.0; WARMUP_FRAMES]) {
+                tracing::warn!("Vocoder warmup: {}", e);
+            }
+        }
+    }
+    let mut held: Vec<_> = (0..removers.size()).map(|_| removers.get()).collect();
+    held.iter_mut().for_each(|remover| { remover.run(&[0.0; WARMUP_FRAMES * HOP_SIZE]); });
+    tracing::info!("Models warmed up in {:.2?}", start.elapsed());
+}
+/// Checks out an instance of the requested vocoder, or of the default one if `index` is out of range.
+pub fn get_vocoder(index: usize) -> Pooled<'static, HiFiGANLoader> {
+    let pools = VOCODERS.get().unwrap();
+    pools[vocoder_index(index, pools.len())].get()
+}
+/// Vocodes one note, sharing a batched inference with concurrent renders when batching is on.
+pub fn vocode(index: usize, mel: Array2<f64>, f0: &[f64]) -> anyhow::Result<Vec<f64>> {
+    let Some(batchers) = BATCHERS.get() else {
+        return get_vocoder(index).run(mel, f0);
+    };
+    let index = vocoder_index(index, batchers.len());
+    batchers[index]
+        .submit((mel, f0.to_vec()), |items| {
+            let count = items.len();
+            match get_vocoder(index).run_batch(items) {
+                Ok(waves) => waves.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e.to_string()); count],
+            }
+        })
+        .and_then(|output| output)
+        .map_err(anyhow::Error::msg)
+}
+fn vocoder_index(index: usize, count: usize) -> usize {
+    if index < count {
+        index
+    } else {
+        tracing::warn!("Vocoder index {} out of range ({} loaded); using the default", index, count);
+        0
+    }
+}
+pub fn get_remover() -> Pooled<'static, HNSEPLoader> {
+    REMOVER.get().unwrap().get()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_verify_names() {
+        use ort::{tensor::{Shape, SymbolicDimensions, TensorElementType}, value::ValueType};
+        let outlet = |name| Outlet::new(name, ValueType::Tensor {
+            ty: TensorElementType::Float32,
+            shape: Shape::new([1, -1]),
+            dimension_symbols: SymbolicDimensions::empty(2),
+        });
+        let outlets = [outlet("c"), outlet("f0")];
+        assert!(verify_names("input", &["c", "f0"], &outlets).is_ok());
+        let err = verify_names("input", &["mel", "f0"], &outlets).unwrap_err();
+        assert_eq!(err, "no input named \"mel\" (model has: c, f0)");
+    }
+    #[test]
+    fn test_vocoder_index() {
+        assert_eq!(vocoder_index(0, 1), 0);
+        assert_eq!(vocoder_index(2, 3), 2);
+        assert_eq!(vocoder_index(3, 3), 0);
+    }
+    #[test]
+    fn test_intra_threads() {
+        assert_eq!(intra_threads(0, 2, 8), 4);
+        assert_eq!(intra_threads(0, 3, 8), 2);
+        assert_eq!(intra_threads(0, 16, 8), 1);
+        assert_eq!(intra_threads(3, 2, 8), 3);
+    }
+    #[test]
+    fn test_model_signature() {
+        let dir = std::env::temp_dir().join("hifi_model_sig_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.onnx"), dir.join("b.onnx"));
+        std::fs::write(&a, b"vocoder a").unwrap();
+        std::fs::write(&b, b"vocoder b").unwrap();
+        let (basis, other_basis) = (vec![vec![(2, 0.5)]], vec![vec![(2, 0.25)]]);
+        assert_eq!(model_signature(&a, &basis), model_signature(&a, &basis));
+        assert_ne!(model_signature(&a, &basis), model_signature(&b, &basis));
+        assert_ne!(model_signature(&a, &basis), model_signature(&a, &other_basis));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
-pub fn get_remover() -> Arc<Mutex<HNSEPLoader>> {
-    REMOVER.get().cloned().unwrap()
-}
\ No newline at end of file
@@ -0,0 +1,105 @@
+use std::{collections::HashMap, sync::{Condvar, Mutex}, time::{Duration, Instant}};
+struct Queue<I, O> {
+    pending: Vec<(u64, I)>,
+    done: HashMap<u64, Result<O, String>>,
+    next_id: u64,
+    leading: bool,
+}
+/// Gathers inputs from concurrent callers so one of them can run them together. The first
+/// caller to find no batch in progress waits up to `window` (or until `max_size` inputs are
+/// queued), runs the batch and hands every other caller its output.
+pub struct Batcher<I, O> {
+    queue: Mutex<Queue<I, O>>,
+    changed: Condvar,
+    max_size: usize,
+    window: Duration,
+}
+impl<I, O> Batcher<I, O> {
+    pub fn new(max_size: usize, window: Duration) -> Self {
+        Self {
+            queue: Mutex::new(Queue { pending: Vec::new(), done: HashMap::new(), next_id: 0, leading: false }),
+            changed: Condvar::new(),
+            max_size: max_size.max(1),
+            window,
+        }
+    }
+    /// `run` must return one output per input, in order. If it panics, every caller in that
+    /// batch gets the panic message as an error instead of unwinding.
+    pub fn submit(&self, input: I, run: impl Fn(Vec<I>) -> Vec<O>) -> Result<O, String> {
+        let mut queue = self.queue.lock().unwrap();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.pending.push((id, input));
+        self.changed.notify_all();
+        loop {
+            if let Some(output) = queue.done.remove(&id) {
+                return output;
+            }
+            if queue.leading {
+                queue = self.changed.wait(queue).unwrap();
+                continue;
+            }
+            queue.leading = true;
+            let deadline = Instant::now() + self.window;
+            while queue.pending.len() < self.max_size {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                queue = self.changed.wait_timeout(queue, deadline - now).unwrap().0;
+            }
+            let take = queue.pending.len().min(self.max_size);
+            let (ids, inputs): (Vec<_>, Vec<_>) = queue.pending.drain(..take).unzip();
+            drop(queue);
+            let outputs = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run(inputs)));
+            queue = self.queue.lock().unwrap();
+            match outputs {
+                Ok(outputs) => queue.done.extend(ids.into_iter().zip(outputs.into_iter().map(Ok))),
+                Err(payload) => {
+                    let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown panic".to_string());
+                    let error = format!("Batched inference panicked: {}", message);
+                    queue.done.extend(ids.into_iter().map(|id| (id, Err(error.clone()))));
+                }
+            }
+            queue.leading = false;
+            self.changed.notify_all();
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, thread};
+    #[test]
+    fn test_concurrent_submits_share_a_batch() {
+        let batcher = Arc::new(Batcher::new(4, Duration::from_secs(5)));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let workers: Vec<_> = (0..4).map(|i| {
+            let (batcher, calls) = (batcher.clone(), calls.clone());
+            thread::spawn(move || batcher.submit(i, |inputs: Vec<i32>| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                inputs.iter().map(|x| x * 10).collect()
+            }))
+        }).collect();
+        let outputs: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_eq!(outputs, [Ok(0), Ok(10), Ok(20), Ok(30)]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let alone = Batcher::new(4, Duration::from_millis(1));
+        assert_eq!(alone.submit(7, |inputs: Vec<i32>| inputs), Ok(7));
+    }
+    #[test]
+    fn test_panicking_batch_returns_errors() {
+        let batcher = Arc::new(Batcher::new(3, Duration::from_secs(5)));
+        let workers: Vec<_> = (0..3).map(|i| {
+            let batcher = batcher.clone();
+            thread::spawn(move || batcher.submit(i, |_: Vec<i32>| -> Vec<i32> { panic!("bad note") }))
+        }).collect();
+        for worker in workers {
+            let err = worker.join().expect("a panicking batch must not unwind into waiters").unwrap_err();
+            assert!(err.contains("bad note"), "{}", err);
+        }
+        assert_eq!(batcher.submit(1, |inputs: Vec<i32>| inputs), Ok(1));
+    }
+}
@@ -1,34 +1,198 @@
-use std::path::PathBuf;
-use ort::{ session::{Session, builder::GraphOptimizationLevel}, value::Value };
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use half::f16;
+use ort::{ session::Session, tensor::TensorElementType, value::{Outlet, Value, ValueType} };
 use ndarray::{Array2, Axis};
+use crate::{consts::{ExecutionProvider, HIFI_CONFIG, HOP_SIZE}, model::{check_tensor_names, load_session}};
 #[derive(Debug)]
 pub struct HiFiGANLoader {
     session: Session,
+    pub provider: ExecutionProvider,
+    fp16: bool,
 }
 impl HiFiGANLoader {
-    pub fn new(model_path: &PathBuf) -> Self {
-        Self {
-            session: Session::builder().unwrap()
-                .with_optimization_level(GraphOptimizationLevel::Level3).unwrap()
-                .commit_from_file(model_path).unwrap()
+    pub fn new(model_path: &Path) -> Self {
+        let (session, provider) = load_session(model_path);
+        check_tensor_names(
+            model_path,
+            &session,
+            &[&HIFI_CONFIG.vocoder_mel_name, &HIFI_CONFIG.vocoder_f0_name],
+            &[&HIFI_CONFIG.vocoder_output_name],
+        );
+        let fp16 = expects_fp16(session.inputs(), &HIFI_CONFIG.vocoder_mel_name);
+        if fp16 {
+            tracing::info!("Vocoder {} takes fp16 inputs", model_path.display());
         }
+        Self { session, provider, fp16 }
     }
-    pub fn run(&mut self, mel: Array2<f64>, f0: &[f64]) -> Vec<f64> {
-        let (n_mels, n_frames) = mel.dim();
-        let mel_f32: Vec<f32> = mel
-            .axis_iter(Axis(1))
-            .flat_map(|col| col) 
-            .map(|&x| x as f32) 
-            .collect();
-        let f0_f32: Vec<f32> = f0.into_iter().map(|&x| x as f32).collect();
-        let mel_tensor = Value::from_array(([1, n_frames as i64, n_mels as i64], mel_f32)).unwrap();
-        let f0_tensor = Value::from_array(([1, f0.len() as i64], f0_f32)).unwrap();
-        self.session.run(vec![("mel", mel_tensor), ("f0", f0_tensor)]).unwrap()
-            .get("waveform").unwrap()
-            .try_extract_tensor::<f32>().unwrap()
+    /// The mel input is `[batch, frames, bins]`; `None` when the model leaves the bin count dynamic.
+    pub fn mel_bins(&self) -> Option<usize> {
+        let input = self.session.inputs().iter().find(|input| input.name() == HIFI_CONFIG.vocoder_mel_name)?;
+        let bins = *input.dtype().tensor_shape()?.last()?;
+        usize::try_from(bins).ok()
+    }
+    pub fn run(&mut self, mel: Array2<f64>, f0: &[f64]) -> Result<Vec<f64>> {
+        Ok(self.run_notes(&[(mel, f0.to_vec())], 0)?.pop().unwrap())
+    }
+    /// Runs several notes, whatever their lengths, as one `[batch, frames, bins]` inference
+    /// (see [`run_padded`]).
+    pub fn run_batch(&mut self, items: Vec<(Array2<f64>, Vec<f64>)>) -> Result<Vec<Vec<f64>>> {
+        self.run_notes(&items, BATCH_PAD_FRAMES)
+    }
+    fn run_notes(&mut self, items: &[(Array2<f64>, Vec<f64>)], margin: usize) -> Result<Vec<Vec<f64>>> {
+        run_padded(items, margin, |batch| {
+            let output = if self.fp16 {
+                self.infer(batch, f16::from_f64, f16::to_f64)
+            } else {
+                self.infer(batch, |x| x as f32, |x: f32| x as f64)
+            };
+            check_output_len(output.len() / batch.size.max(1), batch.frames)?;
+            Ok(output)
+        })
+    }
+    fn infer<T>(&mut self, batch: &Batch, to: fn(f64) -> T, from: fn(T) -> f64) -> Vec<f64>
+    where
+        T: ort::tensor::PrimitiveTensorElementType + Copy + std::fmt::Debug + 'static,
+    {
+        let (size, frames) = (batch.size as i64, batch.frames as i64);
+        let mel_t: Vec<T> = batch.mel.iter().map(|&x| to(x)).collect();
+        let f0_t: Vec<T> = batch.f0.iter().map(|&x| to(x)).collect();
+        let mel_tensor = Value::from_array(([size, frames, batch.n_mels as i64], mel_t)).unwrap();
+        let f0_tensor = Value::from_array(([size, frames], f0_t)).unwrap();
+        let inputs = vec![(HIFI_CONFIG.vocoder_mel_name.as_str(), mel_tensor), (HIFI_CONFIG.vocoder_f0_name.as_str(), f0_tensor)];
+        self.session.run(inputs).unwrap()
+            .get(&HIFI_CONFIG.vocoder_output_name).unwrap()
+            .try_extract_tensor::<T>().unwrap()
             .1
-            .into_iter()
-            .map(|x| *x as f64) 
+            .iter()
+            .map(|&x| from(x)) 
+            .collect()
+    }
+}
+struct Batch {
+    mel: Vec<f64>,
+    f0: Vec<f64>,
+    size: usize,
+    frames: usize,
+    n_mels: usize,
+}
+/// A model trained for another hop size yields a waveform of the wrong length, which would
+/// otherwise be sliced into clicks; allow at most one hop of slack for edge padding.
+fn check_output_len(samples: usize, frames: usize) -> Result<()> {
+    let expected = frames * HOP_SIZE;
+    if samples.abs_diff(expected) > HOP_SIZE {
+        return Err(anyhow!(
+            "Vocoder returned {} samples for {} frames (expected {}): model hop size looks like {} instead of {}",
+            samples, frames, expected, samples.checked_div(frames).unwrap_or(0), HOP_SIZE
+        ));
+    }
+    Ok(())
+}
+/// Silent mel frames appended after the longest note of a batch. Must cover the generator's
+/// receptive field, so the samples kept for each note only ever see its own frames and silence,
+/// whichever notes it was batched with.
+const BATCH_PAD_FRAMES: usize = 32;
+/// Pads every note with silence to the longest note plus `margin` frames, runs one inference
+/// and trims each waveform back to its own frames. Waveforms come back in the order of `items`.
+fn run_padded(
+    items: &[(Array2<f64>, Vec<f64>)],
+    margin: usize,
+    infer: impl FnOnce(&Batch) -> Result<Vec<f64>>,
+) -> Result<Vec<Vec<f64>>> {
+    let frames = items.iter().map(|(mel, _)| mel.ncols()).max().unwrap_or(0) + margin;
+    let batch = pack_batch(items, frames);
+    let output = infer(&batch)?;
+    let row = output.len().checked_div(batch.size).unwrap_or(0);
+    Ok(output.chunks(row.max(1))
+        .zip(items)
+        .map(|(wave, (mel, _))| wave[..(mel.ncols() * row / frames.max(1)).min(wave.len())].to_vec())
+        .chain(std::iter::repeat_with(Vec::new))
+        .take(items.len())
+        .collect())
+}
+/// Mels are `[bins, frames]` per note; the model wants them frame-major. Notes shorter than
+/// `frames` are padded with the log-mel floor and an unvoiced f0.
+fn pack_batch(items: &[(Array2<f64>, Vec<f64>)], frames: usize) -> Batch {
+    let n_mels = items.first().map_or(0, |(mel, _)| mel.nrows());
+    let silence = 1e-9f64.ln();
+    let mut batch = Batch { mel: Vec::new(), f0: Vec::new(), size: items.len(), frames, n_mels };
+    for (mel, f0) in items {
+        for (t, col) in mel.axis_iter(Axis(1)).enumerate() {
+            batch.mel.extend(col.iter().copied());
+            batch.f0.push(f0.get(t).or(f0.last()).copied().unwrap_or(0.0));
+        }
+        let pad = frames.saturating_sub(mel.ncols());
+        batch.mel.extend(std::iter::repeat_n(silence, pad * n_mels));
+        batch.f0.extend(std::iter::repeat_n(0.0, pad));
+    }
+    batch
+}
+/// Half-precision exports take fp16 for every input; the mel input decides.
+fn expects_fp16(inputs: &[Outlet], mel_name: &str) -> bool {
+    inputs.iter()
+        .find(|input| input.name() == mel_name)
+        .is_some_and(|input| matches!(input.dtype(), ValueType::Tensor { ty: TensorElementType::Float16, .. }))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ort::tensor::{Shape, SymbolicDimensions};
+    #[test]
+    fn test_expects_fp16() {
+        let outlet = |name, ty| Outlet::new(name, ValueType::Tensor {
+            ty,
+            shape: Shape::new([1, -1, 128]),
+            dimension_symbols: SymbolicDimensions::empty(3),
+        });
+        let fp16 = [outlet("mel", TensorElementType::Float16), outlet("f0", TensorElementType::Float16)];
+        let fp32 = [outlet("mel", TensorElementType::Float32), outlet("f0", TensorElementType::Float32)];
+        assert!(expects_fp16(&fp16, "mel"));
+        assert!(!expects_fp16(&fp32, "mel"));
+        assert!(!expects_fp16(&fp16, "c"));
+        let x = 0.123456789;
+        assert!((f16::to_f64(f16::from_f64(x)) - x).abs() < 1e-3);
+    }
+    /// Stands in for the vocoder: each frame becomes `hop` samples of its mel sum plus its f0,
+    /// mixed with its neighbours in the same note the way a convolutional generator would.
+    fn context_model(batch: &Batch, hop: usize) -> Vec<f64> {
+        let frames: Vec<f64> = batch.mel.chunks(batch.n_mels)
+            .zip(&batch.f0)
+            .map(|(col, f0)| col.iter().sum::<f64>() + f0)
+            .collect();
+        frames.chunks(batch.frames.max(1))
+            .flat_map(|row| {
+                (0..row.len()).map(move |t| {
+                    let side = |i: Option<usize>| i.and_then(|i| row.get(i)).copied().unwrap_or(0.0);
+                    row[t] + 0.5 * (side(t.checked_sub(1)) + side(Some(t + 1)))
+                })
+            })
+            .flat_map(|x| std::iter::repeat_n(x, hop))
             .collect()
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_check_output_len() {
+        assert!(check_output_len(10 * HOP_SIZE, 10).is_ok());
+        assert!(check_output_len(10 * HOP_SIZE - 100, 10).is_ok());
+        let err = check_output_len(10 * 256, 10).unwrap_err().to_string();
+        assert!(err.contains("hop size looks like 256 instead of 512"), "{}", err);
+    }
+    #[test]
+    fn test_batch_matches_single() {
+        let note = |frames: usize, seed: f64| {
+            let mel = Array2::from_shape_fn((3, frames), |(b, t)| seed + b as f64 * 0.5 + t as f64);
+            (mel, (0..frames).map(|t| 100.0 * seed + t as f64).collect::<Vec<_>>())
+        };
+        let items = vec![note(4, 1.0), note(2, 2.0), note(5, 3.0), note(4, 4.0)];
+        let mut shapes = Vec::new();
+        let batched = run_padded(&items, BATCH_PAD_FRAMES, |batch| {
+            shapes.push((batch.size, batch.frames, batch.n_mels));
+            Ok(context_model(batch, 4))
+        }).unwrap();
+        assert_eq!(shapes, [(4, 5 + BATCH_PAD_FRAMES, 3)]);
+        for (item, out) in items.iter().zip(&batched) {
+            let single = run_padded(std::slice::from_ref(item), BATCH_PAD_FRAMES, |batch| Ok(context_model(batch, 4))).unwrap();
+            assert_eq!(out.len(), item.0.ncols() * 4);
+            assert_eq!(*out, single[0]);
+        }
+    }
+}
@@ -1,21 +1,20 @@
-use std::path::PathBuf;
-use ort::{ session::{Session, builder::GraphOptimizationLevel}, value::Value };
+use std::path::Path;
+use ort::{ session::Session, value::Value };
 use ndarray::{Array2, Array4, azip};
 use oxifft::Complex;
-use crate::{consts::{FFT_SIZE, HOP_SIZE}, utils::stft::*};
+use crate::{consts::{ExecutionProvider, FFT_SIZE, HIFI_CONFIG, HOP_SIZE, WindowKind}, model::{check_tensor_names, load_session}, utils::stft::*};
 const SEG_LENGTH: usize = 32 * HOP_SIZE;
 const OUTPUT_BIN: usize = FFT_SIZE / 2 + 1;
 #[derive(Debug)]
 pub struct HNSEPLoader {
     session: Session,
+    pub provider: ExecutionProvider,
 }
 impl HNSEPLoader {
-    pub fn new(model_path: &PathBuf) -> Self {
-        Self {
-            session: Session::builder().unwrap()
-                .with_optimization_level(GraphOptimizationLevel::Level3).unwrap()
-                .commit_from_file(model_path).unwrap()
-        }
+    pub fn new(model_path: &Path) -> Self {
+        let (session, provider) = load_session(model_path);
+        check_tensor_names(model_path, &session, &[&HIFI_CONFIG.hnsep_input_name], &[&HIFI_CONFIG.hnsep_output_name]);
+        Self { session, provider }
     }
     pub fn run(&mut self, wave: &[f64]) -> Vec<f64> {
         let orig_len = wave.len();
@@ -26,7 +25,7 @@ impl HNSEPLoader {
         x_pad.extend(std::iter::repeat(0.0).take(left));
         x_pad.extend_from_slice(wave);
         x_pad.extend(std::iter::repeat(0.0).take(right));
-        let spec = stft_core(&x_pad, FFT_SIZE, HOP_SIZE);
+        let spec = stft_core(&x_pad, FFT_SIZE, HOP_SIZE, WindowKind::Hann);
         let t_spec = spec.ncols();
         let (real, imag): (Vec<f32>, Vec<f32>) = spec
             .iter() 
@@ -51,8 +50,8 @@ impl HNSEPLoader {
                 arr4.into_raw_vec_and_offset().0
             ),
         ).unwrap();
-        let outputs = self.session.run(vec![("input", input_value)]).unwrap();
-        let output_data = outputs.get("output")
+        let outputs = self.session.run(vec![(HIFI_CONFIG.hnsep_input_name.as_str(), input_value)]).unwrap();
+        let output_data = outputs.get(&HIFI_CONFIG.hnsep_output_name)
             .unwrap()
             .try_extract_tensor::<f32>()
             .unwrap()
@@ -71,6 +70,7 @@ impl HNSEPLoader {
             (t_spec - 1) * HOP_SIZE + FFT_SIZE,
             FFT_SIZE,
             HOP_SIZE,
+            WindowKind::Hann,
         );
         x_pred_pad.drain(0..left);
         x_pred_pad.truncate(orig_len);
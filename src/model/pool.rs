@@ -0,0 +1,74 @@
+use std::{ops::{Deref, DerefMut}, sync::{Condvar, Mutex}};
+/// A fixed set of model instances. Each render checks one out for the duration of its
+/// inference, so up to `size` inferences run in parallel and the rest wait for a return.
+#[derive(Debug)]
+pub struct Pool<T> {
+    idle: Mutex<Vec<T>>,
+    returned: Condvar,
+    size: usize,
+}
+impl<T> Pool<T> {
+    pub fn new(items: Vec<T>) -> Self {
+        let size = items.len();
+        Self { idle: Mutex::new(items), returned: Condvar::new(), size }
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn get(&self) -> Pooled<'_, T> {
+        let mut idle = self.returned
+            .wait_while(self.idle.lock().unwrap(), |idle| idle.is_empty())
+            .unwrap();
+        Pooled { pool: self, item: idle.pop() }
+    }
+}
+pub struct Pooled<'a, T> {
+    pool: &'a Pool<T>,
+    item: Option<T>,
+}
+impl<T> Deref for Pooled<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.item.as_ref().unwrap()
+    }
+}
+impl<T> DerefMut for Pooled<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().unwrap()
+    }
+}
+impl<T> Drop for Pooled<'_, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.pool.idle.lock().unwrap().push(item);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::{Arc, mpsc}, thread, time::Duration};
+    #[test]
+    fn test_concurrent_checkout() {
+        let pool = Arc::new(Pool::new(vec![0usize, 1]));
+        let first = pool.get();
+        let (tx, rx) = mpsc::channel();
+        let worker = {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                let second = pool.get();
+                tx.send(*second).unwrap();
+                let third = pool.get();
+                tx.send(*third).unwrap();
+            })
+        };
+        assert_ne!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), *first);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        let held = *first;
+        drop(first);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), held);
+        worker.join().unwrap();
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+    }
+}
@@ -1,21 +1,40 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use ndarray::{Array2, Axis, concatenate, s};
-use std::{collections::HashMap, path::PathBuf};
+use serde::Serialize;
+use std::{collections::HashMap, fmt, io, ops::Range, path::PathBuf, time::Duration};
 use tracing::info;
 use crate::{
-    audio::{post_process::{loudness_norm, pre_emphasis_base_tension}, read_audio, write_audio},
-    consts::{SAMPLE_RATE, ORIGIN_HOP_SIZE, HOP_SIZE, FEATURE_EXT, HIFI_CONFIG},
-    model::{get_remover, get_vocoder},
+    audio::{eq::ShelfEq, post_process::{Compressor, breathiness_scale, de_ess, declick_loop, fade_edges, loudness_norm, mix_hnsep, noise_gate, peak_normalize, pitch_shift, pre_emphasis_base_tension, TruePeakLimiter}, audio_channel_count, read_audio, read_audio_channels, write_audio},
+    consts::{SAMPLE_RATE, HOP_SIZE, FEATURE_EXT, HIFI_CONFIG, LoopStyle, MelScale, PFlagMode, PitchInterp},
+    model::{get_remover, vocode},
     utils::{
-        cache::{CACHE_MANAGER, Features}, dynamic_range_compression, growl::growl, interp::Akima, interp1d, 
-        midi_to_hz, mel::mel, parser::{flag_parser, pitch_parser, pitch_string_to_cents, tempo_parser}, reflect_pad_2d
+        cache::{CACHE_MANAGER, Features, cache_file_path}, dynamic_range_compression, formant::{Formant, warp_envelope}, growl::growl, interp::{Akima, CubicSpline, Pchip}, interp1d, Extrapolate, 
+        midi_to_hz, mel::{analysis_hop, frame_offset, mel, MEL_CENTERS}, pitch::{Vibrato, quantize_pitch, smooth_pitch}, parser::{TempoMap, flag_parser, pitch_parser, pitch_string_to_cents, tempo_parser}, crossfade_pad_2d, pingpong_pad_2d, reflect_pad_2d
     },
 };
 const SR_F64: f64 = SAMPLE_RATE as f64;
-const THOP_ORIGIN: f64 = ORIGIN_HOP_SIZE as f64 / SR_F64;
-const THOP_ORIGIN_HALF: f64 = THOP_ORIGIN / 2.0;
 const THOP: f64 = HOP_SIZE as f64 / SR_F64;
 const THOP_HALF: f64 = THOP / 2.0;
+pub const INLINE_OUTPUT: &str = "-";
+#[derive(Debug)]
+pub enum RenderError {
+    InvalidArguments(String),
+    InputNotFound(PathBuf),
+    Timeout(Duration),
+    CacheLocked(PathBuf),
+}
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidArguments(msg) => write!(f, "Invalid arguments: {}", msg),
+            Self::InputNotFound(path) => write!(f, "Input audio not found: {}", path.display()),
+            Self::Timeout(limit) => write!(f, "Render timed out after {:?}", limit),
+            Self::CacheLocked(path) => write!(f, "Cache is locked by another render: {}", path.display()),
+        }
+    }
+}
+impl std::error::Error for RenderError {}
+#[derive(Serialize)]
 pub struct Resampler {
     in_file: PathBuf,
     out_file: PathBuf,
@@ -28,12 +47,55 @@ pub struct Resampler {
     cutoff: f64,
     volume: f64,
     modulation: f64,
-    tempo: f64,
+    tempo: TempoMap,
     pitchbend: Vec<f64>,
 }
+/// Warped and FFT-scaled gender renders differ, so they must not share a feature cache.
+fn gender_key(key: &str, value: Option<f64>) -> &str {
+    if key == "g" && HIFI_CONFIG.gender_warp && value.is_some_and(|g| g != 0.) { "gw" } else { key }
+}
+/// Strength for `loudness_norm`, or `None` when the note skips it. `Z` turns normalization off
+/// for this note whatever `wave_norm` says; otherwise it runs at full strength, or scaled by
+/// `P` (0~100) when `p_flag = loudness`.
+fn loudness_strength(flags: &HashMap<String, Option<f64>>, wave_norm: bool, p_flag: PFlagMode) -> Option<u8> {
+    (wave_norm && !flags.contains_key("Z")).then(|| match p_flag {
+        PFlagMode::Peak => 100,
+        PFlagMode::Loudness => flags.get("P")
+            .and_then(|o| o.as_ref())
+            .copied()
+            .unwrap_or(100.0)
+            .clamp(0.0, 100.0) as u8,
+    })
+}
+/// With `p_flag = peak`, `P` (0~100) moves the note peak that far towards `peak_limit`.
+/// Returns whether it ran, in which case the peak the volume stage sees has to be re-measured.
+fn peak_stage(render: &mut [f64], flags: &HashMap<String, Option<f64>>, p_flag: PFlagMode, peak_limit: f64) -> bool {
+    let Some(&p) = flags.get("P").and_then(|o| o.as_ref()).filter(|_| p_flag == PFlagMode::Peak) else {
+        return false;
+    };
+    info!("Peak normalizing to {} (amount: {:.0})", peak_limit, p);
+    peak_normalize(render, peak_limit, p);
+    true
+}
 impl Resampler {
-    pub fn new(args: Vec<String>) -> Result<()> {
-        Self {
+    /// Renders to `out_file`, or returns the per-channel samples instead when it is [`INLINE_OUTPUT`].
+    pub fn new(args: Vec<String>) -> Result<Option<Vec<Vec<f64>>>> {
+        Self::parse(&args)
+            .map_err(|e| RenderError::InvalidArguments(e.to_string()))?
+            .render()
+    }
+    pub fn validate(args: Vec<String>) -> Result<Self> {
+        let resampler = Self::parse(&args)
+            .map_err(|e| RenderError::InvalidArguments(e.to_string()))?;
+        let samples = resampler.read_input(None)?.len();
+        info!("Dry run: {} decodes to {} samples", resampler.in_file.display(), samples);
+        Ok(resampler)
+    }
+    fn parse(args: &[String]) -> Result<Self> {
+        if args.len() != 13 {
+            return Err(anyhow!("expected 13 arguments, got {}", args.len()));
+        }
+        Ok(Self {
             in_file: PathBuf::from(args[0].to_string()),
             out_file: PathBuf::from(args[1].to_string()),
             pitch: pitch_parser(&args[2])? as f64,
@@ -45,62 +107,135 @@ impl Resampler {
             cutoff: args[8].parse::<f64>()? / 1000.,
             volume: args[9].parse::<f64>()? / 100.,
             modulation: args[10].parse::<f64>()? / 100.,
-            tempo: tempo_parser(&args[11])? * 96.,
+            tempo: tempo_parser(&args[11])?,
             pitchbend: pitch_string_to_cents(&args[12])?,
-        }.render()
+        })
     }
-    fn render(&mut self) -> Result<()> {
-        let mut features = self.get_features()?;
-        self.resample(&mut features)
+    fn render(&mut self) -> Result<Option<Vec<Vec<f64>>>> {
+        let channels = if HIFI_CONFIG.preserve_channels {
+            audio_channel_count(&self.in_file).map_err(|e| self.input_error(e))?
+        } else {
+            1
+        };
+        let mut features = (0..channels)
+            .map(|ch| self.get_features((channels > 1).then_some(ch)))
+            .collect::<Result<Vec<_>>>()?;
+        if self.out_file.file_name().and_then(|s| s.to_str()) == Some("nul") {
+            info!("Null output file - skipping write");
+            return Ok(None);
+        }
+        let rendered = features.iter_mut()
+            .map(|f| self.resample(f))
+            .collect::<Result<Vec<_>>>()?;
+        // A single gain for every channel keeps the stereo balance intact.
+        let max = rendered.iter().map(|(_, max)| *max).fold(0.0, f64::max);
+        let gain = if max > HIFI_CONFIG.peak_limit { self.volume / max } else { self.volume };
+        let mut renders: Vec<Vec<f64>> = rendered.into_iter()
+            .map(|(mut render, _)| {
+                render.iter_mut().for_each(|x| *x *= gain);
+                render
+            })
+            .collect();
+        let loop_region = self.loop_region(renders[0].len());
+        if let Some(region) = loop_region.as_ref().filter(|_| HIFI_CONFIG.loop_declick_ms > 0.0) {
+            let fade = (HIFI_CONFIG.loop_declick_ms / 1000.0 * SR_F64).round() as usize;
+            renders.iter_mut().for_each(|render| declick_loop(render, region.start, fade));
+        }
+        if HIFI_CONFIG.edge_fade_ms > 0.0 {
+            // A looped render's tail must keep meeting the loop start.
+            fade_edges(&mut renders, SR_F64, HIFI_CONFIG.edge_fade_ms, loop_region.is_none());
+        }
+        // Last sample-modifying stage, so nothing after it can push peaks past the ceiling.
+        if let Some(limiter) = TruePeakLimiter::from_config() {
+            info!("Applying true-peak limiter: {:?}", limiter);
+            limiter.apply(&mut renders, SR_F64);
+        }
+        if self.out_file.as_os_str() == INLINE_OUTPUT {
+            info!("Successfully processed: {} -> inline", self.in_file.display());
+            return Ok(Some(renders));
+        }
+        write_audio(&self.out_file, &renders, loop_region)?;
+        info!("Successfully processed: {} -> {}", self.in_file.display(), self.out_file.display());
+        Ok(None)
+    }
+    /// The sustained region starts where the consonant ends and runs to the end of the note.
+    fn loop_region(&self, len: usize) -> Option<Range<usize>> {
+        let looped = HIFI_CONFIG.loop_mode || self.flags.contains_key("He");
+        let vel = (1.0 - self.velocity).exp2();
+        (looped && HIFI_CONFIG.loop_markers)
+            .then(|| ((self.consonant * vel * SR_F64).round() as usize).min(len)..len)
+    }
+    /// Per-channel renders get their own feature caches.
+    fn cache_stem(&self, channel: Option<usize>) -> String {
+        let stem = self.in_file.file_stem().unwrap().to_str().unwrap();
+        channel.map_or(stem.to_string(), |ch| format!("{}_ch{}", stem, ch))
     }
-    fn get_features(&mut self) -> Result<Features> {
+    fn cache_path(&self, channel: Option<usize>, suffix: &str) -> PathBuf {
+        cache_file_path(&self.in_file, &self.cache_stem(channel), suffix, &HIFI_CONFIG.cache_dir)
+    }
+    fn get_features(&mut self, channel: Option<usize>) -> Result<Features> {
         [("Hb", 100.), ("Hv", 100.), ("Ht", 0.), ("g", 0.)]
             .iter()
             .for_each(|(k, v)| { self.flags.entry(k.to_string()).or_insert(Some(*v)); });
         let flag_suf = self.flags.iter()
-            .filter(|(k, _)| ["Hb", "Hv", "Ht", "g"].contains(&k.as_str()))
-            .map(|(k, v)| format!("{}{}", k, v.as_ref().unwrap())) 
+            .filter(|(k, v)| ["Hb", "Hv", "Ht", "g"].contains(&k.as_str()) || (k.as_str() == "B" && v.is_some_and(|b| b != 0.)))
+            .map(|(k, v)| format!("{}{}", gender_key(k, *v), v.as_ref().unwrap())) 
             .collect::<Vec<_>>()
             .join("_");
-        let stem = self.in_file.file_stem().unwrap().to_str().unwrap();
-        let cache_name = format!("{}_{}{}", stem, flag_suf, FEATURE_EXT);
-        let features_path = self.in_file.with_file_name(cache_name);
+        let features_path = self.cache_path(channel, &format!("_{}{}", flag_suf, FEATURE_EXT));
         let force_gen = self.flags.contains_key("G");
         if let Some(features) = CACHE_MANAGER.load_features_cache(&features_path, force_gen) {
             return Ok(features);
         }
         info!("Generating features (cache not found or forced): {}", features_path.display());
-        let features = self.generate_features()?;
-        CACHE_MANAGER.save_features_cache(&features_path, &features);
+        let features = self.generate_features(channel)?;
+        CACHE_MANAGER.save_features_cache(&features_path, &features)
+            .map_err(|e| RenderError::CacheLocked(e.path))?;
         Ok(features)
     }
-    fn generate_features(&self) -> Result<Features> {
+    fn input_error(&self, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast_ref::<io::Error>() {
+            Some(io_err) if io_err.kind() == io::ErrorKind::NotFound => {
+                RenderError::InputNotFound(self.in_file.clone()).into()
+            }
+            _ => e,
+        }
+    }
+    fn read_input(&self, channel: Option<usize>) -> Result<Vec<f64>> {
+        match channel {
+            None => read_audio(&self.in_file),
+            Some(ch) => read_audio_channels(&self.in_file).map(|mut channels| channels.swap_remove(ch)),
+        }.map_err(|e| self.input_error(e))
+    }
+    fn generate_features(&self, channel: Option<usize>) -> Result<Features> {
         let bre = self.flags.get("Hb").and_then(|o| o.as_ref()).copied().unwrap();
         let voicing = self.flags.get("Hv").and_then(|o| o.as_ref()).copied().unwrap();
         let tension = self.flags.get("Ht").and_then(|o| o.as_ref()).copied().unwrap();
-        info!("Breath: {}, Voicing: {}, Tension: {}", bre, voicing, tension);
-        let mut wave = read_audio(&self.in_file)?;
+        let breathiness = self.flags.get("B").and_then(|o| o.as_ref()).copied().unwrap_or(0.);
+        info!("Breath: {}, Voicing: {}, Tension: {}, Breathiness: {}", bre, voicing, tension, breathiness);
+        let mut wave = self.read_input(channel)?;
         info!("Wave length: {}", wave.len());
-        if tension != 0. || bre != voicing {
+        if tension != 0. || bre != voicing || breathiness != 0. {
             info!("Applying HNSEP separation for breath/voicing/tension adjustment");
-            let stem = self.in_file.file_stem().unwrap().to_str().unwrap();
-            let hnsep_path = self.in_file.with_file_name(format!("{}_hnsep", stem));
+            let hnsep_path = self.cache_path(channel, "_hnsep");
             let force_gen = self.flags.contains_key("G");
-            let seg_output = if !force_gen && hnsep_path.exists() {
-                CACHE_MANAGER.load_hnsep_cache(&hnsep_path, force_gen).unwrap()
-            } else {
-                info!("Generating HNSEP features: {}", hnsep_path.display());
-                let remover_arc = get_remover();
-                let mut remover = remover_arc.lock().unwrap();
-                let seg = remover.run(&wave);
-                CACHE_MANAGER.save_hnsep_cache(&hnsep_path, seg).unwrap()
+            let seg_output = match CACHE_MANAGER.load_hnsep_cache(&hnsep_path, force_gen) {
+                Some(seg) => seg,
+                None => {
+                    info!("Generating HNSEP features: {}", hnsep_path.display());
+                    let seg = get_remover().run(&wave);
+                    CACHE_MANAGER.save_hnsep_cache(&hnsep_path, seg)
+                        .map_err(|e| RenderError::CacheLocked(e.path))?
+                        .unwrap()
+                }
             };
-            let (bre_scale, voicing_scale) = (bre.clamp(0., 500.) / 100., voicing.clamp(0., 150.) / 100.);
+            let bre_scale = bre.clamp(0., 500.) / 100. * breathiness_scale(breathiness);
+            let voicing_scale = voicing.clamp(0., 150.) / 100.;
             if tension != 0. {
-                let mut voicing_seg = seg_output.iter()
+                let voicing_seg = seg_output.iter()
                     .map(|&s| voicing_scale * s)
                     .collect::<Vec<f64>>();
-                pre_emphasis_base_tension(&mut voicing_seg, -tension.clamp(-100., 100.) / 50.);
+                let voicing_seg = pre_emphasis_base_tension(&voicing_seg, -tension.clamp(-100., 100.) / 50.)?;
                 wave.iter_mut()
                     .zip(seg_output.iter())
                     .zip(voicing_seg.iter())
@@ -108,11 +243,7 @@ impl Resampler {
                         *w = bre_scale * (*w - s) + em;
                     });
             } else {
-                wave.iter_mut()
-                    .zip(seg_output.iter())
-                    .for_each(|(w, &s)| {
-                        *w = bre_scale * (*w - s) + voicing_scale * s;
-                    });
+                mix_hnsep(&mut wave, &seg_output, bre_scale, voicing_scale);
             };
         } else if bre != 100. || voicing != 100. {
             info!("Applying simple volume scaling: {}", bre / 100.);
@@ -134,28 +265,34 @@ impl Resampler {
         };
         let gender = self.flags.get("g").and_then(|o| o.as_ref()).copied().unwrap().clamp(-600., 600.);
         info!("Gender adjustment: {}", gender);
-        let mut mel_origin = mel(&mut wave, gender / 100., 1.);
+        let warp = HIFI_CONFIG.gender_warp && gender != 0.;
+        let mut mel_origin = mel(&mut wave, if warp { 0. } else { gender / 100. }, HIFI_CONFIG.analysis_speed);
         info!("Mel shape: {:?}", mel_origin.dim());
         dynamic_range_compression(&mut mel_origin);
+        if warp {
+            warp_envelope(&mut mel_origin, &MEL_CENTERS, (gender / 1200.).exp2(), 0.);
+        }
         Ok(Features { mel_origin, scale })
     }
-    fn resample(&self, features: &mut Features) -> Result<()> {
-        if self.out_file.file_name().and_then(|s| s.to_str()) == Some("nul") {
-            info!("Null output file - skipping write");
-            return Ok(());
-        }
+    /// Returns the render before the volume stage, along with its pre-normalization peak.
+    fn resample(&self, features: &mut Features) -> Result<(Vec<f64>, f64)> {
         let mel_origin = &mut features.mel_origin;
         info!(
             "Modulation: {:.1}, Scale: {:.1}, Mel shape: {:?}",
             self.modulation, features.scale, mel_origin.dim()
         );
+        // Mel frames are `analysis_speed` times the default hop apart in the input. Everything
+        // below works in seconds, so only the frame spacing changes; the note timing doesn't.
+        let thop_origin = analysis_hop(HIFI_CONFIG.analysis_speed) as f64 / SR_F64;
+        // Centred frames sit on their hop; the others half a hop later.
+        let origin_offset = frame_offset(thop_origin, HIFI_CONFIG.stft_center);
         let mel_cols = mel_origin.ncols();
         let mut t_origin = Vec::with_capacity(mel_cols);
         for i in 0..mel_cols {
-            let val = i as f64 * THOP_ORIGIN + THOP_ORIGIN_HALF;
+            let val = i as f64 * thop_origin + origin_offset;
             t_origin.push(val);
         }
-        let mut t_total = t_origin.last().copied().unwrap() + THOP_ORIGIN_HALF;
+        let mut t_total = t_origin.last().copied().unwrap() + origin_offset;
         let vel = (1.0 - self.velocity).exp2();
         let start = self.offset;
         let cutoff = self.cutoff;
@@ -169,19 +306,23 @@ impl Resampler {
         );
         if HIFI_CONFIG.loop_mode || self.flags.contains_key("He") {
             info!("Enabling loop mode");
-            let start_idx = (((con + THOP_ORIGIN_HALF) / THOP_ORIGIN).floor() as usize).clamp(0, mel_cols);
-            let end_idx = (((end + THOP_ORIGIN_HALF) / THOP_ORIGIN).floor() as usize).clamp(start_idx, mel_cols);
+            let start_idx = (((con + origin_offset) / thop_origin).floor() as usize).clamp(0, mel_cols);
+            let end_idx = (((end + origin_offset) / thop_origin).floor() as usize).clamp(start_idx, mel_cols);
             let mel_loop = mel_origin.slice(s![.., start_idx..end_idx]);
-            let pad_size = (length_req / THOP_ORIGIN).floor() as usize + 1;
-            let padded_mel = reflect_pad_2d(mel_loop, pad_size);
+            let pad_size = (length_req / thop_origin).floor() as usize + 1;
+            let padded_mel = match HIFI_CONFIG.loop_style {
+                LoopStyle::Reflect => reflect_pad_2d(mel_loop, pad_size),
+                LoopStyle::Crossfade => crossfade_pad_2d(mel_loop, pad_size, HIFI_CONFIG.loop_crossfade_frames),
+                LoopStyle::PingPong => pingpong_pad_2d(mel_loop, pad_size),
+            };
             *mel_origin = concatenate![Axis(1), mel_origin.slice(s![.., 0..start_idx]), padded_mel];
-            stretch_len = pad_size as f64 * THOP_ORIGIN;
+            stretch_len = pad_size as f64 * thop_origin;
             t_origin = Vec::with_capacity(mel_origin.ncols()); 
             for i in 0..mel_origin.ncols() {
-                let val = i as f64 * THOP_ORIGIN + THOP_ORIGIN_HALF;
+                let val = i as f64 * thop_origin + origin_offset;
                 t_origin.push(val);
             }
-            t_total = t_origin.last().copied().unwrap() + THOP_ORIGIN_HALF;
+            t_total = t_origin.last().copied().unwrap() + origin_offset;
             info!("Looped mel shape: {:?}, new total time: {:.4}", mel_origin.dim(), t_total);
         }
         let scal_ratio = if stretch_len < length_req {
@@ -213,16 +354,26 @@ impl Resampler {
         stretched_mel.iter_mut().for_each(|t| {
             *t = stretch(*t).clamp(0.0, t_origin.last().copied().unwrap());
         });
-        let mel_render = interp1d(&t_origin, &mel_origin, &stretched_mel);
+        let mut mel_render = interp1d(&t_origin, &mel_origin, &stretched_mel, Extrapolate::Clamp);
+        let formant = Formant::from_flags(&self.flags);
+        if !formant.is_neutral() {
+            info!("Formant: {:?}", formant);
+            formant.apply(&mut mel_render, &MEL_CENTERS);
+        }
         info!("Render mel shape: {:?}, Processing pitch...", mel_render.dim());
         let mut pitch_base = Vec::with_capacity(self.pitchbend.len());
+        // Quantize before the `t` offset so a deliberate detune survives the snap.
+        let quantize = self.flags.get("Hq").and_then(|o| o.as_ref()).copied().unwrap_or(0.);
         for &pb in &self.pitchbend {
-            let base = pb + self.pitch;
+            let base = quantize_pitch(pb + self.pitch, quantize);
             let val = self.flags.get("t")
                 .and_then(|o| o.as_ref())
                 .map_or(base, |&t| base + t.clamp(-1200., 1200.) / 100.0);
             pitch_base.push(val);
         }
+        if let Some(&strength) = self.flags.get("Hs").and_then(|o| o.as_ref()) {
+            smooth_pitch(&mut pitch_base, strength);
+        }
         let new_start = start * vel - slice_start as f64 * THOP;
         let new_end = (con * vel + length_req) - slice_start as f64 * THOP;
         let mut t = Vec::with_capacity(mel_render.ncols());
@@ -230,20 +381,30 @@ impl Resampler {
             let val = i as f64 * THOP;
             t.push(val);
         }
-        let t_scale = (self.pitchbend.len() as f64 - 1.) / (mel_render.ncols() as f64 * THOP);
-        let pitch_render = Akima::new(&pitch_base)
-            .sample_with_slice(&t.iter()
-                .map(|&x| x.clamp(0., mel_render.ncols() as f64 * THOP) * t_scale)
-                .collect::<Vec<_>>());
+        let t_total = mel_render.ncols() as f64 * THOP;
+        let fractions: Vec<f64> = t.iter().map(|&x| x.clamp(0., t_total) / t_total).collect();
+        let positions = self.tempo.point_positions(self.pitchbend.len(), &fractions);
+        let mut pitch_render = match HIFI_CONFIG.pitch_interp {
+            PitchInterp::Akima => Akima::new(&pitch_base).sample_with_slice(&positions),
+            PitchInterp::Cubic => CubicSpline::new(&pitch_base).sample_with_slice(&positions),
+            PitchInterp::Pchip => Pchip::new(&pitch_base).sample_with_slice(&positions),
+        };
+        if let Some(vibrato) = Vibrato::from_flags(&self.flags) {
+            info!("Vibrato: {:?}", vibrato);
+            let sustain_start = con * vel - slice_start as f64 * THOP;
+            vibrato.apply(&mut pitch_render, &t, new_start, sustain_start..new_end);
+        }
         let mut f0_render = Vec::with_capacity(pitch_render.len());
         for &x in &pitch_render {
             f0_render.push(midi_to_hz(x));
         }
         info!("F0 render length: {}", f0_render.len());
         let mut render = {
-            let vocoder_arc = get_vocoder();
-            let mut vocoder = vocoder_arc.lock().unwrap();
-            let mut wav_con = vocoder.run(mel_render, &f0_render);
+            let vocoder = self.flags.get("Hm").and_then(|o| o.as_ref()).map_or(0, |&m| m.max(0.) as usize);
+            if HIFI_CONFIG.mel_scale != MelScale::NaturalLog {
+                mel_render.mapv_inplace(|x| HIFI_CONFIG.mel_scale.encode(x));
+            }
+            let mut wav_con = vocode(vocoder, mel_render, &f0_render)?;
             info!("Vocoder output length: {}", wav_con.len());
             let (start_idx, end_idx) = (
                 (new_start * SR_F64).floor() as usize,
@@ -283,35 +444,100 @@ impl Resampler {
                 .zip(interp1d(
                     &t,
                     &Array2::from_shape_vec((1, gain_data.len()), gain_data).unwrap(),
-                    &audio_time
+                    &audio_time,
+                    Extrapolate::Clamp,
                 ).row(0).iter())
                 .for_each(|(r, g)| *r *= g);
             info!("Amplitude modulation applied");
         }
         render.iter_mut().for_each(|x| *x /= features.scale);
-        let max = render.iter()
-            .map(|x| x.abs())
-            .max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
-            .unwrap();
+        if let Some(&hp) = self.flags.get("Hp").and_then(|o| o.as_ref()).filter(|&&hp| hp != 0.0) {
+            info!("Applying pitch shift ({:.0} cents)", hp);
+            pitch_shift(&mut render, SR_F64, hp);
+        }
         if let Some(&hg) = self.flags.get("HG").and_then(|o| o.as_ref()) {
-            info!("Applying growl (strength: {:.1})", hg);
-            growl(&mut render, SR_F64, 80.0, hg.clamp(0.0, 100.0) / 100.0);
+            let rate = self.flags.get("HGf").and_then(|o| o.as_ref()).map_or(80.0, |&f| f.clamp(30.0, 200.0));
+            info!("Applying growl (strength: {:.1}, rate: {:.1} Hz)", hg, rate);
+            growl(&mut render, SR_F64, rate, hg.clamp(0.0, 100.0) / 100.0, HIFI_CONFIG.growl_lfo);
         }
-        if HIFI_CONFIG.wave_norm {
-            let p_strength = self.flags.get("P")
-                .and_then(|o| o.as_ref())
-                .copied()
-                .unwrap_or(100.0)
-                .clamp(0.0, 100.0) as u8; 
-            loudness_norm(&mut render, SR_F64,  -16.0, p_strength);
+        if let Some(&hd) = self.flags.get("Hd").and_then(|o| o.as_ref()).filter(|&&hd| hd > 0.0) {
+            info!("Applying de-esser (strength: {:.1})", hd);
+            de_ess(&mut render, SR_F64, hd);
         }
-        if max > HIFI_CONFIG.peak_limit {
-            render.iter_mut().for_each(|x| *x *= self.volume / max);
-        } else {
-            render.iter_mut().for_each(|x| *x *= self.volume);
+        if HIFI_CONFIG.noise_gate {
+            noise_gate(&mut render, SR_F64, HIFI_CONFIG.noise_gate_threshold);
         }
-        write_audio(&self.out_file, &render)?;
-        info!("Successfully processed: {} -> {}", self.in_file.display(), self.out_file.display());
+        let eq = ShelfEq::from_flags(&self.flags);
+        if !eq.is_neutral() {
+            info!("Shelf EQ: {:?}", eq);
+            eq.apply(&mut render, SR_F64);
+        }
+        // Taken before loudness normalization, which clamps to ±1, so a hot note still gets
+        // scaled down by the volume stage; the shaping stages above do count.
+        let mut max = render.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+        if let Some(p_strength) = loudness_strength(&self.flags, HIFI_CONFIG.wave_norm, HIFI_CONFIG.p_flag) {
+            loudness_norm(&mut render, SR_F64, HIFI_CONFIG.loudness_target_lufs, p_strength);
+        }
+        if let Some(compressor) = Compressor::from_config() {
+            info!("Applying compressor: {:?}", compressor);
+            compressor.apply(&mut render, SR_F64);
+        }
+        if peak_stage(&mut render, &self.flags, HIFI_CONFIG.p_flag, HIFI_CONFIG.peak_limit) {
+            max = render.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+        }
+        Ok((render, max))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::FFT_SIZE, utils::mel::analysis_stft};
+    #[test]
+    fn test_origin_frame_times_follow_onset() {
+        // Silence, then a tone at 0.5 s: the frame whose window is half full of tone (half the
+        // steady energy) must be timed at the onset in both framing modes.
+        let onset = 0.5;
+        let wave: Vec<f64> = (0..SAMPLE_RATE as usize)
+            .map(|i| i as f64 / SR_F64)
+            .map(|t| if t < onset { 0.0 } else { 0.5 * (2.0 * std::f64::consts::PI * 440.0 * t).sin() })
+            .collect();
+        let hop = analysis_hop(1.0);
+        let thop = hop as f64 / SR_F64;
+        for center in [false, true] {
+            let spec = analysis_stft(&mut wave.clone(), FFT_SIZE, hop, center);
+            let energy: Vec<f64> = spec.columns().into_iter().map(|c| c.iter().map(|x| x * x).sum()).collect();
+            let half = energy[energy.len() / 2 + 4] / 2.0;
+            let i = energy.iter().position(|&e| e >= half).unwrap();
+            let frac = (half - energy[i - 1]) / (energy[i] - energy[i - 1]);
+            let t = (i as f64 - 1.0 + frac) * thop + frame_offset(thop, center);
+            assert!((t - onset).abs() < thop / 4.0, "center={}: onset at {:.4} s", center, t);
+        }
+    }
+    #[test]
+    fn test_peak_stage() -> Result<()> {
+        let source: Vec<f64> = (0..1000).map(|n| 0.2 * (n as f64 * 0.01).sin()).collect();
+        let peak = |flags: &str, p_flag| -> Result<f64> {
+            let mut render = source.clone();
+            let ran = peak_stage(&mut render, &flag_parser(flags)?, p_flag, 0.9);
+            assert_eq!(ran, flags.starts_with('P') && p_flag == PFlagMode::Peak);
+            Ok(render.iter().fold(0.0f64, |m, x| m.max(x.abs())))
+        };
+        let original = peak("", PFlagMode::Peak)?;
+        assert!((peak("P100", PFlagMode::Peak)? - 0.9).abs() < 1e-12);
+        assert!((peak("P50", PFlagMode::Peak)? - (original + 0.9) / 2.0).abs() < 1e-3);
+        assert_eq!(peak("P100", PFlagMode::Loudness)?, original);
         Ok(())
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_z_skips_loudness_norm() -> Result<()> {
+        let loudness = PFlagMode::Loudness;
+        assert_eq!(loudness_strength(&flag_parser("")?, true, loudness), Some(100));
+        assert_eq!(loudness_strength(&flag_parser("P40")?, true, loudness), Some(40));
+        assert_eq!(loudness_strength(&flag_parser("P40Z")?, true, loudness), None);
+        assert_eq!(loudness_strength(&flag_parser("Zg-3")?, true, PFlagMode::Peak), None);
+        assert_eq!(loudness_strength(&flag_parser("P40")?, false, loudness), None);
+        // With `p_flag = peak`, P no longer touches the LUFS stage.
+        assert_eq!(loudness_strength(&flag_parser("P40")?, true, PFlagMode::Peak), Some(100));
+        Ok(())
+    }
+}
@@ -1,111 +1,581 @@
-use axum::{ extract::State, http::StatusCode, response::IntoResponse, routing::get, Router };
-use std::{ net::SocketAddr, path::PathBuf, sync::{Arc, atomic::{AtomicBool, Ordering}} };
+mod metrics;
+mod rate_limit;
+use anyhow::{anyhow, Result};
+use axum::{ extract::{ConnectInfo, DefaultBodyLimit, Query, Request, State}, http::{header::{AUTHORIZATION, CONTENT_TYPE}, HeaderMap, Method, StatusCode}, middleware::{self, Next}, response::{IntoResponse, Response}, routing::{get, post}, Json, Router };
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use once_cell::sync::Lazy;
+use std::{ collections::hash_map::RandomState, fmt, fs::File, hash::{BuildHasher, Hasher}, io, net::SocketAddr, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}, time::{Duration, Instant} };
 use tokio::sync::Semaphore;
-use tracing::{info, warn, error};
-use crate::resample::Resampler;
+use tracing::{info, info_span, warn, error, Instrument, Span};
+use self::{metrics::Metrics, rate_limit::RateLimiter};
+use crate::{audio, consts::HIFI_CONFIG, resample::{RenderError, Resampler, INLINE_OUTPUT}, utils::cache::CACHE_MANAGER};
+const REQUEST_ID_HEADER: &str = "x-request-id";
 #[derive(Clone)]
 pub struct AppState {
     server_ready: Arc<AtomicBool>,
     concurrency_semaphore: Arc<Semaphore>,
+    max_workers: usize,
+    priority_semaphore: Arc<Semaphore>,
+    priority_workers: usize,
+    version: Arc<VersionInfo>,
+    metrics: Arc<Metrics>,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
-pub fn split_arguments(input: &str) -> Vec<String> {
-    let tokens: Vec<&str> = input.split(' ').collect();
+#[derive(Clone, Serialize)]
+struct ModelInfo {
+    path: PathBuf,
+    size: u64,
+    sha256: String,
+}
+impl ModelInfo {
+    fn from_path(path: &Path) -> Self {
+        let digest = File::open(path).and_then(|mut file| {
+            let mut hasher = Sha256::new();
+            let size = io::copy(&mut file, &mut hasher)?;
+            Ok((size, hasher.finalize()))
+        });
+        let (size, sha256) = match digest {
+            Ok((size, hash)) => (size, hash.iter().map(|b| format!("{:02x}", b)).collect()),
+            Err(e) => {
+                warn!("Hash model {} failed: {}", path.display(), e);
+                (0, String::new())
+            }
+        };
+        Self { path: path.to_path_buf(), size, sha256 }
+    }
+}
+#[derive(Clone, Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    vocoder: ModelInfo,
+    hnsep: ModelInfo,
+}
+impl VersionInfo {
+    fn collect() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            vocoder: ModelInfo::from_path(&HIFI_CONFIG.vocoder_path),
+            hnsep: ModelInfo::from_path(&HIFI_CONFIG.hnsep_path),
+        }
+    }
+}
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+type ApiError = (StatusCode, Json<ErrorResponse>);
+fn api_error(status: StatusCode, code: &'static str, message: impl Into<String>, detail: Option<String>) -> ApiError {
+    (status, Json(ErrorResponse { code, message: message.into(), detail }))
+}
+fn not_ready() -> ApiError {
+    api_error(StatusCode::SERVICE_UNAVAILABLE, "not_ready", "Server initializing, please retry.", None)
+}
+/// Compares fixed-length SHA-256 digests, so neither the contents nor the length of the
+/// token leak through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(&b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+/// Checks the `Authorization: Bearer` header of mutating routes when `api_token` is set.
+/// The health check stays open so liveness probes keep working.
+fn authorize(token: &str, headers: &HeaderMap) -> Result<(), ApiError> {
+    if token.is_empty() {
+        return Ok(());
+    }
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if constant_time_eq(provided.trim().as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        warn!("Rejected request with missing or invalid token.");
+        Err(api_error(StatusCode::UNAUTHORIZED, "unauthorized", "Missing or invalid bearer token.", None))
+    }
+}
+fn render_error(e: &anyhow::Error) -> ApiError {
+    match e.downcast_ref::<RenderError>() {
+        Some(RenderError::InvalidArguments(msg)) => {
+            api_error(StatusCode::BAD_REQUEST, "invalid_arguments", "Invalid arguments.", Some(msg.clone()))
+        }
+        Some(RenderError::InputNotFound(path)) => {
+            api_error(StatusCode::NOT_FOUND, "input_not_found", "Input audio not found.", Some(path.display().to_string()))
+        }
+        Some(RenderError::Timeout(_)) => {
+            api_error(StatusCode::GATEWAY_TIMEOUT, "timeout", "Render timed out.", Some(e.to_string()))
+        }
+        Some(RenderError::CacheLocked(_)) => {
+            api_error(StatusCode::SERVICE_UNAVAILABLE, "cache_busy", "Cache is busy, please retry.", Some(e.to_string()))
+        }
+        None => {
+            api_error(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Error processing: Internal error.", Some(e.to_string()))
+        }
+    }
+}
+#[derive(Deserialize)]
+struct PostParams {
+    max_concurrency: Option<usize>,
+    dry_run: Option<u8>,
+    inline: Option<u8>,
+}
+/// `saturated` is set while every worker is busy, so clients can back off before queueing more.
+#[derive(Serialize)]
+struct HealthResponse {
+    ready: bool,
+    message: &'static str,
+    available_permits: usize,
+    max_workers: usize,
+    saturated: bool,
+}
+#[derive(Serialize)]
+struct BatchResult {
+    ok: bool,
+    message: String,
+}
+#[derive(Deserialize)]
+struct ClearCacheRequest {
+    path: PathBuf,
+}
+#[derive(Serialize)]
+struct ClearCacheResponse {
+    removed: usize,
+}
+/// The last 11 tokens are always the numeric/flag parameters; everything before them is
+/// `<input>.wav <output>`, where either path may contain spaces or `.wav` substrings and the
+/// output ends in one of [`audio::OUTPUT_EXTENSIONS`]. Every `.wav ` boundary that leaves such an
+/// output is a candidate split; the first one whose input exists on disk wins, falling back to
+/// the first candidate. An output of [`INLINE_OUTPUT`] asks for the audio in the response.
+pub fn split_arguments(input: &str) -> Result<Vec<String>> {
+    let tokens: Vec<&str> = input.trim_end().split(' ').collect();
+    if tokens.len() < 13 {
+        return Err(anyhow!("Expected at least 13 arguments, got {}", tokens.len()));
+    }
+    let params = tokens[tokens.len()-11..].iter().map(|s| s.to_string());
+    if tokens[tokens.len()-12] == INLINE_OUTPUT {
+        let mut args = vec![tokens[..tokens.len()-12].join(" "), INLINE_OUTPUT.to_string()];
+        args.extend(params);
+        return Ok(args);
+    }
     let prefix = tokens[..tokens.len()-11].join(" ");
-    let split_idx = prefix.find(".wav ").unwrap();
-    let (in_file, out_file) = prefix.split_at(split_idx + 4);
-    let mut args = vec![
-        in_file.to_string(),
-        out_file.trim_start_matches(' ').to_string()
-    ];
-    args.extend(tokens[tokens.len()-11..].iter().map(|s| s.to_string()));
-    args
+    let lower = prefix.to_ascii_lowercase();
+    let candidates: Vec<(&str, &str)> = lower
+        .match_indices(".wav ")
+        .map(|(idx, _)| {
+            let (in_file, out_file) = prefix.split_at(idx + 4);
+            (in_file, out_file.trim_start_matches(' '))
+        })
+        .filter(|(_, out_file)| {
+            Path::new(out_file)
+                .extension()
+                .is_some_and(|ext| audio::OUTPUT_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+        })
+        .collect();
+    let (in_file, out_file) = candidates
+        .iter()
+        .find(|(in_file, _)| Path::new(in_file).exists())
+        .or(candidates.first())
+        .ok_or_else(|| anyhow!("Cannot locate input/output paths in '{}'", prefix))?;
+    let mut args = vec![in_file.to_string(), out_file.to_string()];
+    args.extend(params);
+    Ok(args)
+}
+/// Clients that can build argv themselves may send it as a JSON array of 13 strings
+/// (`Content-Type: application/json`), which avoids guessing where the paths end.
+fn request_arguments(headers: &HeaderMap, body: &str) -> Result<Vec<String>> {
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().starts_with("application/json"));
+    if !is_json {
+        return split_arguments(body);
+    }
+    let args: Vec<String> = serde_json::from_str(body)?;
+    if args.len() != 13 {
+        return Err(anyhow!("Expected 13 arguments, got {}", args.len()));
+    }
+    Ok(args)
+}
+struct NoteInfo {
+    input: String,
+    output: String,
+}
+impl NoteInfo {
+    fn new(args: &[String]) -> Self {
+        Self {
+            input: Path::new(&args[0]).file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            output: Path::new(&args[1]).file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        }
+    }
+}
+impl fmt::Display for NoteInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' -> '{}'", self.input, self.output)
+    }
+}
+/// Interactive requests may ask for the priority lane through the `X-Max-Concurrency`
+/// header (or `?max_concurrency=`). It is honored only when the value does not exceed
+/// `priority_workers`, so inference parallelism stays bounded by `max_workers + priority_workers`.
+fn wants_priority(ceiling: usize, headers: &HeaderMap, params: &PostParams) -> bool {
+    let requested = headers
+        .get("x-max-concurrency")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .or(params.max_concurrency);
+    match requested {
+        Some(n) if n > 0 && n <= ceiling => true,
+        Some(n) => {
+            warn!("Ignoring max concurrency {} (priority_workers={})", n, ceiling);
+            false
+        }
+        None => false,
+    }
+}
+async fn render(state: &AppState, args: Vec<String>, priority: bool) -> Result<Option<Vec<Vec<f64>>>> {
+    let start = Instant::now();
+    let res = render_inner(state, args, priority).await;
+    state.metrics.record_render(start.elapsed(), res.is_ok());
+    res
+}
+async fn render_inner(state: &AppState, args: Vec<String>, priority: bool) -> Result<Option<Vec<Vec<f64>>>> {
+    let semaphore = if priority { &state.priority_semaphore } else { &state.concurrency_semaphore };
+    let permit = Arc::new(Mutex::new(Some(semaphore.clone().acquire_owned().await?)));
+    let (in_file, out_file) = (args[0].clone(), args[1].clone());
+    let span = Span::current();
+    let task = tokio::task::spawn_blocking({
+        let permit = permit.clone();
+        move || {
+            let _enter = span.enter();
+            let res = Resampler::new(args);
+            permit.lock().unwrap().take();
+            res
+        }
+    });
+    if HIFI_CONFIG.render_timeout_secs == 0 {
+        return task.await?;
+    }
+    let limit = Duration::from_secs(HIFI_CONFIG.render_timeout_secs);
+    match tokio::time::timeout(limit, task).await {
+        Ok(res) => res?,
+        Err(_) => {
+            // The blocking render cannot be cancelled; hand its permit back so the queue keeps moving.
+            permit.lock().unwrap().take();
+            error!("Render timed out after {:?}: '{}' -> '{}'", limit, in_file, out_file);
+            Err(RenderError::Timeout(limit).into())
+        }
+    }
 }
 async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
     let ready = state.server_ready.load(Ordering::SeqCst);
-    let (status, msg) = if ready {
-        (StatusCode::OK, "Server Ready")
-    } else {
-        (StatusCode::SERVICE_UNAVAILABLE, "Server Initializing")
+    let available_permits = state.concurrency_semaphore.available_permits();
+    let saturated = ready && available_permits == 0;
+    let (status, message) = match (ready, saturated) {
+        (false, _) => (StatusCode::SERVICE_UNAVAILABLE, "Server Initializing"),
+        (true, true) => (StatusCode::OK, "Server Saturated"),
+        (true, false) => (StatusCode::OK, "Server Ready"),
     };
-    info!("{}", msg);
-    (status, msg.to_string())
+    info!("{}", message);
+    (status, Json(HealthResponse {
+        ready,
+        message,
+        available_permits,
+        max_workers: state.max_workers,
+        saturated,
+    }))
+}
+async fn version(State(state): State<AppState>) -> Json<VersionInfo> {
+    Json(state.version.as_ref().clone())
+}
+async fn metrics(State(state): State<AppState>) -> String {
+    state.metrics.render()
+}
+fn next_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static STATE: Lazy<RandomState> = Lazy::new(RandomState::new);
+    let mut hasher = STATE.build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:08x}", hasher.finish() as u32)
+}
+async fn handle_post(
+    State(state): State<AppState>,
+    Query(params): Query<PostParams>,
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    let request_id = next_request_id();
+    let res = process_post(state, params, headers, body)
+        .instrument(info_span!("request", id = %request_id))
+        .await;
+    ([(REQUEST_ID_HEADER, request_id)], res)
 }
-async fn handle_post(State(state): State<AppState>, body: String) -> (StatusCode, String) {
+async fn process_post(
+    state: AppState,
+    params: PostParams,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, ApiError> {
+    authorize(&HIFI_CONFIG.api_token, &headers)?;
     if !state.server_ready.load(Ordering::SeqCst) {
         warn!("POST arrived but server not ready.");
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            "Server initializing, please retry.".to_string(),
-        );
+        return Err(not_ready());
     }
+    state.metrics.record_request();
     info!("post_data_string: {}", body);
-    let args = split_arguments(&body);
-    let note_info = format!(
-        "'{}' -> '{}'",
-        PathBuf::from(&args[0]).file_stem().unwrap().to_str().unwrap(),
-        PathBuf::from(&args[1]).file_name().unwrap().to_str().unwrap()
-    );
-    info!("Queued {} ...", note_info);
-    let permit = state.concurrency_semaphore.acquire_owned().await.unwrap();
-    let task_result = tokio::task::spawn_blocking(move || {
-        let _permit = permit;
-        Resampler::new(args)
-    }).await.unwrap();
-    match task_result {
-        Ok(()) => {
-            info!("Processing {} successful.", note_info);
-            (StatusCode::OK, format!("Success: {}", note_info))
+    let mut args = request_arguments(&headers, &body).map_err(|e| {
+        warn!("Invalid request: {}", e);
+        api_error(StatusCode::BAD_REQUEST, "invalid_arguments", "Invalid arguments.", Some(e.to_string()))
+    })?;
+    if params.inline.is_some_and(|v| v != 0) {
+        args[1] = INLINE_OUTPUT.to_string();
+    }
+    let note = NoteInfo::new(&args);
+    if params.dry_run.is_some_and(|v| v != 0) {
+        info!(input = %note.input, output = %note.output, "Dry run");
+        return match tokio::task::spawn_blocking(move || Resampler::validate(args)).await {
+            Ok(Ok(parsed)) => Ok(Json(parsed).into_response()),
+            Ok(Err(e)) => Err(render_error(&e)),
+            Err(e) => Err(render_error(&e.into())),
+        };
+    }
+    let priority = wants_priority(state.priority_workers, &headers, &params);
+    info!(input = %note.input, output = %note.output, priority, "Queued");
+    let start = Instant::now();
+    let res = render(&state, args, priority).await;
+    let duration_ms = start.elapsed().as_millis() as u64;
+    match res {
+        Ok(Some(audio)) => {
+            info!(input = %note.input, duration_ms, "Processing successful (inline)");
+            let wav = audio::encode_wav(&audio).map_err(|e| render_error(&e))?;
+            Ok(([(CONTENT_TYPE, "audio/wav")], wav).into_response())
+        }
+        Ok(None) => {
+            info!(input = %note.input, output = %note.output, duration_ms, "Processing successful");
+            Ok(format!("Success: {}", note).into_response())
         }
         Err(e) => {
-            error!("Processing {} failed: {}", note_info, e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error processing: Internal error.".to_string()
-            )
+            error!(input = %note.input, output = %note.output, duration_ms, error = %e, "Processing failed");
+            Err(render_error(&e))
         }
     }
 }
-pub async fn run(port: u16, max_workers: usize) {
+async fn handle_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    let request_id = next_request_id();
+    let res = process_batch(state, headers, body)
+        .instrument(info_span!("request", id = %request_id))
+        .await;
+    ([(REQUEST_ID_HEADER, request_id)], res)
+}
+async fn process_batch(
+    state: AppState,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<Vec<BatchResult>>, ApiError> {
+    authorize(&HIFI_CONFIG.api_token, &headers)?;
+    if !state.server_ready.load(Ordering::SeqCst) {
+        warn!("Batch arrived but server not ready.");
+        return Err(not_ready());
+    }
+    let tasks: Vec<_> = body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(index, line)| {
+            let (state, line) = (state.clone(), line.to_string());
+            state.metrics.record_request();
+            tokio::spawn(async move {
+                let args = split_arguments(&line)?;
+                let note = NoteInfo::new(&args);
+                let start = Instant::now();
+                let res = render(&state, args, false).await;
+                let duration_ms = start.elapsed().as_millis() as u64;
+                res.inspect(|_| info!(input = %note.input, output = %note.output, duration_ms, "Processing successful"))
+                    .inspect_err(|e| error!(input = %note.input, output = %note.output, duration_ms, error = %e, "Processing failed"))?;
+                Ok::<_, anyhow::Error>(note)
+            }.instrument(info_span!("line", index)))
+        })
+        .collect();
+    info!("Queued batch of {} renders ...", tasks.len());
+    let mut results = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        results.push(match task.await {
+            Ok(Ok(note)) => BatchResult { ok: true, message: format!("Success: {}", note) },
+            Ok(Err(e)) => BatchResult { ok: false, message: e.to_string() },
+            Err(e) => BatchResult { ok: false, message: e.to_string() },
+        });
+    }
+    Ok(Json(results))
+}
+async fn clear_cache(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ClearCacheRequest>,
+) -> Result<Json<ClearCacheResponse>, ApiError> {
+    authorize(&HIFI_CONFIG.api_token, &headers)?;
+    if HIFI_CONFIG.cache_clear_root.as_os_str().is_empty() {
+        return Err(api_error(StatusCode::FORBIDDEN, "forbidden", "Cache clearing is disabled.", None));
+    }
+    let (Ok(root), Ok(dir)) = (HIFI_CONFIG.cache_clear_root.canonicalize(), req.path.canonicalize()) else {
+        return Err(api_error(StatusCode::BAD_REQUEST, "invalid_path", "Invalid path.", Some(req.path.display().to_string())));
+    };
+    if !dir.starts_with(&root) {
+        warn!("Refused to clear cache outside {}: {}", root.display(), dir.display());
+        return Err(api_error(StatusCode::FORBIDDEN, "forbidden", "Path is outside the allowed cache root.", None));
+    }
+    let (Ok(permits), Ok(priority_permits)) = (
+        state.concurrency_semaphore.clone().try_acquire_many_owned(state.max_workers as u32),
+        state.priority_semaphore.clone().try_acquire_many_owned(state.priority_workers as u32),
+    ) else {
+        return Err(api_error(StatusCode::CONFLICT, "busy", "Renders in progress, please retry.", None));
+    };
+    let removed = tokio::task::spawn_blocking(move || {
+        let _permits = (permits, priority_permits);
+        CACHE_MANAGER.clear_dir(&dir)
+    }).await.unwrap();
+    Ok(Json(ClearCacheResponse { removed }))
+}
+/// Peers are keyed by IP from `ConnectInfo`, which only TCP listeners provide.
+async fn rate_limit(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if let (Some(limiter), Some(ConnectInfo(peer))) = (&state.rate_limiter, req.extensions().get::<ConnectInfo<SocketAddr>>())
+        && req.method() == Method::POST
+        && !limiter.check(peer.ip())
+    {
+        warn!("Rate limit exceeded for {}", peer.ip());
+        return api_error(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "Too many requests, please slow down.", None)
+            .into_response();
+    }
+    next.run(req).await
+}
+async fn shutdown_signal(state: AppState) {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Failed to listen for shutdown signal: {}", e);
+        return;
+    }
+    state.server_ready.store(false, Ordering::SeqCst);
+    info!("Shutdown requested, waiting for in-flight renders...");
+}
+async fn serve_tcp(addr: SocketAddr, app: Router, state: AppState) {
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    state.server_ready.store(true, Ordering::SeqCst);
+    info!(
+        "Listening on {}; axum + inference-thread={}",
+        listener.local_addr().unwrap(),
+        state.max_workers
+    );
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(state))
+        .await
+        .unwrap();
+}
+/// Clears a socket left behind by a server that didn't shut down cleanly. Anything else at
+/// `path`, or a socket another server still answers on, is left alone and reported.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("another server is listening on {}", path.display())));
+    }
+    warn!("Removing stale socket: {}", path.display());
+    std::fs::remove_file(path)
+}
+#[cfg(unix)]
+async fn serve_unix(path: &Path, app: Router, state: AppState) {
+    remove_stale_socket(path).unwrap_or_else(|e| panic!("Cannot listen on unix:{}: {}", path.display(), e));
+    let listener = tokio::net::UnixListener::bind(path).unwrap();
+    state.server_ready.store(true, Ordering::SeqCst);
+    info!(
+        "Listening on unix:{}; axum + inference-thread={}",
+        path.display(),
+        state.max_workers
+    );
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(state))
+        .await
+        .unwrap();
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("Remove socket {} failed: {}", path.display(), e);
+    }
+}
+pub async fn run(addr: SocketAddr, max_workers: usize) {
     info!("Starting server (max_workers={})...", max_workers);
     let app_state = AppState {
         server_ready: Arc::new(AtomicBool::new(false)),
         concurrency_semaphore: Arc::new(Semaphore::new(max_workers)),
+        max_workers,
+        priority_semaphore: Arc::new(Semaphore::new(HIFI_CONFIG.priority_workers)),
+        priority_workers: HIFI_CONFIG.priority_workers,
+        version: Arc::new(tokio::task::spawn_blocking(VersionInfo::collect).await.unwrap()),
+        metrics: Arc::new(Metrics::default()),
+        rate_limiter: (HIFI_CONFIG.rate_limit_per_sec > 0)
+            .then(|| Arc::new(RateLimiter::new(HIFI_CONFIG.rate_limit_per_sec))),
     };
     let app = Router::new()
         .route("/", get(health_check).post(handle_post))
+        // A batch carries one note per line, so it gets its own, larger limit.
+        .route("/batch", post(handle_batch).layer(DefaultBodyLimit::max(HIFI_CONFIG.max_batch_body_bytes)))
+        .route("/cache/clear", post(clear_cache))
+        .route("/version", get(version))
+        .route("/metrics", get(metrics))
+        .layer(middleware::from_fn_with_state(app_state.clone(), rate_limit))
+        .layer(DefaultBodyLimit::max(HIFI_CONFIG.max_body_bytes))
         .with_state(app_state.clone());
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    app_state.server_ready.store(true, Ordering::SeqCst);
-    info!(
-        "Listening on {}; axum + inference-thread={}",
-        listener.local_addr().unwrap(),
-        max_workers
-    );
-    axum::serve(listener, app).await.unwrap();
+    let uds_path = Some(HIFI_CONFIG.uds_path.as_path()).filter(|path| !path.as_os_str().is_empty());
+    match uds_path {
+        #[cfg(unix)]
+        Some(path) => serve_unix(path, app, app_state.clone()).await,
+        _ => {
+            if let Some(path) = uds_path {
+                warn!("Unix domain sockets are not supported on this platform, ignoring uds_path={}", path.display());
+            }
+            serve_tcp(addr, app, app_state.clone()).await;
+        }
+    }
+    let _drained = app_state.concurrency_semaphore
+        .acquire_many(max_workers as u32)
+        .await
+        .unwrap();
+    let _priority_drained = app_state.priority_semaphore
+        .acquire_many(app_state.priority_workers as u32)
+        .await
+        .unwrap();
+    info!("All renders finished, server stopped.");
 }
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
     use crate::{
-        server::split_arguments,
+        resample::{RenderError, Resampler, INLINE_OUTPUT},
+        server::{authorize, next_request_id, render_error, request_arguments, split_arguments, wants_priority, ModelInfo, PostParams},
         utils::parser::{pitch_parser, tempo_parser}
     };
     #[test]
     fn test_basic_arguments() {
         let input = "input.wav output.wav C4 1.0 \"\" 0.0 1000.0 0.0 0.0 100.0 0.0 !120 AA";
-        let args = split_arguments(input);
+        let args = split_arguments(input).unwrap();
         assert_eq!(args[0], "input.wav");
         assert_eq!(args[1], "output.wav");
         let pitch = pitch_parser(&args[2]).unwrap();
         assert_eq!(pitch, 60);
         let tempo = tempo_parser(&args[11]).unwrap();
-        assert_eq!(tempo, 120.0);
+        assert_eq!(tempo.initial(), 120.0);
     }
     #[test]
     fn test_paths_with_spaces() {
         let input = "my audio file.wav output dir/result.wav A4 0.8 \"flag\" 1.5 2000.0 0.5 0.3 90.0 2.0 !90 B7CPCV";
-        let args = split_arguments(input);
+        let args = split_arguments(input).unwrap();
         assert_eq!(args[0], "my audio file.wav");
         assert_eq!(args[1], "output dir/result.wav");
         let pitch = pitch_parser(&args[2]).unwrap();
@@ -114,7 +584,7 @@ mod tests {
     #[test]
     fn test_minimum_tokens() {
         let input = "a.wav b.wav 60 0.0 x 0.0 0.0 0.0 0.0 0.0 0.0 !100 zz";
-        let args = split_arguments(input);
+        let args = split_arguments(input).unwrap();
         assert_eq!(args.len(), 13);
         assert_eq!(args[0], "a.wav");
         assert_eq!(args[1], "b.wav");
@@ -122,22 +592,157 @@ mod tests {
     #[test]
     fn test_parameter_types() {
         let input = "in.wav out.wav C5 1.5 \"fe+10\" -2.3 500.5 3.0 -0.5 80.0 -1.0 !150 AB#14#CD";
-        let args = split_arguments(input);
+        let args = split_arguments(input).unwrap();
         let pitch = pitch_parser(&args[2]).unwrap();
         assert_eq!(pitch, 72);
         let tempo = tempo_parser(&args[11]).unwrap();
-        assert_eq!(tempo, 150.0);
+        assert_eq!(tempo.initial(), 150.0);
         let offset: f64 = args[5].parse().unwrap();
         assert_eq!(offset, -2.3);
     }
     #[test]
     fn test_path_compatibility() {
         let input = "test data/input.wav output_dir/out.wav D4 1.0 \"\" 0.0 500.0 0.0 0.0 80.0 0.0 !100 C5CC";
-        let args = split_arguments(input);
+        let args = split_arguments(input).unwrap();
         let in_path = PathBuf::from(&args[0]);
         assert!(in_path.ends_with("input.wav"));
         let out_path = PathBuf::from(&args[1]);
         assert!(out_path.ends_with("out.wav"));
         assert!(out_path.starts_with("output_dir"));
     }
+    #[test]
+    fn test_wav_substrings_in_paths() {
+        let params = "C4 100 g0 0 500 0 0 100 0 !100 AA";
+        let args = split_arguments(&format!("C:\\voice\\a.wav.bak.wav C:\\my.wav songs\\out.wav {}", params)).unwrap();
+        assert_eq!(args[0], "C:\\voice\\a.wav.bak.wav");
+        assert_eq!(args[1], "C:\\my.wav songs\\out.wav");
+        let args = split_arguments(&format!("in.WAV out.Wav {}", params)).unwrap();
+        assert_eq!((args[0].as_str(), args[1].as_str()), ("in.WAV", "out.Wav"));
+        let dir = std::env::temp_dir().join("hifi_split.wav dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        let in_file = dir.join("a.wav");
+        std::fs::write(&in_file, b"").unwrap();
+        let in_str = in_file.to_str().unwrap();
+        let args = split_arguments(&format!("{} cache/out.wav {}", in_str, params)).unwrap();
+        assert_eq!(args[0], in_str);
+        assert_eq!(args[1], "cache/out.wav");
+        std::fs::remove_dir_all(&dir).unwrap();
+        let args = split_arguments(&format!("a.wav my.wav songs\\b.FLAC {}", params)).unwrap();
+        assert_eq!((args[0].as_str(), args[1].as_str()), ("a.wav", "my.wav songs\\b.FLAC"));
+        assert!(split_arguments(&format!("a.wav b.mp3 {}", params)).is_err());
+    }
+    #[test]
+    fn test_invalid_arguments() {
+        assert!(split_arguments("a.wav b.wav 60").is_err());
+        assert!(split_arguments("a.flac b.flac 60 0.0 x 0.0 0.0 0.0 0.0 0.0 0.0 !100 zz").is_err());
+    }
+    #[test]
+    fn test_json_arguments() {
+        use axum::http::{header::CONTENT_TYPE, HeaderMap, HeaderValue};
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+        let body = r#"["C:\\a.wav.bak.wav", "D:\\my.wav dir\\out.wav", "C4", "100", "g0", "0", "500", "0", "0", "100", "0", "!100", "AA"]"#;
+        let args = request_arguments(&headers, body).unwrap();
+        assert_eq!(args[0], "C:\\a.wav.bak.wav");
+        assert_eq!(args[1], "D:\\my.wav dir\\out.wav");
+        assert_eq!(args[12], "AA");
+        assert!(request_arguments(&headers, r#"["a.wav", "b.wav"]"#).is_err());
+        assert!(request_arguments(&headers, "a.wav b.wav C4 100 g0 0 500 0 0 100 0 !100 AA").is_err());
+        assert_eq!(request_arguments(&HeaderMap::new(), "a.wav b.wav C4 100 g0 0 500 0 0 100 0 !100 AA").unwrap().len(), 13);
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_remove_stale_socket() {
+        use super::remove_stale_socket;
+        use std::os::unix::net::UnixListener;
+        let path = std::env::temp_dir().join("hifi_stale_test.sock");
+        let _ = std::fs::remove_file(&path);
+        assert!(remove_stale_socket(&path).is_ok());
+        std::fs::write(&path, b"").unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+        let live = UnixListener::bind(&path).unwrap();
+        assert_eq!(remove_stale_socket(&path).unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+        drop(live);
+        assert!(path.exists());
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+    }
+    #[test]
+    fn test_inline_text_body() {
+        use axum::http::{header::CONTENT_TYPE, HeaderMap, HeaderValue};
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+        let args = request_arguments(&headers, "C:\\my voice\\a.wav - C4 100 g0 0 500 0 0 100 0 !100 AA").unwrap();
+        assert_eq!(args.len(), 13);
+        assert_eq!((args[0].as_str(), args[1].as_str()), ("C:\\my voice\\a.wav", INLINE_OUTPUT));
+        assert_eq!(args[12], "AA");
+    }
+    #[test]
+    fn test_dry_run_validation() {
+        let args = split_arguments("missing_dry_run.wav out.wav C4 100 g0 0 500 0 0 100 0 !100 AA").unwrap();
+        let err = Resampler::validate(args).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RenderError>(), Some(RenderError::InputNotFound(_))));
+        let err = Resampler::validate(vec!["a.wav".to_string()]).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RenderError>(), Some(RenderError::InvalidArguments(_))));
+    }
+    #[test]
+    fn test_render_error_status() {
+        use axum::http::StatusCode;
+        let not_found = RenderError::InputNotFound(PathBuf::from("a.wav")).into();
+        assert_eq!(render_error(&not_found).0, StatusCode::NOT_FOUND);
+        let invalid = RenderError::InvalidArguments("bad pitch".to_string()).into();
+        assert_eq!(render_error(&invalid).0, StatusCode::BAD_REQUEST);
+        let timeout = RenderError::Timeout(std::time::Duration::from_secs(1)).into();
+        assert_eq!(render_error(&timeout).0, StatusCode::GATEWAY_TIMEOUT);
+        let locked = RenderError::CacheLocked(PathBuf::from("a_hnsep")).into();
+        assert_eq!(render_error(&locked).0, StatusCode::SERVICE_UNAVAILABLE);
+        let internal = anyhow::anyhow!("vocoder failed");
+        let (status, body) = render_error(&internal);
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body.code, "internal_error");
+        assert_eq!(body.detail.as_deref(), Some("vocoder failed"));
+    }
+    #[test]
+    fn test_priority_request() {
+        use axum::http::HeaderMap;
+        let mut headers = HeaderMap::new();
+        let no_query = PostParams { max_concurrency: None, dry_run: None, inline: None };
+        assert!(!wants_priority(2, &headers, &no_query));
+        assert!(wants_priority(2, &headers, &PostParams { max_concurrency: Some(2), dry_run: None, inline: None }));
+        headers.insert("x-max-concurrency", "1".parse().unwrap());
+        assert!(wants_priority(2, &headers, &no_query));
+        assert!(!wants_priority(0, &headers, &no_query));
+        headers.insert("x-max-concurrency", "8".parse().unwrap());
+        assert!(!wants_priority(2, &headers, &no_query));
+    }
+    #[test]
+    fn test_bearer_auth() {
+        use axum::http::{header::AUTHORIZATION, HeaderMap};
+        let mut headers = HeaderMap::new();
+        assert!(authorize("", &headers).is_ok());
+        assert!(authorize("secret", &headers).is_err());
+        for wrong in ["Bearer secreT", "Bearer secre", "Bearer secrets"] {
+            headers.insert(AUTHORIZATION, wrong.parse().unwrap());
+            assert!(authorize("secret", &headers).is_err(), "{}", wrong);
+        }
+        headers.insert(AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(authorize("secret", &headers).is_ok());
+    }
+    #[test]
+    fn test_request_id_unique() {
+        let (a, b) = (next_request_id(), next_request_id());
+        assert_eq!(a.len(), 8);
+        assert_ne!(a, b);
+    }
+    #[test]
+    fn test_model_info_hash() {
+        let path = std::env::temp_dir().join("hifi_model_info_test.onnx");
+        std::fs::write(&path, b"abc").unwrap();
+        let info = ModelInfo::from_path(&path);
+        assert_eq!(info.size, 3);
+        assert_eq!(info.sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        std::fs::remove_file(&path).unwrap();
+    }
 }
\ No newline at end of file
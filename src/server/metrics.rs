@@ -0,0 +1,70 @@
+use std::{fmt::Write, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use crate::utils::cache::CACHE_MANAGER;
+const DURATION_BUCKETS: [f64; 9] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    successes: AtomicU64,
+    failures: AtomicU64,
+    duration_buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    duration_count: AtomicU64,
+    duration_sum_micros: AtomicU64,
+}
+impl Metrics {
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn record_render(&self, elapsed: Duration, success: bool) {
+        let counter = if success { &self.successes } else { &self.failures };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64();
+        DURATION_BUCKETS.iter()
+            .zip(self.duration_buckets.iter())
+            .filter(|&(&le, _)| secs <= le)
+            .for_each(|(_, bucket)| { bucket.fetch_add(1, Ordering::Relaxed); });
+        self.duration_count.fetch_add(1, Ordering::Relaxed);
+        self.duration_sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}");
+        };
+        let (hits, misses) = CACHE_MANAGER.feature_cache_stats();
+        counter("hifi_requests_total", "Total render requests received.", self.requests.load(Ordering::Relaxed));
+        counter("hifi_render_success_total", "Renders finished successfully.", self.successes.load(Ordering::Relaxed));
+        counter("hifi_render_failure_total", "Renders that failed or timed out.", self.failures.load(Ordering::Relaxed));
+        counter("hifi_feature_cache_hits_total", "Feature cache loads served from disk.", hits);
+        counter("hifi_feature_cache_misses_total", "Feature cache loads that required generation.", misses);
+        let name = "hifi_render_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Render duration in seconds.\n# TYPE {name} histogram");
+        DURATION_BUCKETS.iter()
+            .zip(self.duration_buckets.iter())
+            .for_each(|(le, bucket)| {
+                let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {}", bucket.load(Ordering::Relaxed));
+            });
+        let count = self.duration_count.load(Ordering::Relaxed);
+        let sum = self.duration_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}\n{name}_sum {sum}\n{name}_count {count}");
+        out
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_duration_histogram() {
+        let metrics = Metrics::default();
+        metrics.record_request();
+        metrics.record_render(Duration::from_millis(300), true);
+        metrics.record_render(Duration::from_secs(20), false);
+        let text = metrics.render();
+        assert!(text.contains("hifi_requests_total 1\n"));
+        assert!(text.contains("hifi_render_success_total 1\n"));
+        assert!(text.contains("hifi_render_failure_total 1\n"));
+        assert!(text.contains("hifi_render_duration_seconds_bucket{le=\"0.25\"} 0\n"));
+        assert!(text.contains("hifi_render_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(text.contains("hifi_render_duration_seconds_bucket{le=\"30\"} 2\n"));
+        assert!(text.contains("hifi_render_duration_seconds_count 2\n"));
+    }
+}
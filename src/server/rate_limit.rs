@@ -0,0 +1,59 @@
+use dashmap::DashMap;
+use std::{net::IpAddr, time::{Duration, Instant}};
+const MAX_TRACKED_PEERS: usize = 4096;
+const IDLE_EVICT: Duration = Duration::from_secs(60);
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+/// Token bucket per peer IP: refills at `rate` tokens/s and bursts up to `rate` (at least 1).
+pub struct RateLimiter {
+    rate: f64,
+    capacity: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+}
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            capacity: (rate as f64).max(1.0),
+            buckets: DashMap::new(),
+        }
+    }
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() > MAX_TRACKED_PEERS {
+            self.buckets.retain(|_, b| now.saturating_duration_since(b.last) < IDLE_EVICT);
+        }
+        let mut bucket = self.buckets
+            .entry(ip)
+            .or_insert(Bucket { tokens: self.capacity, last: now });
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(2);
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let now = Instant::now();
+        assert!(limiter.check_at(a, now));
+        assert!(limiter.check_at(a, now));
+        assert!(!limiter.check_at(a, now));
+        assert!(limiter.check_at(b, now));
+        assert!(limiter.check_at(a, now + Duration::from_millis(500)));
+        assert!(!limiter.check_at(a, now + Duration::from_millis(500)));
+    }
+}
@@ -3,9 +3,13 @@ pub mod stft;
 pub mod parser;
 pub mod cache;
 pub mod growl;
+pub mod formant;
+pub mod pitch;
 pub mod mel;
 mod mel_basis;
-use ndarray::{Array2, ArrayView2, Axis, azip, s};
+use ndarray::{Array2, ArrayView2, ArrayViewMut1, Axis, azip, concatenate, s};
+use once_cell::sync::OnceCell;
+use oxifft::threading::{ThreadPool, get_default_pool};
 use std::{cmp::Ordering, f64::EPSILON};
 #[inline(always)]
 pub fn lerp(a: f64, b: f64, t: f64) -> f64 {
@@ -19,13 +23,63 @@ pub fn midi_to_hz(x: f64) -> f64 {
 pub fn dynamic_range_compression(s: &mut Array2<f64>) {
     s.mapv_inplace(|x| x.max(1e-9).ln());
 }
-pub fn interp1d(x: &[f64], y: &Array2<f64>, xi: &[f64]) -> Array2<f64> {
+/// What [`interp1d`] returns for queries outside the sampled range: the nearest end column
+/// (`Clamp`), the first or last segment's line carried on (`Linear`), or zeros (`Zero`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Extrapolate {
+    #[default]
+    Clamp,
+    Linear,
+    Zero,
+}
+/// Output columns per task when [`interp1d`] splits a long stretch across the thread pool.
+const INTERP_CHUNK_COLUMNS: usize = 256;
+/// Linear interpolation of the columns of `y`, sampled at `x`, at the points `xi`.
+pub fn interp1d(x: &[f64], y: &Array2<f64>, xi: &[f64], extrapolate: Extrapolate) -> Array2<f64> {
+    if xi.len() >= 2 * INTERP_CHUNK_COLUMNS {
+        interp1d_parallel(x, y, xi, extrapolate)
+    } else {
+        interp1d_serial(x, y, xi, extrapolate)
+    }
+}
+/// Every column is found and blended independently, so chunks of `xi` give the same result
+/// as the serial path.
+fn interp1d_parallel(x: &[f64], y: &Array2<f64>, xi: &[f64], extrapolate: Extrapolate) -> Array2<f64> {
+    let chunks: Vec<&[f64]> = xi.chunks(INTERP_CHUNK_COLUMNS).collect();
+    let results: Vec<OnceCell<Array2<f64>>> = (0..chunks.len()).map(|_| OnceCell::new()).collect();
+    get_default_pool().parallel_for(chunks.len(), |c| {
+        let _ = results[c].set(interp1d_serial(x, y, chunks[c], extrapolate));
+    });
+    let views: Vec<ArrayView2<f64>> = results.iter().map(|r| r.get().unwrap().view()).collect();
+    concatenate(Axis(1), &views).unwrap()
+}
+fn interp1d_serial(x: &[f64], y: &Array2<f64>, xi: &[f64], extrapolate: Extrapolate) -> Array2<f64> {
     let (n_r, n_xi) = (y.nrows(), xi.len());
     let mut res = Array2::zeros((n_r, n_xi));
     let (y_col0, y_col_e) = (y.column(0), y.column(x.len() - 1));
     let (x_first, x_last) = (x[0], *x.last().unwrap());
+    let blend = |mut res_col: ArrayViewMut1<f64>, idx: usize, t: f64| {
+        azip!((
+            res in &mut res_col,
+            &y0 in &y.column(idx),
+            &y1 in &y.column(idx + 1)
+        ) {
+            *res = lerp(y0, y1, t);
+        });
+    };
     azip!((mut res_col in res.axis_iter_mut(Axis(1)), &xi_val in xi) {
-        if xi_val >= x_last - EPSILON {
+        let outside = xi_val < x_first || xi_val > x_last;
+        if outside && extrapolate == Extrapolate::Zero {
+            // Already zero.
+        } else if outside && extrapolate == Extrapolate::Linear && x.len() > 1 {
+            let idx = if xi_val < x_first { 0 } else { x.len() - 2 };
+            if (x[idx + 1] - x[idx]).abs() < f64::EPSILON {
+                // A zero-width edge segment has no slope to continue; hold the edge value.
+                res_col.assign(if xi_val < x_first { &y_col0 } else { &y_col_e });
+            } else {
+                blend(res_col, idx, (xi_val - x[idx]) / (x[idx + 1] - x[idx]));
+            }
+        } else if xi_val >= x_last - EPSILON {
             res_col.assign(&y_col_e);
         } else if xi_val <= x_first + EPSILON {
             res_col.assign(&y_col0);
@@ -34,13 +88,7 @@ pub fn interp1d(x: &[f64], y: &Array2<f64>, xi: &[f64]) -> Array2<f64> {
                 .unwrap_or_else(|i| i.saturating_sub(1))
                 .clamp(0, x.len() - 2);
             let t = if (x[idx+1] - x[idx]).abs() < EPSILON { 0.0 } else { (xi_val - x[idx]) / (x[idx+1] - x[idx]) };
-            azip!((
-                res in &mut res_col,
-                &y0 in &y.column(idx),
-                &y1 in &y.column(idx + 1)
-            ) {
-                *res = lerp(y0, y1, t);
-            });
+            blend(res_col, idx, t);
         }
     });
     res
@@ -64,6 +112,46 @@ pub fn reflect_pad_2d(arr: ArrayView2<f64>, pad: usize) -> Array2<f64> {
         });
     pad_arr
 }
+/// Extends `arr` by `pad` frames by repeating it as a cycle whose first `fade` frames blend the
+/// tail back into the head (equal power: sin²/cos² weights on power, `arr` being log-magnitude
+/// mel), so every repeat continues smoothly from the previous one at a constant level.
+pub fn crossfade_pad_2d(arr: ArrayView2<f64>, pad: usize, fade: usize) -> Array2<f64> {
+    let n_cols = arr.ncols();
+    if n_cols == 0 {
+        return arr.to_owned();
+    }
+    let fade = fade.min(n_cols / 2);
+    let period = n_cols - fade;
+    let mut cycle = arr.slice(s![.., 0..period]).to_owned();
+    for j in 0..fade {
+        let t = (j as f64 + 0.5) / fade as f64 * std::f64::consts::FRAC_PI_2;
+        let (fade_in, fade_out) = (t.sin().powi(2), t.cos().powi(2));
+        azip!((c in &mut cycle.column_mut(j), &tail in &arr.column(period + j)) {
+            *c = 0.5 * (fade_out * (2.0 * tail).exp() + fade_in * (2.0 * *c).exp()).max(1e-18).ln();
+        });
+    }
+    let mut pad_arr = Array2::zeros((arr.nrows(), n_cols + pad));
+    pad_arr.slice_mut(s![.., 0..period]).assign(&arr.slice(s![.., 0..period]));
+    for col in period..n_cols + pad {
+        pad_arr.column_mut(col).assign(&cycle.column((col - period) % period));
+    }
+    pad_arr
+}
+/// Extends `arr` by `pad` frames, playing it forward then backward in turn without repeating
+/// the turning frames.
+pub fn pingpong_pad_2d(arr: ArrayView2<f64>, pad: usize) -> Array2<f64> {
+    let n_cols = arr.ncols();
+    if n_cols == 0 {
+        return arr.to_owned();
+    }
+    let period = (2 * (n_cols - 1)).max(1);
+    let mut pad_arr = Array2::zeros((arr.nrows(), n_cols + pad));
+    for (col, mut pad_col) in pad_arr.axis_iter_mut(Axis(1)).enumerate() {
+        let k = col % period;
+        pad_col.assign(&arr.column(if k < n_cols { k } else { period - k }));
+    }
+    pad_arr
+}
 pub fn reflect_pad_1d(s: &mut Vec<f64>, left: usize, right: usize) {
     let len = s.len();
     s.reserve(left + right);
@@ -71,7 +159,7 @@ pub fn reflect_pad_1d(s: &mut Vec<f64>, left: usize, right: usize) {
     s.copy_within(0..len, left);
     for i in 0..left {
         let m_idx = 1 + (i % (len - 1));
-        s[i] = s[left + m_idx];
+        s[left - 1 - i] = s[left + m_idx];
     }
     for i in 0..right {
         let m_idx = (len - 2) - (i % (len - 1));
@@ -89,4 +177,82 @@ pub fn linspace(start: f64, end: f64, n: usize) -> Vec<f64> {
             (0..n).map(|i| start + step * i as f64).collect()
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn max_step(arr: &Array2<f64>) -> f64 {
+        (1..arr.ncols())
+            .map(|i| (&arr.column(i) - &arr.column(i - 1)).mapv(f64::abs).fold(0.0, |a: f64, &b| a.max(b)))
+            .fold(0.0, f64::max)
+    }
+    #[test]
+    fn test_parallel_interp1d_matches_serial() {
+        let x: Vec<f64> = (0..400).map(|i| i as f64 * 0.01).collect();
+        let y = Array2::from_shape_fn((128, x.len()), |(r, c)| ((r * 7 + c * 3) % 11) as f64 - 5.0);
+        // A long looped sustain: 20000 output frames, including queries past both ends.
+        let xi: Vec<f64> = (0..20000).map(|i| -0.1 + i as f64 * 0.00021).collect();
+        let serial = interp1d_serial(&x, &y, &xi, Extrapolate::Clamp);
+        let parallel = interp1d(&x, &y, &xi, Extrapolate::Clamp);
+        assert_eq!(parallel, serial);
+        assert_eq!(interp1d(&x, &y, &xi[..100], Extrapolate::Clamp), interp1d_serial(&x, &y, &xi[..100], Extrapolate::Clamp));
+        let linear = Extrapolate::Linear;
+        assert_eq!(interp1d(&x, &y, &xi, linear), interp1d_serial(&x, &y, &xi, linear));
+    }
+    #[test]
+    fn test_interp1d_extrapolation() {
+        let x = [0.0, 1.0, 2.0];
+        // Row 0 rises by 1 per unit until the last segment, which rises by 3.
+        let y = Array2::from_shape_vec((2, 3), vec![0.0, 1.0, 4.0, 5.0, 5.0, 5.0]).unwrap();
+        let xi = [-1.0, 0.5, 3.0];
+        let column = |res: &Array2<f64>, c: usize| res.column(c).to_vec();
+        let clamp = interp1d(&x, &y, &xi, Extrapolate::default());
+        assert_eq!((column(&clamp, 0), column(&clamp, 1), column(&clamp, 2)), (vec![0.0, 5.0], vec![0.5, 5.0], vec![4.0, 5.0]));
+        let linear = interp1d(&x, &y, &xi, Extrapolate::Linear);
+        assert_eq!((column(&linear, 0), column(&linear, 1), column(&linear, 2)), (vec![-1.0, 5.0], vec![0.5, 5.0], vec![7.0, 5.0]));
+        let zero = interp1d(&x, &y, &xi, Extrapolate::Zero);
+        assert_eq!((column(&zero, 0), column(&zero, 1), column(&zero, 2)), (vec![0.0, 0.0], vec![0.5, 5.0], vec![0.0, 0.0]));
+        // Queries on the ends themselves are inside the range for every mode.
+        assert_eq!(interp1d(&x, &y, &[2.0], Extrapolate::Zero).column(0).to_vec(), vec![4.0, 5.0]);
+        // Duplicated end points leave a zero-width edge segment: hold instead of dividing by zero.
+        let held = interp1d(&[0.0, 0.0, 1.0, 1.0], &Array2::from_shape_vec((1, 4), vec![2.0, 3.0, 4.0, 6.0]).unwrap(), &[-1.0, 2.0], Extrapolate::Linear);
+        assert_eq!(held.row(0).to_vec(), vec![2.0, 6.0]);
+    }
+    #[test]
+    fn test_reflect_pad_1d() {
+        // numpy.pad([1, 2, 3, 4, 5], (3, 2), mode="reflect")
+        let mut s = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+        reflect_pad_1d(&mut s, 3, 2);
+        assert_eq!(s, [4.0, 3.0, 2.0, 1.0, 2.0, 3.0, 4.0, 5.0, 4.0, 3.0]);
+    }
+    #[test]
+    fn test_crossfade_loop_seam() {
+        // A rising texture: reflecting it folds back with a jump at every other seam.
+        let region = Array2::from_shape_fn((4, 40), |(b, t)| -8.0 + 0.1 * t as f64 + 0.2 * b as f64);
+        let reflected = reflect_pad_2d(region.view(), 200);
+        let crossfaded = crossfade_pad_2d(region.view(), 200, 16);
+        assert_eq!(crossfaded.dim(), reflected.dim());
+        assert_eq!(crossfaded.slice(s![.., ..24]), region.slice(s![.., ..24]));
+        assert!(max_step(&crossfaded) < max_step(&reflected) / 4.0);
+        assert_eq!(crossfade_pad_2d(region.slice(s![.., ..1]), 3, 16).ncols(), 4);
+    }
+    #[test]
+    fn test_crossfade_level_is_flat() {
+        // Head and tail at the same level must not bump (or dip) across the seam.
+        let region = Array2::from_shape_fn((3, 40), |(b, _)| -3.0 - b as f64);
+        let crossfaded = crossfade_pad_2d(region.view(), 100, 16);
+        for (b, row) in crossfaded.outer_iter().enumerate() {
+            assert!(row.iter().all(|&v| (v - (-3.0 - b as f64)).abs() < 1e-9), "row {}", b);
+        }
+    }
+    #[test]
+    fn test_pingpong_pad() {
+        let region = Array2::from_shape_fn((2, 4), |(b, t)| (t * 10 + b) as f64);
+        let padded = pingpong_pad_2d(region.view(), 9);
+        assert_eq!(padded.ncols(), 4 + 9);
+        let order: Vec<usize> = padded.row(0).iter().map(|&x| x as usize / 10).collect();
+        assert_eq!(order, [0, 1, 2, 3, 2, 1, 0, 1, 2, 3, 2, 1, 0]);
+        assert_eq!(padded.row(1)[4], 21.0);
+        assert_eq!(pingpong_pad_2d(region.slice(s![.., ..1]), 2).row(0).to_vec(), [0.0; 3]);
+    }
+}
@@ -1,13 +1,20 @@
 use std::collections::HashMap;
-use std::fs::{create_dir_all, rename, File};
+use std::fmt;
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, FileTimes};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, Instant, SystemTime};
 use ndarray::{Array0, Array1, Array2};
-use ndarray_npy::{read_npy, write_npy, NpzReader, NpzWriter};
+use ndarray_npy::{read_npy, NpzReader, NpzWriter};
 use once_cell::sync::Lazy;
 use fs2::FileExt;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
 use tracing::{info, warn};
+use crate::consts::{FEATURE_EXT, HIFI_CONFIG, HifiConfig};
+use crate::model::MODEL_SIGNATURE;
 macro_rules! defer {
     ($($stmt:stmt);* $(;)?) => {
         let _defer = {
@@ -28,69 +35,231 @@ pub struct Features {
     pub mel_origin: Array2<f64>,
     pub scale: f64,
 }
+/// Another render kept a cache locked for longer than `cache_lock_timeout_ms`.
+#[derive(Debug)]
+pub struct LockTimeout {
+    pub path: PathBuf,
+    pub timeout: Duration,
+}
+impl fmt::Display for LockTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Acquire exclusive lock timeout ({}ms): {}", self.timeout.as_millis(), self.path.display())
+    }
+}
+impl std::error::Error for LockTimeout {}
+/// Why an exclusive cache lock wasn't taken.
+#[derive(Debug)]
+enum LockError {
+    Io(io::Error),
+    Timeout(LockTimeout),
+}
 #[derive(Debug, Default)]
 struct CrossProcessLockManager {
     lock_files: Mutex<HashMap<PathBuf, Arc<File>>>,
 }
 impl CrossProcessLockManager {
-    fn get_lock_file(&self, path: &Path) -> Arc<File> {
+    fn get_lock_file(&self, path: &Path) -> io::Result<Arc<File>> {
         let lock_path = path.with_extension("lock");
         let mut lock_files = self.lock_files.lock().unwrap();
         if let Some(file) = lock_files.get(path) {
-            return file.clone();
+            return Ok(file.clone());
         }
         if let Some(parent) = lock_path.parent() {
-            create_dir_all(parent).unwrap();
+            create_dir_all(parent)?;
         }
         let file = File::options()
             .read(true)
             .write(true)
             .create(true)
-            .open(&lock_path)
-            .unwrap();
+            .open(&lock_path)?;
         let file_arc = Arc::new(file);
         lock_files.insert(path.to_path_buf(), file_arc.clone());
-        file_arc
+        Ok(file_arc)
     }
-    fn acquire_shared(&self, path: &Path) {
-        let lock_file = self.get_lock_file(path);
-        (&*lock_file).lock_shared().unwrap();
+    fn acquire_shared(&self, path: &Path) -> io::Result<()> {
+        let lock_file = self.get_lock_file(path)?;
+        (&*lock_file).lock_shared()
     }
-    fn acquire_exclusive(&self, path: &Path, timeout: Duration) {
-        let lock_file = self.get_lock_file(path);
+    fn acquire_exclusive(&self, path: &Path, timeout: Duration) -> Result<(), LockError> {
+        let lock_file = self.get_lock_file(path).map_err(LockError::Io)?;
         let start = Instant::now();
         loop {
             match (&*lock_file).try_lock_exclusive() {
-                Ok(()) => return,
+                Ok(()) => return Ok(()),
                 Err(_) => {
                     if start.elapsed() >= timeout {
-                        panic!("Acquire exclusive lock timeout ({}ms): {:?}", timeout.as_millis(), path);
+                        return Err(LockError::Timeout(LockTimeout { path: path.to_path_buf(), timeout }));
                     }
                     std::thread::sleep(Duration::from_millis(10));
                 }
             }
         }
     }
+    fn try_acquire_exclusive(&self, path: &Path) -> bool {
+        self.get_lock_file(path).is_ok_and(|file| file.try_lock_exclusive().is_ok())
+    }
+    /// Only paths whose lock was acquired have a lock file to release.
     fn release(&self, path: &Path) {
-        let lock_file = self.get_lock_file(path);
-        (&*lock_file).unlock().unwrap();
+        let lock_file = self.lock_files.lock().unwrap().get(path).cloned();
+        if let Some(lock_file) = lock_file {
+            (&*lock_file).unlock().unwrap();
+        }
+    }
+    fn forget(&self, path: &Path) {
+        self.lock_files.lock().unwrap().remove(path);
     }
 }
-#[derive(Debug, Default)]
+fn is_cache_entry(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    name.ends_with(FEATURE_EXT) || name.ends_with("_hnsep")
+}
+fn is_cache_file(path: &Path) -> bool {
+    is_cache_entry(path) || path.extension().is_some_and(|ext| ext == "tmp")
+}
+/// Where the cache `{stem}{suffix}` for `source` lives: beside the source, or, when `cache_dir` is
+/// set, under it with a hash of the absolute source path so equal stems from different folders
+/// don't collide.
+pub fn cache_file_path(source: &Path, stem: &str, suffix: &str, cache_dir: &Path) -> PathBuf {
+    if cache_dir.as_os_str().is_empty() {
+        return source.with_file_name(format!("{}{}", stem, suffix));
+    }
+    let absolute = source.canonicalize()
+        .or_else(|_| std::path::absolute(source))
+        .unwrap_or_else(|_| source.to_path_buf());
+    let digest = Sha256::digest(absolute.to_string_lossy().as_bytes());
+    let hash: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    cache_dir.join(format!("{}_{}{}", stem, hash, suffix))
+}
+/// Makes the finished temp file durable before renaming it over `path`, so a crash can't leave
+/// a named but empty cache behind.
+fn persist(file: File, tmp_path: &Path, path: &Path) -> std::io::Result<()> {
+    file.sync_all()?;
+    drop(file);
+    rename(tmp_path, path)?;
+    // Directories can't be opened as files on Windows; there the rename is left to the OS.
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let dir = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+/// Hnsep caches are compressed NPZ; caches written by older versions are a bare `.npy`.
+fn read_hnsep(path: &Path) -> anyhow::Result<Array1<f64>> {
+    let mut magic = [0u8; 6];
+    File::open(path)?.read_exact(&mut magic)?;
+    if &magic == b"\x93NUMPY" {
+        return Ok(read_npy(path)?);
+    }
+    Ok(NpzReader::new(File::open(path)?)?.by_name("hnsep")?)
+}
+/// Reads don't reliably bump atime (relatime/noatime mounts), so cache hits record it explicitly.
+fn mark_accessed(path: &Path) {
+    if let Ok(file) = File::options().write(true).open(path) {
+        let _ = file.set_times(FileTimes::new().set_accessed(SystemTime::now()));
+    }
+}
+/// Decoded features keyed by cache path, with the file's mtime when they were read or written.
+type FeatureMemory = LruCache<PathBuf, (SystemTime, Features)>;
+#[derive(Debug)]
 pub struct CacheManager {
     lock_manager: CrossProcessLockManager,
+    feature_hits: AtomicU64,
+    feature_misses: AtomicU64,
+    ttl: Option<Duration>,
+    memory: Option<Mutex<FeatureMemory>>,
+    lock_timeout: Duration,
+    /// Loads always miss and saves write nothing.
+    disabled: bool,
+}
+/// No TTL and no in-memory copies, but the configured default lock timeout: a zero timeout
+/// would fail every contended save at once.
+impl Default for CacheManager {
+    fn default() -> Self {
+        Self {
+            lock_manager: CrossProcessLockManager::default(),
+            feature_hits: AtomicU64::new(0),
+            feature_misses: AtomicU64::new(0),
+            ttl: None,
+            memory: None,
+            lock_timeout: Duration::from_millis(HifiConfig::default().cache_lock_timeout_ms),
+            disabled: false,
+        }
+    }
 }
 impl CacheManager {
+    fn new(ttl_secs: u64, memory_entries: usize, lock_timeout_ms: u64) -> Self {
+        Self {
+            lock_timeout: Duration::from_millis(lock_timeout_ms),
+            ttl: (ttl_secs > 0).then(|| Duration::from_secs(ttl_secs)),
+            memory: NonZeroUsize::new(memory_entries).map(|n| Mutex::new(LruCache::new(n))),
+            ..Self::default()
+        }
+    }
+    /// Returns the in-memory copy of `path` if the file on disk hasn't changed since it was taken.
+    fn recall(&self, path: &Path) -> Option<Features> {
+        let memory = self.memory.as_ref()?;
+        let mtime = path.metadata().and_then(|meta| meta.modified());
+        let mut memory = memory.lock().unwrap();
+        match (memory.get(path), mtime) {
+            (Some((stored, features)), Ok(mtime)) if *stored == mtime => {
+                let features = features.clone();
+                drop(memory);
+                if self.is_expired(path) {
+                    return None;
+                }
+                mark_accessed(path);
+                Some(features)
+            }
+            (Some(_), _) => {
+                memory.pop(path);
+                None
+            }
+            (None, _) => None,
+        }
+    }
+    fn remember(&self, path: &Path, features: &Features) {
+        if let Some(memory) = &self.memory
+            && let Ok(mtime) = path.metadata().and_then(|meta| meta.modified())
+        {
+            memory.lock().unwrap().put(path.to_path_buf(), (mtime, features.clone()));
+        }
+    }
+    fn is_expired(&self, path: &Path) -> bool {
+        let Some(ttl) = self.ttl else { return false };
+        let age = path.metadata().and_then(|meta| meta.modified()).ok().and_then(|time| time.elapsed().ok());
+        let expired = age.is_some_and(|age| age > ttl);
+        if expired {
+            info!("Cache older than {}s, regenerating: {}", ttl.as_secs(), path.display());
+        }
+        expired
+    }
     fn validate_file_path(&self, path: &Path) {
         if let Some(parent) = path.parent() {
             create_dir_all(parent).unwrap();
         }
     }
+    pub fn feature_cache_stats(&self) -> (u64, u64) {
+        (self.feature_hits.load(Ordering::Relaxed), self.feature_misses.load(Ordering::Relaxed))
+    }
     pub fn load_features_cache(&self, path: &Path, force_gen: bool) -> Option<Features> {
-        if force_gen || !path.exists() {
+        let features = if force_gen || self.disabled { None } else { self.recall(path) }.or_else(|| {
+            let features = self.read_features_cache(path, force_gen, &MODEL_SIGNATURE)?;
+            self.remember(path, &features);
+            Some(features)
+        });
+        let counter = if features.is_some() { &self.feature_hits } else { &self.feature_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        features
+    }
+    fn read_features_cache(&self, path: &Path, force_gen: bool, signature: &[u8]) -> Option<Features> {
+        if force_gen || self.disabled || !path.exists() || self.is_expired(path) {
+            return None;
+        }
+        if let Err(e) = self.lock_manager.acquire_shared(path) {
+            warn!("Lock cache {} failed: {}", path.display(), e);
             return None;
         }
-        self.lock_manager.acquire_shared(path);
         defer! {
             self.lock_manager.release(path);
         }
@@ -108,60 +277,471 @@ impl CacheManager {
                 return None;
             }
         };
+        let stored: Option<Array1<u8>> = reader.by_name("model_sig").ok();
+        if stored.as_ref().and_then(|sig| sig.as_slice()) != Some(signature) {
+            info!("Cache made for another model/mel config, regenerating: {}", path.display());
+            return None;
+        }
         let scale_arr: Array0<f64> = reader.by_name("scale").unwrap();
         let mel_origin = reader.by_name("mel_origin").unwrap();
+        mark_accessed(path);
         info!("Cache loaded: {}", path.display());
         Some(Features { mel_origin, scale: scale_arr.into_scalar() })
     }
     pub fn load_hnsep_cache(&self, path: &Path, force_gen: bool) -> Option<Vec<f64>> {
-        if force_gen || !path.exists() {
+        if force_gen || self.disabled || !path.exists() || self.is_expired(path) {
+            return None;
+        }
+        if let Err(e) = self.lock_manager.acquire_shared(path) {
+            warn!("Lock cache {} failed: {}", path.display(), e);
             return None;
         }
-        self.lock_manager.acquire_shared(path);
         defer! {
             self.lock_manager.release(path);
         }
-        let hnsep_arr = read_npy::<_, Array1<f64>>(path).unwrap();
-        let hnsep_vec = hnsep_arr.to_vec();
+        let hnsep_vec = match read_hnsep(path) {
+            Ok(arr) => arr.to_vec(),
+            Err(e) => {
+                warn!("Read hnsep cache {} failed: {}", path.display(), e);
+                return None;
+            }
+        };
+        mark_accessed(path);
         info!("Hnsep cache loaded: {} (length: {})", path.display(), hnsep_vec.len());
         Some(hnsep_vec)
     }
-    pub fn save_features_cache(&self, path: &Path, features: &Features) -> Option<Features> {
+    pub fn save_features_cache(&self, path: &Path, features: &Features) -> Result<Option<Features>, LockTimeout> {
+        if self.disabled {
+            return Ok(Some(features.clone()));
+        }
+        let saved = self.write_features_cache(path, features, &MODEL_SIGNATURE)?;
+        if let Some(saved) = &saved {
+            self.remember(path, saved);
+        }
+        self.enforce_configured_cap(path);
+        Ok(saved)
+    }
+    fn write_features_cache(&self, path: &Path, features: &Features, signature: &[u8]) -> Result<Option<Features>, LockTimeout> {
         self.validate_file_path(path);
-        self.lock_manager.acquire_exclusive(path, Duration::from_secs(5));
+        match self.lock_manager.acquire_exclusive(path, self.lock_timeout) {
+            Ok(()) => {}
+            Err(LockError::Timeout(e)) => return Err(e),
+            Err(LockError::Io(e)) => {
+                warn!("Lock cache {} failed, not caching: {}", path.display(), e);
+                return Ok(Some(features.clone()));
+            }
+        }
         defer! {
             self.lock_manager.release(path);
         }
-        if path.exists() {
+        if path.exists() && let Some(cached) = self.read_features_cache(path, false, signature) {
             info!("Cache exists, reuse: {}", path.display());
-            return self.load_features_cache(path, false);
+            return Ok(Some(cached));
         }
         let tmp_path = path.with_extension("tmp");
         let file = File::create(&tmp_path).unwrap();
         let mut writer = NpzWriter::new(file);
         writer.add_array("mel_origin", &features.mel_origin).unwrap();
         writer.add_array("scale", &Array0::from_elem((), features.scale)).unwrap();
-        writer.finish().unwrap();
-        rename(&tmp_path, path).unwrap();
+        writer.add_array("model_sig", &Array1::from_vec(signature.to_vec())).unwrap();
+        persist(writer.finish().unwrap(), &tmp_path, path).unwrap();
         info!("Features saved to: {}", path.display());
-        Some(features.clone())
+        Ok(Some(features.clone()))
+    }
+    pub fn save_hnsep_cache(&self, path: &Path, data: Vec<f64>) -> Result<Option<Vec<f64>>, LockTimeout> {
+        if self.disabled {
+            return Ok(Some(data));
+        }
+        let saved = self.write_hnsep_cache(path, data)?;
+        self.enforce_configured_cap(path);
+        Ok(saved)
     }
-    pub fn save_hnsep_cache(&self, path: &Path, data: Vec<f64>) -> Option<Vec<f64>> {
+    fn write_hnsep_cache(&self, path: &Path, data: Vec<f64>) -> Result<Option<Vec<f64>>, LockTimeout> {
         self.validate_file_path(path);
-        self.lock_manager.acquire_exclusive(path, Duration::from_secs(5));
+        match self.lock_manager.acquire_exclusive(path, self.lock_timeout) {
+            Ok(()) => {}
+            Err(LockError::Timeout(e)) => return Err(e),
+            Err(LockError::Io(e)) => {
+                warn!("Lock cache {} failed, not caching: {}", path.display(), e);
+                return Ok(Some(data));
+            }
+        }
         defer! {
             self.lock_manager.release(path);
         }
-        if path.exists() {
+        if path.exists() && let Some(cached) = self.load_hnsep_cache(path, false) {
             info!("Hnsep cache exists, reuse: {}", path.display());
-            return self.load_hnsep_cache(path, false);
+            return Ok(Some(cached));
         }
         let tmp_path = path.with_extension("tmp");
         let hnsep_arr = Array1::from_vec(data);
-        write_npy(&tmp_path, &hnsep_arr).unwrap();
-        rename(&tmp_path, path).unwrap();
+        let mut writer = NpzWriter::new_compressed(File::create(&tmp_path).unwrap());
+        writer.add_array("hnsep", &hnsep_arr).unwrap();
+        persist(writer.finish().unwrap(), &tmp_path, path).unwrap();
         info!("Hnsep saved to: {} (length: {})", path.display(), hnsep_arr.len());
-        Some(hnsep_arr.to_vec())
+        Ok(Some(hnsep_arr.to_vec()))
+    }
+    fn enforce_configured_cap(&self, saved: &Path) {
+        let max_bytes = HIFI_CONFIG.cache_max_bytes;
+        if max_bytes > 0 && let Some(dir) = saved.parent() {
+            self.enforce_size_cap(dir, max_bytes, saved);
+        }
+    }
+    /// Deletes the least recently accessed caches in `dir` until their total size fits in
+    /// `max_bytes`. `keep` (the cache just written) and caches locked by a reader are skipped.
+    fn enforce_size_cap(&self, dir: &Path, max_bytes: u64, keep: &Path) -> usize {
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Read dir {} failed: {}", dir.display(), e);
+                return 0;
+            }
+        };
+        let mut caches: Vec<(PathBuf, u64, SystemTime)> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                let meta = entry.metadata().ok()?;
+                let accessed = meta.accessed().or_else(|_| meta.modified()).ok()?;
+                (meta.is_file() && is_cache_entry(&path) && path != keep)
+                    .then_some((path, meta.len(), accessed))
+            })
+            .collect();
+        let keep_size = keep.metadata().map_or(0, |meta| meta.len());
+        let mut total = caches.iter().map(|(_, size, _)| size).sum::<u64>() + keep_size;
+        if total <= max_bytes {
+            return 0;
+        }
+        caches.sort_by_key(|&(_, _, accessed)| accessed);
+        let mut evicted = 0;
+        for (path, size, _) in caches {
+            if total <= max_bytes {
+                break;
+            }
+            if self.evict(&path) {
+                total -= size;
+                evicted += 1;
+            }
+        }
+        info!("Evicted {} caches under {} ({} bytes remain)", evicted, dir.display(), total);
+        evicted
+    }
+    fn evict(&self, path: &Path) -> bool {
+        // A fresh handle, so readers holding the shared handle in this process also block eviction.
+        let lock_path = path.with_extension("lock");
+        let had_lock = lock_path.exists();
+        let Ok(lock) = File::options().read(true).write(true).create(true).truncate(false).open(&lock_path) else {
+            return false;
+        };
+        if lock.try_lock_exclusive().is_err() {
+            warn!("Cache in use, not evicted: {}", path.display());
+            return false;
+        }
+        let removed = remove_file(path).is_ok();
+        let _ = FileExt::unlock(&lock);
+        if !had_lock {
+            let _ = remove_file(&lock_path);
+        }
+        removed
+    }
+    /// Removes `.lock` files left beside existing caches by crashed processes. A lock that
+    /// someone still holds (in this or another process) is left alone.
+    pub fn cleanup_stale_locks(&self, dir: &Path) -> usize {
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Read dir {} failed: {}", dir.display(), e);
+                return 0;
+            }
+        };
+        let mut removed = 0;
+        for lock_path in entries.flatten().map(|entry| entry.path()) {
+            if lock_path.extension().is_none_or(|ext| ext != "lock") {
+                continue;
+            }
+            let Some(cache_path) = [lock_path.with_extension(""), lock_path.with_extension("npz")]
+                .into_iter()
+                .find(|path| path.is_file() && is_cache_entry(path))
+            else {
+                continue;
+            };
+            // A fresh handle, so a lock held through the shared handle in this process conflicts too.
+            let Ok(lock) = File::open(&lock_path) else { continue };
+            if lock.try_lock_exclusive().is_err() {
+                continue;
+            }
+            if remove_file(&lock_path).is_ok() {
+                removed += 1;
+            }
+            let _ = FileExt::unlock(&lock);
+            self.lock_manager.forget(&cache_path);
+        }
+        if removed > 0 {
+            info!("Removed {} stale lock files under {}", removed, dir.display());
+        }
+        removed
+    }
+    pub fn clear_dir(&self, dir: &Path) -> usize {
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Read dir {} failed: {}", dir.display(), e);
+                return 0;
+            }
+        };
+        // `file_type` doesn't follow symlinks; linked entries may point outside `dir` and are
+        // left alone.
+        let (mut dirs, mut files) = (Vec::new(), Vec::new());
+        for entry in entries.flatten() {
+            match entry.file_type() {
+                Ok(ty) if ty.is_dir() => dirs.push(entry.path()),
+                Ok(ty) if !ty.is_symlink() => files.push(entry.path()),
+                _ => {}
+            }
+        }
+        let mut removed = dirs.iter().map(|sub| self.clear_dir(sub)).sum();
+        for path in files.iter().filter(|path| is_cache_file(path)) {
+            let lock_path = path.with_extension("lock");
+            let had_lock = lock_path.exists();
+            if !self.lock_manager.try_acquire_exclusive(path) {
+                warn!("Cache in use, skipped: {}", path.display());
+                continue;
+            }
+            if remove_file(path).is_ok() {
+                removed += 1;
+            }
+            if remove_file(&lock_path).is_ok() && had_lock {
+                removed += 1;
+            }
+            self.lock_manager.release(path);
+            self.lock_manager.forget(path);
+        }
+        for path in files.iter().filter(|path| path.extension().is_some_and(|ext| ext == "lock")) {
+            if !path.exists() || !self.lock_manager.try_acquire_exclusive(path) {
+                continue;
+            }
+            if remove_file(path).is_ok() {
+                removed += 1;
+            }
+            self.lock_manager.release(path);
+            self.lock_manager.forget(path);
+        }
+        info!("Cleared {} cache files under {}", removed, dir.display());
+        removed
+    }
+}
+pub static CACHE_MANAGER: Lazy<CacheManager> = Lazy::new(|| CacheManager {
+    disabled: !HIFI_CONFIG.cache_enabled,
+    ..CacheManager::new(HIFI_CONFIG.cache_ttl_secs, HIFI_CONFIG.feature_memory_entries, HIFI_CONFIG.cache_lock_timeout_ms)
+});
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_dir_all, write};
+    #[test]
+    fn test_clear_dir() {
+        let dir = std::env::temp_dir().join("hifi_cache_clear_test");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(dir.join("sub")).unwrap();
+        for name in ["a_Hb100hifi.npz", "a_hnsep", "a_hnsep.lock", "b.tmp", "sub/c_hnsep", "a.wav"] {
+            write(dir.join(name), b"").unwrap();
+        }
+        let manager = CacheManager::default();
+        assert_eq!(manager.clear_dir(&dir), 5);
+        assert!(dir.join("a.wav").exists());
+        assert!(!dir.join("sub/c_hnsep").exists());
+        assert!(!dir.join("a_hnsep.lock").exists());
+        remove_dir_all(&dir).unwrap();
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_clear_dir_skips_symlinks() {
+        let dir = std::env::temp_dir().join("hifi_cache_symlink_test");
+        let outside = std::env::temp_dir().join("hifi_cache_symlink_outside");
+        let _ = remove_dir_all(&dir);
+        let _ = remove_dir_all(&outside);
+        create_dir_all(&dir).unwrap();
+        create_dir_all(&outside).unwrap();
+        write(outside.join("d_hnsep"), b"").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("linked")).unwrap();
+        std::os::unix::fs::symlink(outside.join("d_hnsep"), dir.join("e_hnsep")).unwrap();
+        assert_eq!(CacheManager::default().clear_dir(&dir), 0);
+        assert!(outside.join("d_hnsep").exists());
+        remove_dir_all(&dir).unwrap();
+        remove_dir_all(&outside).unwrap();
+    }
+    #[test]
+    fn test_lock_file_errors_propagate() {
+        let file = std::env::temp_dir().join("hifi_cache_lock_parent");
+        write(&file, b"").unwrap();
+        // A regular file can't be the parent directory of a lock.
+        let path = file.join("a_hnsep");
+        let manager = CacheManager::default();
+        assert!(manager.lock_manager.get_lock_file(&path).is_err());
+        assert!(manager.lock_manager.acquire_shared(&path).is_err());
+        assert!(matches!(manager.lock_manager.acquire_exclusive(&path, Duration::ZERO), Err(LockError::Io(_))));
+        assert!(!manager.lock_manager.try_acquire_exclusive(&path));
+        manager.lock_manager.release(&path);
+        remove_file(&file).unwrap();
+    }
+    #[test]
+    fn test_model_signature_mismatch() {
+        let dir = std::env::temp_dir().join("hifi_cache_sig_test");
+        let _ = remove_dir_all(&dir);
+        let path = dir.join("a_Hb100hifi.npz");
+        let features = Features { mel_origin: Array2::from_elem((2, 3), 0.5), scale: 1.5 };
+        let manager = CacheManager::default();
+        manager.write_features_cache(&path, &features, b"old").unwrap().unwrap();
+        let cached = manager.read_features_cache(&path, false, b"old").unwrap();
+        assert_eq!((cached.mel_origin, cached.scale), (features.mel_origin.clone(), 1.5));
+        assert!(manager.read_features_cache(&path, false, b"new").is_none());
+        let regenerated = Features { scale: 2.5, ..features };
+        assert_eq!(manager.write_features_cache(&path, &regenerated, b"new").unwrap().unwrap().scale, 2.5);
+        assert_eq!(manager.read_features_cache(&path, false, b"new").unwrap().scale, 2.5);
+        remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_cache_file_path() {
+        let source = Path::new("/voice/A3/a.wav");
+        assert_eq!(cache_file_path(source, "a", "_hnsep", Path::new("")), Path::new("/voice/A3/a_hnsep"));
+        let cache_dir = Path::new("/tmp/hifi_cache");
+        let in_dir = cache_file_path(source, "a", "_hnsep", cache_dir);
+        assert_eq!(in_dir.parent().unwrap(), cache_dir);
+        let name = in_dir.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("a_") && name.ends_with("_hnsep") && name.len() == "a__hnsep".len() + 16);
+        assert_eq!(in_dir, cache_file_path(source, "a", "_hnsep", cache_dir));
+        assert_ne!(in_dir, cache_file_path(Path::new("/voice/C4/a.wav"), "a", "_hnsep", cache_dir));
+        assert_eq!(in_dir.with_extension("lock").parent().unwrap(), cache_dir);
+    }
+    #[test]
+    fn test_hnsep_compression() {
+        let dir = std::env::temp_dir().join("hifi_cache_hnsep_test");
+        let _ = remove_dir_all(&dir);
+        let data: Vec<f64> = (0..44100).map(|i| (i as f64 * 0.01).sin()).collect();
+        let manager = CacheManager::default();
+        let path = dir.join("a_hnsep");
+        manager.write_hnsep_cache(&path, data.clone()).unwrap().unwrap();
+        assert!(path.metadata().unwrap().len() < data.len() as u64 * 8);
+        assert_eq!(manager.load_hnsep_cache(&path, false).unwrap(), data);
+        let legacy = dir.join("b_hnsep");
+        ndarray_npy::write_npy(&legacy, &Array1::from_vec(data.clone())).unwrap();
+        assert_eq!(manager.load_hnsep_cache(&legacy, false).unwrap(), data);
+        write(&legacy, b"garbage").unwrap();
+        assert!(manager.load_hnsep_cache(&legacy, false).is_none());
+        remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_feature_memory() {
+        let dir = std::env::temp_dir().join("hifi_cache_memory_test");
+        let _ = remove_dir_all(&dir);
+        let path = dir.join("a_g0hifi.npz");
+        let features = Features { mel_origin: Array2::from_elem((2, 3), 0.5), scale: 1.5 };
+        let manager = CacheManager::new(0, 2, 5000);
+        manager.save_features_cache(&path, &features).unwrap().unwrap();
+        let mtime = path.metadata().unwrap().modified().unwrap();
+        write(&path, b"garbage").unwrap();
+        File::options().write(true).open(&path).unwrap()
+            .set_times(FileTimes::new().set_modified(mtime)).unwrap();
+        assert_eq!(manager.load_features_cache(&path, false).unwrap().scale, 1.5);
+        assert!(manager.load_features_cache(&path, true).is_none());
+        File::options().write(true).open(&path).unwrap()
+            .set_times(FileTimes::new().set_modified(mtime + Duration::from_secs(1))).unwrap();
+        assert!(manager.load_features_cache(&path, false).is_none());
+        assert!(manager.memory.as_ref().unwrap().lock().unwrap().is_empty());
+        remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_lock_timeout() {
+        assert_eq!(CacheManager::default().lock_timeout, Duration::from_millis(5000));
+        let dir = std::env::temp_dir().join("hifi_cache_lock_timeout_test");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("a_hnsep");
+        let holder = File::create(path.with_extension("lock")).unwrap();
+        holder.lock_exclusive().unwrap();
+        let manager = CacheManager::new(0, 0, 50);
+        let start = Instant::now();
+        let err = manager.save_hnsep_cache(&path, vec![1.0]).unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!((err.path, err.timeout), (path.clone(), Duration::from_millis(50)));
+        assert!(!path.exists());
+        FileExt::unlock(&holder).unwrap();
+        assert_eq!(manager.save_hnsep_cache(&path, vec![1.0]).unwrap().unwrap(), [1.0]);
+        remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_cache_disabled() {
+        let dir = std::env::temp_dir().join("hifi_cache_disabled_test");
+        let _ = remove_dir_all(&dir);
+        let manager = CacheManager { disabled: true, ..CacheManager::new(0, 2, 5000) };
+        let features = Features { mel_origin: Array2::from_elem((2, 3), 0.5), scale: 1.5 };
+        let path = dir.join("a_g0hifi.npz");
+        assert_eq!(manager.save_features_cache(&path, &features).unwrap().unwrap().scale, 1.5);
+        assert_eq!(manager.save_hnsep_cache(&dir.join("a_hnsep"), vec![1.0]).unwrap().unwrap(), [1.0]);
+        assert!(manager.load_features_cache(&path, false).is_none());
+        assert!(!dir.exists());
+    }
+    #[test]
+    fn test_ttl_expiry() {
+        let dir = std::env::temp_dir().join("hifi_cache_ttl_test");
+        let _ = remove_dir_all(&dir);
+        let path = dir.join("a_hnsep");
+        let manager = CacheManager::new(60, 0, 5000);
+        manager.write_hnsep_cache(&path, vec![1.0, 2.0]).unwrap().unwrap();
+        assert_eq!(manager.load_hnsep_cache(&path, false).unwrap(), [1.0, 2.0]);
+        let past = SystemTime::now() - Duration::from_secs(120);
+        File::options().write(true).open(&path).unwrap()
+            .set_times(FileTimes::new().set_modified(past)).unwrap();
+        assert!(CacheManager::new(0, 0, 5000).load_hnsep_cache(&path, false).is_some());
+        assert!(manager.load_hnsep_cache(&path, false).is_none());
+        assert_eq!(manager.write_hnsep_cache(&path, vec![3.0]).unwrap().unwrap(), [3.0]);
+        assert_eq!(manager.load_hnsep_cache(&path, false).unwrap(), [3.0]);
+        remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_cleanup_stale_locks() {
+        let dir = std::env::temp_dir().join("hifi_cache_stale_lock_test");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        for name in ["a_hnsep", "a_hnsep.lock", "b_g0hifi.npz", "b_g0hifi.lock", "c_hnsep", "c_hnsep.lock", "orphan.lock"] {
+            write(dir.join(name), b"").unwrap();
+        }
+        let manager = CacheManager::default();
+        manager.lock_manager.acquire_shared(&dir.join("c_hnsep")).unwrap();
+        assert_eq!(manager.cleanup_stale_locks(&dir), 2);
+        assert!(!dir.join("a_hnsep.lock").exists());
+        assert!(!dir.join("b_g0hifi.lock").exists());
+        assert!(dir.join("c_hnsep.lock").exists());
+        assert!(dir.join("orphan.lock").exists());
+        assert!(dir.join("a_hnsep").exists() && dir.join("b_g0hifi.npz").exists());
+        manager.lock_manager.release(&dir.join("c_hnsep"));
+        remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_size_cap_eviction() {
+        let dir = std::env::temp_dir().join("hifi_cache_evict_test");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let now = SystemTime::now();
+        for (i, name) in ["a_Hb100hifi.npz", "b_hnsep", "c_Hb100hifi.npz", "d_hnsep"].iter().enumerate() {
+            write(dir.join(name), [0u8; 10]).unwrap();
+            let time = now - Duration::from_secs(400 - 100 * i as u64);
+            File::options().write(true).open(dir.join(name)).unwrap()
+                .set_times(FileTimes::new().set_accessed(time).set_modified(time)).unwrap();
+        }
+        write(dir.join("a.wav"), [0u8; 100]).unwrap();
+        let reader = File::create(dir.join("b_hnsep.lock")).unwrap();
+        reader.lock_shared().unwrap();
+        let manager = CacheManager::default();
+        assert_eq!(manager.enforce_size_cap(&dir, 100, &dir.join("d_hnsep")), 0);
+        assert_eq!(manager.enforce_size_cap(&dir, 15, &dir.join("d_hnsep")), 2);
+        assert!(!dir.join("a_Hb100hifi.npz").exists());
+        assert!(dir.join("b_hnsep").exists());
+        assert!(!dir.join("c_Hb100hifi.npz").exists());
+        assert!(dir.join("d_hnsep").exists());
+        assert!(dir.join("a.wav").exists());
+        assert!(!dir.join("a_Hb100hifi.lock").exists());
+        FileExt::unlock(&reader).unwrap();
+        remove_dir_all(&dir).unwrap();
     }
 }
-pub static CACHE_MANAGER: Lazy<CacheManager> = Lazy::new(CacheManager::default);
\ No newline at end of file
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use ndarray::{Array1, Array2, Axis};
+use crate::{consts::SAMPLE_RATE, utils::lerp};
+const DB_TO_LOG: f64 = std::f64::consts::LN_10 / 20.0;
+/// Formant flags, applied to the log-mel right before vocoding so f0 and timing are untouched:
+/// - `fe`: envelope shift in cents, -1200~1200 (positive raises the formants)
+/// - `fo`: envelope offset in Hz, -2000~2000, added after the `fe` scaling
+/// - `fp`: centre of a formant emphasis band in Hz (0 or absent disables it)
+/// - `fv`: gain of that band in dB, -24~24
+/// - `fl`: width of that band in mel bins, 1~64, default 8
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Formant {
+    pub shift_cents: f64,
+    pub offset_hz: f64,
+    pub peak_hz: f64,
+    pub gain_db: f64,
+    pub width_bins: f64,
+}
+impl Formant {
+    pub fn from_flags(flags: &HashMap<String, Option<f64>>) -> Self {
+        let get = |key: &str| flags.get(key).and_then(|o| o.as_ref()).copied();
+        Self {
+            shift_cents: get("fe").unwrap_or(0.).clamp(-1200., 1200.),
+            offset_hz: get("fo").unwrap_or(0.).clamp(-2000., 2000.),
+            peak_hz: get("fp").unwrap_or(0.).clamp(0., SAMPLE_RATE as f64 / 2.),
+            gain_db: get("fv").unwrap_or(0.).clamp(-24., 24.),
+            width_bins: get("fl").unwrap_or(8.).clamp(1., 64.),
+        }
+    }
+    pub fn is_neutral(&self) -> bool {
+        self.shift_cents == 0. && self.offset_hz == 0. && (self.peak_hz == 0. || self.gain_db == 0.)
+    }
+    /// `mel` is `[bins, frames]` log magnitude; `centers` holds each bin's centre frequency.
+    pub fn apply(&self, mel: &mut Array2<f64>, centers: &[f64]) {
+        if self.shift_cents != 0. || self.offset_hz != 0. {
+            self.shift(mel, centers);
+        }
+        if self.peak_hz > 0. && self.gain_db != 0. {
+            self.emphasize(mel, centers);
+        }
+    }
+    fn shift(&self, mel: &mut Array2<f64>, centers: &[f64]) {
+        warp_envelope(mel, centers, (self.shift_cents / 1200.).exp2(), self.offset_hz);
+    }
+    fn emphasize(&self, mel: &mut Array2<f64>, centers: &[f64]) {
+        let (bin, t) = fractional_bin(centers, self.peak_hz);
+        let center = bin as f64 + t;
+        let gain: Array1<f64> = (0..centers.len())
+            .map(|i| self.gain_db * DB_TO_LOG * (-0.5 * ((i as f64 - center) / self.width_bins).powi(2)).exp())
+            .collect();
+        for mut frame in mel.axis_iter_mut(Axis(1)) {
+            frame += &gain;
+        }
+    }
+}
+/// Each bin takes the envelope value found at `(f - offset) / ratio`, which moves the
+/// envelope's peaks to `f * ratio + offset`.
+pub fn warp_envelope(mel: &mut Array2<f64>, centers: &[f64], ratio: f64, offset_hz: f64) {
+    let sources: Vec<(usize, f64)> = centers.iter()
+        .map(|&f| fractional_bin(centers, (f - offset_hz) / ratio))
+        .collect();
+    for mut frame in mel.axis_iter_mut(Axis(1)) {
+        let original = frame.to_owned();
+        frame.iter_mut().zip(&sources).for_each(|(x, &(i, t))| {
+            *x = lerp(original[i], original[(i + 1).min(original.len() - 1)], t);
+        });
+    }
+}
+/// Position of `freq` between bin centres, clamped to the first and last bin.
+fn fractional_bin(centers: &[f64], freq: f64) -> (usize, f64) {
+    let last = centers.len() - 1;
+    match centers.partition_point(|&c| c <= freq) {
+        0 => (0, 0.),
+        i if i > last => (last, 0.),
+        i => (i - 1, (freq - centers[i - 1]) / (centers[i] - centers[i - 1])),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn centers() -> Vec<f64> {
+        (0..128).map(|i| 40. + i as f64 * 120.).collect()
+    }
+    fn peak_bin(mel: &Array2<f64>) -> usize {
+        let frame = mel.column(0);
+        (0..frame.len()).max_by(|&a, &b| frame[a].total_cmp(&frame[b])).unwrap()
+    }
+    fn formant_at(centers: &[f64], hz: f64) -> Array2<f64> {
+        Array2::from_shape_fn((centers.len(), 3), |(b, _)| -((centers[b] - hz) / 300.).powi(2))
+    }
+    #[test]
+    fn test_shift_moves_formant_peak() {
+        let centers = centers();
+        let mut mel = formant_at(&centers, 1000.);
+        let flags = HashMap::from([("fe".to_string(), Some(1200.))]);
+        let formant = Formant::from_flags(&flags);
+        formant.apply(&mut mel, &centers);
+        assert!((centers[peak_bin(&mel)] - 2000.).abs() <= 120.);
+        let mut mel = formant_at(&centers, 2000.);
+        Formant { shift_cents: -1200., ..formant }.apply(&mut mel, &centers);
+        assert!((centers[peak_bin(&mel)] - 1000.).abs() <= 120.);
+        let mut mel = formant_at(&centers, 1000.);
+        Formant { shift_cents: 0., offset_hz: 500., ..formant }.apply(&mut mel, &centers);
+        assert!((centers[peak_bin(&mel)] - 1500.).abs() <= 120.);
+    }
+    #[test]
+    fn test_gender_warp_peaks() {
+        use crate::{consts::SAMPLE_RATE, utils::{dynamic_range_compression, mel::{MEL_CENTERS, mel}}};
+        let sr = SAMPLE_RATE as f64;
+        // 100 Hz pulse train shaped by a single formant at 1500 Hz.
+        let wave: Vec<f64> = (0..8192).map(|n| {
+            (1..100).map(|h| {
+                let f = 100. * h as f64;
+                (-((f - 1500.) / 250.).powi(2)).exp() * (std::f64::consts::TAU * f * n as f64 / sr).sin()
+            }).sum::<f64>() * 0.01
+        }).collect();
+        let peak_hz = |spec: Array2<f64>| {
+            let mean = spec.mean_axis(Axis(1)).unwrap();
+            MEL_CENTERS[(0..mean.len()).max_by(|&a, &b| mean[a].total_cmp(&mean[b])).unwrap()]
+        };
+        for gender in [-300., 0., 300.] {
+            let expected = 1500. * (gender / 1200f64).exp2();
+            let mut warped = mel(&mut wave.clone(), 0., 1.);
+            dynamic_range_compression(&mut warped);
+            warp_envelope(&mut warped, &MEL_CENTERS, (gender / 1200.).exp2(), 0.);
+            let legacy = mel(&mut wave.clone(), gender / 100., 1.);
+            for (mode, found) in [("warp", peak_hz(warped)), ("fft", peak_hz(legacy))] {
+                assert!((found / expected).log2().abs() < 1. / 12., "{} g={}: peak {} Hz, expected {} Hz", mode, gender, found, expected);
+            }
+        }
+    }
+    #[test]
+    fn test_emphasis_band() {
+        let centers = centers();
+        let mut mel = Array2::zeros((centers.len(), 2));
+        let flags = HashMap::from([("fp".to_string(), Some(3000.)), ("fv".to_string(), Some(12.))]);
+        let formant = Formant::from_flags(&flags);
+        assert!(!formant.is_neutral());
+        assert!(Formant::from_flags(&HashMap::new()).is_neutral());
+        formant.apply(&mut mel, &centers);
+        let peak = peak_bin(&mel);
+        assert!((centers[peak] - 3000.).abs() <= 120.);
+        assert!((mel[(peak, 1)] - 12. * DB_TO_LOG).abs() < 1e-2);
+        assert!(mel[(127, 0)].abs() < 1e-6);
+    }
+}
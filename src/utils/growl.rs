@@ -1,9 +1,16 @@
 use biquad::{Biquad, Coefficients, DirectForm1, ToHertz};
-use crate::utils::lerp;
+use once_cell::sync::OnceCell;
+use oxifft::threading::{ThreadPool, get_default_pool};
+use crate::{consts::LfoShape, utils::lerp};
 const VIBRATO_FACTOR: f64 = 1.0 / 12.0;
 const HP_CUTOFF_HZ: f64 = 20.0;
 const Q_HIGHPASS: f64 = 0.7071067811865476;
-fn forward_backward_filter<F: Biquad<f64>>(
+/// Renders at least this long run the band split alongside the modulation curve and resample
+/// the band in chunks across the thread pool.
+const PARALLEL_MIN_SAMPLES: usize = 1 << 15;
+const INTERP_CHUNK: usize = 1 << 13;
+/// Zero-phase filtering: each repeat runs the filter forward and then backward over `signal`.
+pub fn forward_backward_filter<F: Biquad<f64>>(
     signal: &mut [f64],
     filter: &mut F,
     repeats: usize,
@@ -43,17 +50,20 @@ fn highpass(
         .collect::<Vec<f64>>();
     (high, low)
 }
-fn square_lfo(num: usize, sr: f64, freq: f64) -> Vec<f64> {
-    let mut lfo = Vec::with_capacity(num);
-    let samples = (sr / freq) as usize;
-    for n in 0..num {
-        if (n % samples) < (samples / 2) {
-            lfo.push(1.0);
-        } else {
-            lfo.push(-1.0);
-        }
-    }
-    lfo
+/// `num` samples of a ±1 LFO whose period is `sr / freq` rounded down to whole samples.
+fn generate_lfo(shape: LfoShape, num: usize, sr: f64, freq: f64) -> Vec<f64> {
+    let samples = ((sr / freq) as usize).max(1);
+    (0..num)
+        .map(|n| {
+            let pos = n % samples;
+            let phase = pos as f64 / samples as f64;
+            match shape {
+                LfoShape::Square => if pos < samples / 2 { 1.0 } else { -1.0 },
+                LfoShape::Sine => (std::f64::consts::TAU * phase).sin(),
+                LfoShape::Triangle => 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs(),
+            }
+        })
+        .collect()
 }
 fn linear_interp(idx: &[f64], x: &[f64]) -> Vec<f64> {
     let mut output = Vec::with_capacity(idx.len());
@@ -68,18 +78,30 @@ fn linear_interp(idx: &[f64], x: &[f64]) -> Vec<f64> {
     }
     output
 }
+/// [`linear_interp`] in `INTERP_CHUNK`-sized tasks; every sample is computed the same way, so
+/// the result is identical.
+fn linear_interp_parallel(idx: &[f64], x: &[f64]) -> Vec<f64> {
+    let n_chunks = idx.len().div_ceil(INTERP_CHUNK);
+    let chunks: Vec<OnceCell<Vec<f64>>> = (0..n_chunks).map(|_| OnceCell::new()).collect();
+    get_default_pool().parallel_for(n_chunks, |c| {
+        let end = ((c + 1) * INTERP_CHUNK).min(idx.len());
+        let _ = chunks[c].set(linear_interp(&idx[c * INTERP_CHUNK..end], x));
+    });
+    chunks.into_iter().flat_map(|c| c.into_inner().unwrap()).collect()
+}
 #[inline]
 fn rms(data: &[f64]) -> f64 {
     let sum_sq = data.iter().fold(0.0, |acc, &x| acc + x * x);
     (sum_sq * (1.0 / data.len() as f64)).sqrt()
 }
-fn apply_pitch_modulation(
-    band: &[f64],
+/// Read positions into the high band that bend its pitch along `lfo`; they only depend on the
+/// LFO, so they can be computed while the band is being split off.
+fn modulation_positions(
     sr: f64,
     lfo: &[f64],
     strength: f64,
 ) -> Vec<f64> {
-    let band_len = band.len();
+    let band_len = lfo.len();
     let mut buf = lfo.iter()
         .map(|&l| 2.0f64.powf(l * (strength * VIBRATO_FACTOR)))
         .collect::<Vec<f64>>(); 
@@ -94,31 +116,113 @@ fn apply_pitch_modulation(
     for (i, val) in buf.iter_mut().enumerate() {
         *val = (i as f64 + *val).clamp(0.0, (band_len - 1) as f64);
     }
-    let mut modulated = linear_interp(&buf, band);
-    let gain = rms(band) / rms(&modulated);
-    modulated.iter_mut().for_each(|m| *m *= gain);
-    modulated
+    buf
 }
 pub fn growl(
     audio: &mut Vec<f64>,
     sr: f64,
     freq: f64,
     strength: f64,
+    shape: LfoShape,
+) {
+    let parallel = audio.len() >= PARALLEL_MIN_SAMPLES;
+    growl_impl(audio, sr, freq, strength, shape, parallel);
+}
+fn growl_impl(
+    audio: &mut Vec<f64>,
+    sr: f64,
+    freq: f64,
+    strength: f64,
+    shape: LfoShape,
+    parallel: bool,
 ) {
     let orig_len = audio.len();
     if orig_len == 0 {
         return;
     }
     let orig_audio = std::mem::take(audio);
-    let (high, mut complement) = highpass(&orig_audio, sr, 400.0);
-    let mod_band = apply_pitch_modulation(
-        &high,
-        sr,
-        &square_lfo(orig_len, sr, freq),
-        strength,
-    );
+    let split = || highpass(&orig_audio, sr, 400.0);
+    let positions = || modulation_positions(sr, &generate_lfo(shape, orig_len, sr, freq), strength);
+    let ((high, mut complement), positions) = if parallel {
+        get_default_pool().join(split, positions)
+    } else {
+        (split(), positions())
+    };
+    let mut mod_band = if parallel {
+        linear_interp_parallel(&positions, &high)
+    } else {
+        linear_interp(&positions, &high)
+    };
+    let gain = rms(&high) / rms(&mod_band);
+    mod_band.iter_mut().for_each(|m| *m *= gain);
     complement.iter_mut()
         .zip(mod_band.iter())
         .for_each(|(c, m)| *c += m);
     *audio = complement;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_lfo_shapes() {
+        // 100 Hz at 4 kHz: a 40-sample period.
+        let expected = [
+            (LfoShape::Square, [1.0, 1.0, -1.0, -1.0]),
+            (LfoShape::Sine, [0.0, 1.0, 0.0, -1.0]),
+            (LfoShape::Triangle, [0.0, 1.0, 0.0, -1.0]),
+        ];
+        for (shape, quarters) in expected {
+            let lfo = generate_lfo(shape, 200, 4000.0, 100.0);
+            for (q, &value) in quarters.iter().enumerate() {
+                assert!((lfo[q * 10] - value).abs() < 1e-12, "{:?} at quarter {}", shape, q);
+            }
+            assert!((40..200).all(|n| (lfo[n] - lfo[n - 40]).abs() < 1e-12));
+            assert!(lfo.iter().all(|v| v.abs() <= 1.0));
+        }
+        let triangle = generate_lfo(LfoShape::Triangle, 40, 4000.0, 100.0);
+        assert!((triangle[5] - 0.5).abs() < 1e-12);
+    }
+    #[test]
+    fn test_parallel_growl_matches_serial() {
+        let sr = 44100.0;
+        let source: Vec<f64> = (0..3 * INTERP_CHUNK + 123)
+            .map(|n| (0.031 * n as f64).sin() + 0.3 * (0.47 * n as f64).sin())
+            .collect();
+        let (mut serial, mut parallel) = (source.clone(), source);
+        growl_impl(&mut serial, sr, 80.0, 0.7, LfoShape::Square, false);
+        growl_impl(&mut parallel, sr, 80.0, 0.7, LfoShape::Square, true);
+        assert_eq!(serial, parallel);
+    }
+    #[test]
+    fn test_growl_rate() {
+        let (sr, carrier) = (44100.0, 1000.0);
+        let w = std::f64::consts::TAU * carrier / sr;
+        for rate in [60.0, 150.0] {
+            let mut audio: Vec<f64> = (0..22050).map(|n| (w * n as f64).sin()).collect();
+            growl(&mut audio, sr, rate, 1.0, LfoShape::Square);
+            // Demodulate the carrier's phase deviation, averaging I/Q over one carrier cycle.
+            let cycle = (sr / carrier) as usize;
+            let (i, q): (Vec<f64>, Vec<f64>) = audio.iter().enumerate()
+                .map(|(n, &x)| (x * (w * n as f64).sin(), x * (w * n as f64).cos()))
+                .unzip();
+            let deviation: Vec<f64> = (2000..audio.len() - 2000)
+                .map(|n| f64::atan2(-q[n..n + cycle].iter().sum::<f64>(), i[n..n + cycle].iter().sum::<f64>()))
+                .collect();
+            let mean = deviation.iter().sum::<f64>() / deviation.len() as f64;
+            let max_lag = (sr / 25.0) as usize;
+            let span = deviation.len() - max_lag;
+            let corr: Vec<f64> = (0..=max_lag)
+                .map(|lag| (0..span).map(|n| (deviation[n] - mean) * (deviation[n + lag] - mean)).sum())
+                .collect();
+            let min_lag = (sr / 220.0) as usize;
+            let best = corr[min_lag..].iter().cloned().fold(f64::MIN, f64::max);
+            // First lobe near the global maximum, climbed to its local peak.
+            let mut period = (min_lag..=max_lag).find(|&lag| corr[lag] >= 0.9 * best).unwrap();
+            while period < max_lag && corr[period + 1] > corr[period] {
+                period += 1;
+            }
+            let expected = sr / rate;
+            assert!((period as f64 / expected - 1.0).abs() < 0.05, "rate {}: period {} vs {}", rate, period, expected);
+        }
+    }
+}
@@ -1,10 +1,14 @@
+/// Akima spline over `y` at `x = 0, 1, 2, ...`. The knots are implicit, so they are always
+/// strictly increasing; query positions may come in any order and are clamped to the knots.
 pub struct Akima {
-    len: usize,
     coeffs: Vec<[f64; 4]>,
 }
 impl Akima {
     pub fn new(y: &[f64]) -> Self {
         let n = y.len();
+        if n < 2 {
+            return Self { coeffs: constant(y) };
+        }
         let mut m = Vec::with_capacity(n + 3);
         m.push(0.0);
         m.push(0.0);
@@ -32,20 +36,214 @@ impl Akima {
                 (s[i] + s[i+1] - 2.0 * m[2 + i]),
             ]);
         }
-        Self { len: n, coeffs }
+        Self { coeffs }
+    }
+    #[inline(always)]
+    pub fn sample(&self, t: f64) -> f64 {
+        sample_segment(&self.coeffs, t)
     }
+    /// [`Self::sample`] at each position, so repeated or out-of-order positions are fine.
     #[inline(always)]
     pub fn sample_with_slice(&self, x: &[f64]) -> Vec<f64> {
-        let mut res = Vec::with_capacity(x.len());
-        let mut i = 0;
-        for &t in x {
-            while i < self.len - 1 && ((i + 1) as f64) < t {
-                i += 1;
+        x.iter().map(|&t| self.sample(t)).collect()
+    }
+}
+/// Natural cubic spline over `y` at `x = 0, 1, 2, ...`: C2-continuous, with zero curvature at
+/// both ends. Smoother than [`Akima`] on slides, but it can overshoot sharp steps.
+pub struct CubicSpline {
+    coeffs: Vec<[f64; 4]>,
+}
+impl CubicSpline {
+    pub fn new(y: &[f64]) -> Self {
+        let n = y.len();
+        if n < 2 {
+            return Self { coeffs: constant(y) };
+        }
+        // Second derivatives from M[i-1] + 4 M[i] + M[i+1] = 6 (y[i+1] - 2 y[i] + y[i-1]),
+        // solved with the Thomas algorithm; M[0] = M[n-1] = 0.
+        let mut m = vec![0.0; n];
+        if n > 2 {
+            let (mut diag, mut rhs) = (vec![4.0; n - 2], vec![0.0; n - 2]);
+            for i in 1..n - 1 {
+                rhs[i - 1] = 6.0 * (y[i + 1] - 2.0 * y[i] + y[i - 1]);
+            }
+            for i in 1..n - 2 {
+                let w = 1.0 / diag[i - 1];
+                diag[i] -= w;
+                rhs[i] -= w * rhs[i - 1];
+            }
+            m[n - 2] = rhs[n - 3] / diag[n - 3];
+            for i in (1..n - 2).rev() {
+                m[i] = (rhs[i - 1] - m[i + 1]) / diag[i - 1];
             }
-            let [c0, c1, c2, c3] = self.coeffs[i];
-            let r = t - i as f64;
-            res.push(c0 + r * (c1 + r * (c2 + r * c3)));
         }
-        res
+        let coeffs = (0..n - 1)
+            .map(|i| [
+                y[i],
+                y[i + 1] - y[i] - (2.0 * m[i] + m[i + 1]) / 6.0,
+                m[i] / 2.0,
+                (m[i + 1] - m[i]) / 6.0,
+            ])
+            .collect();
+        Self { coeffs }
     }
-}
\ No newline at end of file
+    #[inline(always)]
+    pub fn sample(&self, t: f64) -> f64 {
+        sample_segment(&self.coeffs, t)
+    }
+    /// [`Self::sample`] at each position, so repeated or out-of-order positions are fine.
+    #[inline(always)]
+    pub fn sample_with_slice(&self, x: &[f64]) -> Vec<f64> {
+        x.iter().map(|&t| self.sample(t)).collect()
+    }
+}
+/// Monotone piecewise cubic Hermite (PCHIP) over `y` at `x = 0, 1, 2, ...`, with
+/// Fritsch–Carlson slopes: between two points it never leaves their range.
+pub struct Pchip {
+    coeffs: Vec<[f64; 4]>,
+}
+impl Pchip {
+    pub fn new(y: &[f64]) -> Self {
+        let n = y.len();
+        if n < 2 {
+            return Self { coeffs: constant(y) };
+        }
+        let delta: Vec<f64> = y.windows(2).map(|w| w[1] - w[0]).collect();
+        let mut d = vec![0.0; n];
+        if n == 2 {
+            d = vec![delta[0]; 2];
+        } else {
+            for k in 1..n - 1 {
+                let (a, b) = (delta[k - 1], delta[k]);
+                d[k] = if a * b <= 0.0 { 0.0 } else { 2.0 / (1.0 / a + 1.0 / b) };
+            }
+            d[0] = end_slope(delta[0], delta[1]);
+            d[n - 1] = end_slope(delta[n - 2], delta[n - 3]);
+        }
+        let coeffs = (0..n - 1)
+            .map(|i| [
+                y[i],
+                d[i],
+                3.0 * delta[i] - 2.0 * d[i] - d[i + 1],
+                d[i] + d[i + 1] - 2.0 * delta[i],
+            ])
+            .collect();
+        Self { coeffs }
+    }
+    #[inline(always)]
+    pub fn sample(&self, t: f64) -> f64 {
+        sample_segment(&self.coeffs, t)
+    }
+    /// [`Self::sample`] at each position, so repeated or out-of-order positions are fine.
+    #[inline(always)]
+    pub fn sample_with_slice(&self, x: &[f64]) -> Vec<f64> {
+        x.iter().map(|&t| self.sample(t)).collect()
+    }
+}
+/// One-sided three-point end slope, limited so the end segment stays monotone.
+fn end_slope(near: f64, far: f64) -> f64 {
+    let d = (3.0 * near - far) / 2.0;
+    if d.signum() != near.signum() || near == 0.0 {
+        0.0
+    } else if near.signum() != far.signum() && d.abs() > 3.0 * near.abs() {
+        3.0 * near
+    } else {
+        d
+    }
+}
+/// A single point (or none) holds its value everywhere.
+fn constant(y: &[f64]) -> Vec<[f64; 4]> {
+    vec![[y.first().copied().unwrap_or(0.0), 0.0, 0.0, 0.0]]
+}
+/// Evaluates the per-segment cubic `c0 + c1 r + c2 r^2 + c3 r^3` at `t`. With unit-spaced
+/// knots the segment is found directly from `t`, with no search and no state carried between
+/// calls; positions outside the knots clamp.
+fn sample_segment(coeffs: &[[f64; 4]], t: f64) -> f64 {
+    let t = t.clamp(0.0, coeffs.len() as f64);
+    let i = (t.ceil() as usize).saturating_sub(1).min(coeffs.len() - 1);
+    let [c0, c1, c2, c3] = coeffs[i];
+    let r = t - i as f64;
+    c0 + r * (c1 + r * (c2 + r * c3))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const FIXTURE: [f64; 6] = [1.0, 2.0, 4.0, 2.0, 3.0, 2.0];
+    fn knots() -> Vec<f64> {
+        (0..FIXTURE.len()).map(|i| i as f64).collect()
+    }
+    #[test]
+    fn test_interpolators_pass_through_data() {
+        for values in [Akima::new(&FIXTURE).sample_with_slice(&knots()), CubicSpline::new(&FIXTURE).sample_with_slice(&knots())] {
+            for (v, y) in values.iter().zip(FIXTURE) {
+                assert!((v - y).abs() < 1e-12, "{:?}", values);
+            }
+        }
+    }
+    #[test]
+    fn test_unordered_and_repeated_positions() {
+        let akima = Akima::new(&FIXTURE);
+        let sorted = akima.sample_with_slice(&[0.5, 1.5, 2.0, 3.25, 4.75]);
+        let shuffled = akima.sample_with_slice(&[3.25, 0.5, 4.75, 2.0, 1.5]);
+        assert_eq!(shuffled, [sorted[3], sorted[0], sorted[4], sorted[2], sorted[1]]);
+        let repeated = akima.sample_with_slice(&[2.0, 2.0, 2.0 - 1e-12, 2.0 + 1e-12]);
+        assert!(repeated.iter().all(|v| (v - 4.0).abs() < 1e-9));
+        let clamped = akima.sample_with_slice(&[-3.0, 9.0]);
+        assert_eq!(clamped, [1.0, 2.0]);
+        assert_eq!(Akima::new(&[5.0]).sample_with_slice(&[0.0, 3.0]), [5.0, 5.0]);
+        assert_eq!(Pchip::new(&[]).sample_with_slice(&[1.0]), [0.0]);
+        assert_eq!(CubicSpline::new(&[2.0, 4.0]).sample_with_slice(&[0.5]), [3.0]);
+    }
+    #[test]
+    fn test_single_point_sample() {
+        let akima = Akima::new(&FIXTURE);
+        // A fixed shuffle of 0..=50 / 10, plus points past both ends.
+        let shuffled: Vec<f64> = (0..51).map(|i| ((i * 37) % 51) as f64 / 10.0).chain([-1.0, 7.5]).collect();
+        let batch = akima.sample_with_slice(&shuffled);
+        for (&t, &v) in shuffled.iter().zip(&batch) {
+            assert_eq!(akima.sample(t), v);
+            assert_eq!(akima.sample_with_slice(&[t]), [v]);
+        }
+        let mut sorted = shuffled.clone();
+        sorted.sort_by(f64::total_cmp);
+        let mut by_position: Vec<(f64, f64)> = shuffled.iter().copied().zip(batch).collect();
+        by_position.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert_eq!(akima.sample_with_slice(&sorted), by_position.iter().map(|p| p.1).collect::<Vec<_>>());
+        assert_eq!(Pchip::new(&FIXTURE).sample(2.0), 4.0);
+        assert_eq!(CubicSpline::new(&[2.0, 4.0]).sample(0.5), 3.0);
+    }
+    #[test]
+    fn test_pchip_is_monotone() {
+        let rising = [0.0, 0.1, 0.2, 3.0, 3.1, 8.0, 8.0, 8.2];
+        let pchip = Pchip::new(&rising);
+        let x: Vec<f64> = (0..=700).map(|i| i as f64 / 100.0).collect();
+        let values = pchip.sample_with_slice(&x);
+        assert!(values.windows(2).all(|w| w[1] >= w[0] - 1e-12));
+        let at_knots: Vec<f64> = (0..rising.len()).map(|i| i as f64).collect();
+        for (v, y) in pchip.sample_with_slice(&at_knots).iter().zip(rising) {
+            assert!((v - y).abs() < 1e-12);
+        }
+        // Stays within each pair of neighbours on the non-monotone fixture too.
+        let values = Pchip::new(&FIXTURE).sample_with_slice(&x[..=500]);
+        for (&t, v) in x.iter().zip(&values) {
+            let i = (t as usize).min(FIXTURE.len() - 2);
+            let (lo, hi) = (FIXTURE[i].min(FIXTURE[i + 1]), FIXTURE[i].max(FIXTURE[i + 1]));
+            assert!(*v >= lo - 1e-12 && *v <= hi + 1e-12, "{} at {}", v, t);
+        }
+    }
+    #[test]
+    fn test_cubic_spline_is_c2() {
+        let spline = CubicSpline::new(&FIXTURE);
+        let curvature = |[_, _, c2, c3]: [f64; 4], r: f64| 2.0 * c2 + 6.0 * c3 * r;
+        let slope = |[_, c1, c2, c3]: [f64; 4], r: f64| c1 + 2.0 * c2 * r + 3.0 * c3 * r * r;
+        for pair in spline.coeffs.windows(2) {
+            assert!((slope(pair[0], 1.0) - slope(pair[1], 0.0)).abs() < 1e-9);
+            assert!((curvature(pair[0], 1.0) - curvature(pair[1], 0.0)).abs() < 1e-9);
+        }
+        assert!(curvature(spline.coeffs[0], 0.0).abs() < 1e-12);
+        assert!(curvature(*spline.coeffs.last().unwrap(), 1.0).abs() < 1e-9);
+        let mid = spline.sample_with_slice(&[0.5, 2.5]);
+        let akima = Akima::new(&FIXTURE).sample_with_slice(&[0.5, 2.5]);
+        assert!(mid.iter().zip(&akima).all(|(s, a)| (s - a).abs() < 1.0));
+    }
+}
@@ -1,41 +1,143 @@
 use crate::{
-    consts::{FFT_SIZE, ORIGIN_HOP_SIZE},
-    utils::{mel_basis::MEL_BASIS_DATA, reflect_pad_1d, stft::stft_core},
+    consts::{FFT_SIZE, HIFI_CONFIG, MEL_FMAX, MEL_FMIN, N_MELS, ORIGIN_HOP_SIZE, SAMPLE_RATE},
+    utils::{mel_basis::MEL_BASIS_DATA, reflect_pad_1d, stft::stft_magnitude},
 };
-use ndarray::{Array2, ArrayView1, Axis, azip, s};
+use ndarray::{Array2, ArrayView1, Axis, s};
+use once_cell::sync::{Lazy, OnceCell};
+use oxifft::threading::{ThreadPool, get_default_pool};
 const TARGET_BINS: usize = FFT_SIZE / 2 + 1;
+/// Nonzero `(fft_bin, weight)` pairs of each mel filter.
+type MelBasis = Vec<Vec<(usize, f64)>>;
+/// The baked table covers the default setup; other bin counts or frequency ranges get a
+/// generated filterbank.
+pub static MEL_BASIS: Lazy<MelBasis> = Lazy::new(|| {
+    let config = &*HIFI_CONFIG;
+    let custom_range = config.mel_fmin.is_some() || config.mel_fmax.is_some() || config.mel_htk;
+    if config.mel_bins == N_MELS && !custom_range {
+        return MEL_BASIS_DATA.iter().map(|filter| filter.to_vec()).collect();
+    }
+    let (fmin, fmax) = (config.mel_fmin.unwrap_or(MEL_FMIN), config.mel_fmax.unwrap_or(MEL_FMAX));
+    mel_filterbank(SAMPLE_RATE as f64, FFT_SIZE, config.mel_bins, fmin, fmax, config.mel_htk)
+});
+/// Centre frequency (Hz) of each mel bin in the filterbank in use.
+pub static MEL_CENTERS: Lazy<Vec<f64>> = Lazy::new(|| {
+    let config = &*HIFI_CONFIG;
+    let (fmin, fmax) = (config.mel_fmin.unwrap_or(MEL_FMIN), config.mel_fmax.unwrap_or(MEL_FMAX));
+    let edges = mel_edges(config.mel_bins, fmin, fmax, config.mel_htk);
+    edges[1..=config.mel_bins].to_vec()
+});
+const F_SP: f64 = 200.0 / 3.0;
+const MIN_LOG_HZ: f64 = 1000.0;
+const MIN_LOG_MEL: f64 = MIN_LOG_HZ / F_SP;
+fn log_step() -> f64 {
+    6.4f64.ln() / 27.0
+}
+fn hz_to_mel(hz: f64, htk: bool) -> f64 {
+    if htk {
+        2595.0 * (1.0 + hz / 700.0).log10()
+    } else if hz >= MIN_LOG_HZ {
+        MIN_LOG_MEL + (hz / MIN_LOG_HZ).ln() / log_step()
+    } else {
+        hz / F_SP
+    }
+}
+fn mel_to_hz(mel: f64, htk: bool) -> f64 {
+    if htk {
+        700.0 * (10f64.powf(mel / 2595.0) - 1.0)
+    } else if mel >= MIN_LOG_MEL {
+        MIN_LOG_HZ * (log_step() * (mel - MIN_LOG_MEL)).exp()
+    } else {
+        mel * F_SP
+    }
+}
+/// Mel filterbank on the Slaney (or HTK) scale with Slaney area normalization, matching
+/// `librosa.filters.mel(sr, n_fft, n_mels=n_mels, fmin=fmin, fmax=fmax, htk=htk)`.
+pub fn mel_filterbank(sample_rate: f64, fft_size: usize, n_mels: usize, fmin: f64, fmax: f64, htk: bool) -> MelBasis {
+    mel_edges(n_mels, fmin, fmax, htk).windows(3)
+        .map(|edge| {
+            let norm = 2.0 / (edge[2] - edge[0]);
+            (0..fft_size / 2 + 1)
+                .filter_map(|bin| {
+                    let freq = bin as f64 * sample_rate / fft_size as f64;
+                    let lower = (freq - edge[0]) / (edge[1] - edge[0]);
+                    let upper = (edge[2] - freq) / (edge[2] - edge[1]);
+                    let weight = lower.min(upper).max(0.0) * norm;
+                    (weight > 0.0).then_some((bin, weight))
+                })
+                .collect()
+        })
+        .collect()
+}
+/// `n_mels + 2` frequencies evenly spaced on the mel scale; filter `i` peaks at `edges[i + 1]`.
+fn mel_edges(n_mels: usize, fmin: f64, fmax: f64, htk: bool) -> Vec<f64> {
+    let (mel_min, mel_max) = (hz_to_mel(fmin, htk), hz_to_mel(fmax, htk));
+    (0..n_mels + 2)
+        .map(|i| mel_to_hz(mel_min + (mel_max - mel_min) * i as f64 / (n_mels + 1) as f64, htk))
+        .collect()
+}
+/// Analysis hop in samples for a given `speed`; frame `i` of [`mel`] sits `i` hops into the input.
+pub fn analysis_hop(speed: f64) -> usize {
+    ((ORIGIN_HOP_SIZE as f64 * speed).round() as usize).max(1)
+}
+/// Seconds from the start of the input to the centre of frame 0, for a hop of `thop` seconds.
+pub fn frame_offset(thop: f64, center: bool) -> f64 {
+    if center { 0.0 } else { thop / 2.0 }
+}
+/// With `center`, frame `i` is centred on sample `i * hop_len`; otherwise `wave` is padded in
+/// place so frame `i` covers hop `i` and is centred half a hop later (see [`frame_offset`]).
+pub fn analysis_stft(wave: &mut Vec<f64>, fft_size: usize, hop_len: usize, center: bool) -> Array2<f64> {
+    if !center {
+        reflect_pad_1d(wave, (fft_size - hop_len) / 2, (fft_size - hop_len + 1) / 2);
+    }
+    stft_magnitude(wave, fft_size, hop_len, HIFI_CONFIG.window, center)
+}
 pub fn mel(wave: &mut Vec<f64>, key_shift: f64, speed: f64) -> Array2<f64> {
     let fft_size = (FFT_SIZE as f64 * 2f64.powf(key_shift / 12.0)).round() as usize;
-    let hop_len = (ORIGIN_HOP_SIZE as f64 * speed).round() as usize;
+    let hop_len = analysis_hop(speed);
     let scale = FFT_SIZE as f64 / fft_size as f64;
-    reflect_pad_1d(wave, (fft_size - hop_len) / 2, (fft_size - hop_len + 1) / 2);
-    let comp_spec = stft_core(&wave, fft_size, hop_len);
-    let n_frames = comp_spec.ncols();
-    let mut spec = Array2::zeros((comp_spec.nrows(), n_frames));
-    azip!((spec_elem in &mut spec, comp_elem in &comp_spec) {
-        *spec_elem = comp_elem.norm();
-    });
+    let spec = analysis_stft(wave, fft_size, hop_len, HIFI_CONFIG.stft_center);
+    let n_frames = spec.ncols();
     let proc_spec = if key_shift != 0. {
         let mut target = Array2::zeros((TARGET_BINS, n_frames));
-        let src_view = spec.slice(s![..TARGET_BINS, ..]);
+        let src_view = spec.slice(s![..TARGET_BINS.min(spec.nrows()), ..]);
         target.slice_mut(s![..src_view.nrows(), ..]).assign(&src_view);
         target.mapv_inplace(|x| x * scale);
         target
     } else {
         spec
     };
-    let mut mel_spec = Array2::zeros((128, n_frames));
-    azip!((mut mel_row in mel_spec.axis_iter_mut(Axis(0)), nonzeros in ArrayView1::from(&MEL_BASIS_DATA)) {
-        for (frame_idx, mel_val) in mel_row.iter_mut().enumerate() {
-            let mut sum = 0.0;
-            for &(freq_idx, weight) in *nonzeros {
-                if freq_idx < proc_spec.nrows() {
-                    sum += proc_spec[(freq_idx, frame_idx)] * weight;
-                }
-            }
-            *mel_val = sum;
+    if n_frames >= PARALLEL_MIN_FRAMES {
+        apply_basis_parallel(&MEL_BASIS, &proc_spec)
+    } else {
+        apply_basis_serial(&MEL_BASIS, &proc_spec)
+    }
+}
+/// Below this many frames, handing columns to the thread pool costs more than it saves.
+const PARALLEL_MIN_FRAMES: usize = 256;
+fn filter_sum(nonzeros: &[(usize, f64)], spec: &Array2<f64>, frame_idx: usize) -> f64 {
+    let mut sum = 0.0;
+    for &(freq_idx, weight) in nonzeros {
+        if freq_idx < spec.nrows() {
+            sum += spec[(freq_idx, frame_idx)] * weight;
         }
+    }
+    sum
+}
+fn apply_basis_serial(basis: &MelBasis, spec: &Array2<f64>) -> Array2<f64> {
+    Array2::from_shape_fn((basis.len(), spec.ncols()), |(m, f)| filter_sum(&basis[m], spec, f))
+}
+/// One frame per task; each sum runs in the same order as the serial path, so the result is
+/// bit-identical.
+fn apply_basis_parallel(basis: &MelBasis, spec: &Array2<f64>) -> Array2<f64> {
+    let n_frames = spec.ncols();
+    let frames: Vec<OnceCell<Vec<f64>>> = (0..n_frames).map(|_| OnceCell::new()).collect();
+    get_default_pool().parallel_for(n_frames, |frame_idx| {
+        let _ = frames[frame_idx].set(basis.iter().map(|nonzeros| filter_sum(nonzeros, spec, frame_idx)).collect());
     });
+    let mut mel_spec = Array2::zeros((basis.len(), n_frames));
+    for (mut column, frame) in mel_spec.axis_iter_mut(Axis(1)).zip(frames) {
+        column.assign(&ArrayView1::from(frame.get().unwrap()));
+    }
     mel_spec
 }
 
@@ -50,7 +152,61 @@ mod tests {
         let mel_spec = mel(&mut y, 0., 1.0);
         let (pad_left, pad_right) = ((FFT_SIZE - ORIGIN_HOP_SIZE) / 2, (FFT_SIZE - ORIGIN_HOP_SIZE + 1) / 2);
         let expected_frames = ((sample_len + pad_left + pad_right - FFT_SIZE) / ORIGIN_HOP_SIZE) + 1;
-        assert_eq!(mel_spec.dim(), (128, expected_frames));
+        assert_eq!(mel_spec.dim(), (N_MELS, expected_frames));
         assert!(mel_spec.iter().all(|&x| !x.is_nan()));
+        // Padding is symmetric here, so the first frame of the ramp must match the last frame of
+        // the reversed ramp.
+        let mut reversed: Vec<f64> = linspace(0., 1., sample_len).into_iter().rev().collect();
+        let reversed_spec = mel(&mut reversed, 0., 1.0);
+        for (a, b) in mel_spec.column(0).iter().zip(reversed_spec.column(expected_frames - 1)) {
+            assert!((a - b).abs() <= 1e-9 * a.abs().max(1.0), "{} vs {}", a, b);
+        }
+    }
+    #[test]
+    fn test_analysis_speed_frames() {
+        let sample_len = FFT_SIZE * 10;
+        for speed in [0.5, 2.0] {
+            let hop = analysis_hop(speed);
+            assert_eq!(hop, (ORIGIN_HOP_SIZE as f64 * speed) as usize);
+            let mel_spec = mel(&mut linspace(0., 1., sample_len), 0., speed);
+            let padded = sample_len + (FFT_SIZE - hop) / 2 + (FFT_SIZE - hop).div_ceil(2);
+            assert_eq!(mel_spec.ncols(), (padded - FFT_SIZE) / hop + 1);
+        }
+    }
+    #[test]
+    fn test_parallel_basis_matches_serial() {
+        let spec = Array2::from_shape_fn((TARGET_BINS, 2000), |(b, f)| ((b * 31 + f * 17) % 97) as f64 / 97.);
+        let serial = apply_basis_serial(&MEL_BASIS, &spec);
+        let parallel = apply_basis_parallel(&MEL_BASIS, &spec);
+        assert_eq!(serial, parallel);
+    }
+    #[test]
+    fn test_filterbank_matches_baked_table() {
+        let basis = mel_filterbank(SAMPLE_RATE as f64, FFT_SIZE, N_MELS, MEL_FMIN, MEL_FMAX, false);
+        assert_eq!(basis.len(), MEL_BASIS_DATA.len());
+        for (generated, baked) in basis.iter().zip(MEL_BASIS_DATA.iter()) {
+            assert_eq!(generated.len(), baked.len());
+            for (&(bin, weight), &(baked_bin, baked_weight)) in generated.iter().zip(baked.iter()) {
+                assert_eq!(bin, baked_bin);
+                assert!((weight - baked_weight).abs() < 1e-8);
+            }
+        }
+        assert_eq!(mel_filterbank(SAMPLE_RATE as f64, FFT_SIZE, 80, MEL_FMIN, MEL_FMAX, false).len(), 80);
+    }
+    #[test]
+    fn test_filterbank_reference_weights() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-7 * b.abs().max(1e-3);
+        // librosa.filters.mel(sr=44100, n_fft=2048, n_mels=128, fmin=40, fmax=16000)
+        let slaney = mel_filterbank(44100.0, 2048, 128, 40.0, 16000.0, false);
+        for (mel, bin, expected) in [(0, 2, 0.00383336423), (0, 3, 0.030752370134), (64, 112, 0.00170802335), (127, 702, 0.00011341678)] {
+            let &(_, weight) = slaney[mel].iter().find(|&&(b, _)| b == bin).unwrap();
+            assert!(close(weight, expected), "mel {} bin {}: {} != {}", mel, bin, weight, expected);
+        }
+        // librosa.filters.mel(sr=22050, n_fft=1024, n_mels=40, fmin=0, fmax=8000, htk=True)
+        let htk = mel_filterbank(22050.0, 1024, 40, 0.0, 8000.0, true);
+        for (mel, bin, expected) in [(0, 1, 0.0105998159), (0, 2, 0.0211996318), (10, 29, 0.0043451942), (39, 327, 0.00019437357)] {
+            let &(_, weight) = htk[mel].iter().find(|&&(b, _)| b == bin).unwrap();
+            assert!(close(weight, expected), "htk mel {} bin {}: {} != {}", mel, bin, weight, expected);
+        }
     }
 }
\ No newline at end of file
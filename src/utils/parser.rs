@@ -1,6 +1,8 @@
 use anyhow::{anyhow, Result};
 use regex::Regex;
+use serde::Serialize;
 use std::collections::HashMap;
+use crate::consts::HIFI_CONFIG;
 #[inline(always)]
 fn to_uint6(c: u8) -> u8 {
     match c {
@@ -12,10 +14,15 @@ fn to_uint6(c: u8) -> u8 {
         _ => 0,
     }
 }
+/// An RLE count beyond this is treated as corrupt; it is far longer than any real note.
+const MAX_RLE: usize = 1 << 16;
 #[inline(always)]
 fn to_int12_stream<S: AsRef<str>>(b64: S) -> Vec<i16> {
-    b64.as_ref()
-        .as_bytes()
+    let bytes = b64.as_ref().as_bytes();
+    if bytes.len() % 2 != 0 {
+        tracing::warn!("Dropping truncated pitchbend chunk at the end of '{}'", b64.as_ref());
+    }
+    bytes
         .chunks_exact(2) 
         .map(|chunk| {
             let uint12 = (to_uint6(chunk[0]) as u16) << 6 | (to_uint6(chunk[1]) as u16);
@@ -23,6 +30,8 @@ fn to_int12_stream<S: AsRef<str>>(b64: S) -> Vec<i16> {
         })
         .collect()
 }
+/// Decodes UTAU's base64 + RLE pitchbend. Corrupt segments are logged and skipped so the note
+/// still renders with the pitch that could be read.
 pub fn pitch_string_to_cents(string: &str) -> Result<Vec<f64>> {
     let mut res = Vec::new();
     let parts: Vec<_> = string.split('#').collect();
@@ -30,12 +39,21 @@ pub fn pitch_string_to_cents(string: &str) -> Result<Vec<f64>> {
     while idx < parts.len() - 1 {
         let stream = to_int12_stream(parts[idx]);
         res.extend(stream);
-        let rle = parts[idx+1].parse::<usize>()
-            .map_err(|e| anyhow!("Invalid RLE '{}': {}", parts[idx+1], e))?;
-        if rle > 0 {
-            let last = res.last().copied()
-                .ok_or_else(|| anyhow!("Empty pitch stream for '{}'", parts[idx]))?;
-            res.extend(std::iter::repeat(last).take(rle));
+        let rle = match parts[idx+1].parse::<usize>() {
+            Ok(rle) if rle <= MAX_RLE => rle,
+            Ok(rle) => {
+                tracing::warn!("Clamping pitchbend RLE {} to {}", rle, MAX_RLE);
+                MAX_RLE
+            }
+            Err(e) => {
+                tracing::warn!("Skipping invalid pitchbend RLE '{}': {}", parts[idx+1], e);
+                0
+            }
+        };
+        match res.last().copied() {
+            Some(last) => res.extend(std::iter::repeat(last).take(rle)),
+            None if rle > 0 => tracing::warn!("Skipping pitchbend RLE {} with no value to repeat", rle),
+            None => {}
         }
         idx += 2;
     }
@@ -47,24 +65,83 @@ pub fn pitch_string_to_cents(string: &str) -> Result<Vec<f64>> {
         .chain(std::iter::once(0.0))
         .collect())
 }
-#[inline(always)]
-pub fn tempo_parser(arg: &str) -> Result<f64> {
-    let tempo: f64 = arg[1..].parse()?;
-    Ok(tempo)
+/// UTAU pitchbend points come every 5 ticks at 480 ticks per quarter note.
+const TICKS_PER_POINT: f64 = 5.0;
+const TICKS_PER_BEAT: f64 = 480.0;
+/// Tempo changes over a note as `(tick from note start, bpm)`, sorted by tick; the first
+/// tempo also covers any ticks before its own.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TempoMap(Vec<(f64, f64)>);
+impl TempoMap {
+    pub fn initial(&self) -> f64 {
+        self.0[0].1
+    }
+    pub fn is_constant(&self) -> bool {
+        self.0.iter().all(|&(_, bpm)| bpm == self.initial())
+    }
+    /// Seconds from the note start to each of `n` pitchbend points.
+    fn point_times(&self, n: usize) -> Vec<f64> {
+        let (mut times, mut elapsed, mut tick) = (Vec::with_capacity(n), 0.0, 0.0);
+        let mut segment = 0;
+        for k in 0..n {
+            let target = k as f64 * TICKS_PER_POINT;
+            while tick < target {
+                while segment + 1 < self.0.len() && self.0[segment + 1].0 <= tick {
+                    segment += 1;
+                }
+                let next = self.0.get(segment + 1).map_or(target, |&(t, _)| t.min(target));
+                elapsed += (next - tick) * 60.0 / (self.0[segment].1 * TICKS_PER_BEAT);
+                tick = next;
+            }
+            times.push(elapsed);
+        }
+        times
+    }
+    /// Fractional pitchbend index for each of `fractions` (0 to 1 through the note). A constant
+    /// tempo spreads the points evenly; a tempo map places them along its piecewise time axis.
+    pub fn point_positions(&self, n: usize, fractions: &[f64]) -> Vec<f64> {
+        let last = n.saturating_sub(1) as f64;
+        if self.is_constant() || n < 2 {
+            return fractions.iter().map(|&f| f * last).collect();
+        }
+        let times = self.point_times(n);
+        let total = times[n - 1];
+        fractions.iter()
+            .map(|&f| {
+                let t = f * total;
+                let k = times.partition_point(|&p| p <= t).clamp(1, n - 1);
+                let span = times[k] - times[k - 1];
+                let frac = if span > 0.0 { ((t - times[k - 1]) / span).clamp(0.0, 1.0) } else { 0.0 };
+                (k - 1) as f64 + frac
+            })
+            .collect()
+    }
 }
+/// `!120`, or a tempo map such as `!120@0,!90@960` with ticks counted from the note start.
+pub fn tempo_parser(arg: &str) -> Result<TempoMap> {
+    let mut map = arg.split(',')
+        .map(|part| {
+            let part = part.trim();
+            let body = part.strip_prefix('!').unwrap_or(part);
+            let (bpm, tick) = body.split_once('@').unwrap_or((body, "0"));
+            let (bpm, tick): (f64, f64) = (bpm.parse()?, tick.parse()?);
+            if !(bpm > 0.0 && bpm.is_finite() && tick >= 0.0) {
+                return Err(anyhow!("Invalid tempo '{}'", part));
+            }
+            Ok((tick, bpm))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    map.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(TempoMap(map))
+}
+/// UTAU note names (`C-1` = 0 through `G9` = 127), or a bare MIDI number.
 pub fn pitch_parser(arg: &str) -> Result<i32> {
     if let Ok(v) = arg.parse::<i32>() {
         return Ok(v);
     }
-    let (note_part, octave_part) = match arg.char_indices().nth(1) {
-        Some((_, c)) => {
-            if c == '#' {
-                (&arg[0..2], &arg[2..])
-            } else {
-                (&arg[0..1], &arg[1..])
-            }
-        }
-        None => return Err(anyhow!("Invalid pitch format")),
+    let split = if arg.get(1..2) == Some("#") { 2 } else { 1 };
+    let (Some(note_part), Some(octave_part)) = (arg.get(..split), arg.get(split..)) else {
+        return Err(anyhow!("Invalid pitch format '{}'", arg));
     };
     let note_val = match note_part {
         "C" => 0,
@@ -79,25 +156,51 @@ pub fn pitch_parser(arg: &str) -> Result<i32> {
         "A" => 9,
         "A#" => 10,
         "B" => 11,
-        _ => return Err(anyhow!("Invalid note")),
+        _ => return Err(anyhow!("Invalid note '{}' in pitch '{}'", note_part, arg)),
     };
-    let octave = octave_part.parse::<i32>()? + 1;
-    Ok(octave * 12 + note_val)
+    let octave = octave_part.parse::<i32>()
+        .map_err(|_| anyhow!("Invalid octave '{}' in pitch '{}'", octave_part, arg))? + 1;
+    let midi = octave * 12 + note_val;
+    if !(0..=127).contains(&midi) {
+        return Err(anyhow!("Pitch '{}' is outside the MIDI range C-1 to G9", arg));
+    }
+    Ok(midi)
+}
+/// Flag name to its value, `None` for a bare flag.
+pub type Flags = HashMap<String, Option<f64>>;
+/// Unknown flag-like text is logged, or rejected when `strict_flags` is set.
+pub fn flag_parser(s: &str) -> Result<Flags> {
+    let (flags, unknown) = parse_flags(s)?;
+    if !unknown.is_empty() {
+        let message = format!("Unknown flags: {}", unknown.join(", "));
+        if HIFI_CONFIG.strict_flags {
+            return Err(anyhow!(message));
+        }
+        tracing::warn!("{} (ignored)", message);
+    }
+    Ok(flags)
 }
-pub fn flag_parser(s: &str) -> Result<HashMap<String, Option<f64>>> {
+/// Returns the supported flags and whatever text between them was not recognised.
+fn parse_flags(s: &str) -> Result<(Flags, Vec<String>)> {
     let input = s.replace('/', "");
     static SUPPORTED_FLAGS: &[&str] = &[
         "fe", "fl", "fo", "fv", "fp", "ve", "vo", "g", "t", "vl",
-        "A", "B", "G", "P", "S", "p", "R", "D", "C", "Z", "Hv", "Hb", "Ht", "He", "HG"
+        "A", "B", "G", "P", "S", "p", "R", "D", "C", "Z", "Hv", "Hb", "Ht", "He", "HGf", "HG", "Hm", "HVd", "HVr", "HVo", "Hs", "Hq", "Hd", "HL", "HH", "Hp"
     ];
     let re = Regex::new(&format!(r"({})([+-]?\d+(\.\d+)?)?", SUPPORTED_FLAGS.join("|")))?;
     let mut flags = HashMap::new();
+    let (mut leftover, mut last) = (String::new(), 0);
     for cap in re.captures_iter(&input) {
+        let whole = cap.get(0).unwrap();
+        leftover.push_str(&input[last..whole.start()]);
+        leftover.push(' ');
+        last = whole.end();
         let flag = cap.get(1).unwrap().as_str().to_string();
         let value = cap.get(2).map(|m| m.as_str().parse::<f64>().ok()).flatten();
         flags.insert(flag, value); 
     }
-    Ok(flags)
+    leftover.push_str(&input[last..]);
+    Ok((flags, leftover.split_whitespace().map(str::to_string).collect()))
 }
 #[cfg(test)]
 mod tests {
@@ -109,9 +212,38 @@ mod tests {
         pitchbend.iter().for_each(|p| println!("{}", p));
     }
     #[test]
+    fn test_malformed_pitchbend() {
+        // "AB" decodes to 1 cent; a dangling "C" is a truncated chunk.
+        assert_eq!(pitch_string_to_cents("ABABC").unwrap(), [0.01, 0.01, 0.0]);
+        assert_eq!(pitch_string_to_cents("AB#x7#AC").unwrap(), [0.01, 0.02, 0.0]);
+        assert_eq!(pitch_string_to_cents("AB#-3#AC").unwrap(), [0.01, 0.02, 0.0]);
+        let huge = pitch_string_to_cents("AB#99999999999#AC").unwrap();
+        assert_eq!(huge.len(), 1 + MAX_RLE + 2);
+        assert_eq!(pitch_string_to_cents("#4#AB").unwrap(), [0.01, 0.0]);
+    }
+    #[test]
     fn test_tempo() {
         let tempo = tempo_parser("!120").unwrap();
-        assert_eq!(tempo, 120.);
+        assert_eq!(tempo.initial(), 120.);
+        assert!(tempo.is_constant());
+        assert!(tempo_parser("!0").is_err());
+        assert!(tempo_parser("!x@5").is_err());
+    }
+    #[test]
+    fn test_tempo_map() {
+        let tempo = tempo_parser("!60@480,!120@0").unwrap();
+        assert_eq!((tempo.initial(), tempo.is_constant()), (120., false));
+        // 96 points per beat: points 0..=96 span the 120 bpm beat, 96..=192 the 60 bpm one.
+        let times = tempo.point_times(193);
+        assert!((times[96] - 0.5).abs() < 1e-12);
+        assert!((times[192] - 1.5).abs() < 1e-12);
+        assert!((times[144] - 1.0).abs() < 1e-12);
+        let positions = tempo.point_positions(193, &[0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]);
+        for (found, expected) in positions.iter().zip([0.0, 96.0, 144.0, 192.0]) {
+            assert!((found - expected).abs() < 1e-9, "{} != {}", found, expected);
+        }
+        let constant = tempo_parser("!120").unwrap();
+        assert_eq!(constant.point_positions(193, &[0.25]), [48.0]);
     }
     #[test]
     fn test_pitch() {
@@ -123,6 +255,28 @@ mod tests {
         assert_eq!(pitch, 69);
     }
     #[test]
+    fn test_pitch_range_edges() {
+        assert_eq!(pitch_parser("C-1").unwrap(), 0);
+        assert_eq!(pitch_parser("C#-1").unwrap(), 1);
+        assert_eq!(pitch_parser("A-1").unwrap(), 9);
+        assert!(pitch_parser("B#3").is_err());
+        assert_eq!(pitch_parser("G9").unwrap(), 127);
+        assert_eq!(pitch_parser("F#9").unwrap(), 126);
+        assert_eq!(pitch_parser("B-1").unwrap(), 11);
+        for (bad, message) in [
+            ("G#9", "outside the MIDI range"),
+            ("C-2", "outside the MIDI range"),
+            ("H4", "Invalid note 'H'"),
+            ("C", "Invalid octave ''"),
+            ("C#x", "Invalid octave 'x'"),
+            ("é4", "Invalid pitch format"),
+            ("", "Invalid pitch format"),
+        ] {
+            let err = pitch_parser(bad).unwrap_err().to_string();
+            assert!(err.contains(message), "{}: {}", bad, err);
+        }
+    }
+    #[test]
     fn test_parse_empty() -> Result<()> {
         let flags = flag_parser("")?;
         assert!(flags.is_empty()); 
@@ -138,6 +292,41 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn test_parse_vocoder_flag() -> Result<()> {
+        let flags = flag_parser("g-5Hm2Hb80")?;
+        assert_eq!(flags.get("Hm"), Some(&Some(2.0)));
+        assert_eq!(flags.get("g"), Some(&Some(-5.0)));
+        assert_eq!(flags.get("Hb"), Some(&Some(80.0)));
+        Ok(())
+    }
+    #[test]
+    fn test_unknown_flags() -> Result<()> {
+        let (flags, unknown) = parse_flags("g-5Xyz12Hb80 Q3")?;
+        assert_eq!(flags.get("g"), Some(&Some(-5.0)));
+        assert_eq!(flags.get("Hb"), Some(&Some(80.0)));
+        assert_eq!(unknown, ["Xyz12", "Q3"]);
+        assert!(parse_flags("B50Hv70fl0.5G")?.1.is_empty());
+        let flags = flag_parser("g-5Xyz12")?;
+        assert_eq!(flags.len(), 1);
+        Ok(())
+    }
+    #[test]
+    fn test_parse_vibrato_flags() -> Result<()> {
+        let flags = flag_parser("HVd40HVr5.5Hv90HVo120")?;
+        assert_eq!(flags.get("HVd"), Some(&Some(40.0)));
+        assert_eq!(flags.get("HVr"), Some(&Some(5.5)));
+        assert_eq!(flags.get("HVo"), Some(&Some(120.0)));
+        assert_eq!(flags.get("Hv"), Some(&Some(90.0)));
+        Ok(())
+    }
+    #[test]
+    fn test_parse_growl_rate_flag() -> Result<()> {
+        let flags = flag_parser("HG60HGf120")?;
+        assert_eq!(flags.get("HG"), Some(&Some(60.0)));
+        assert_eq!(flags.get("HGf"), Some(&Some(120.0)));
+        Ok(())
+    }
+    #[test]
     fn test_parse_flag_without_value() -> Result<()> {
         let flags = flag_parser("GHeMe")?;
         assert_eq!(flags.get("G"), Some(&None));
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::f64::consts::TAU;
+/// Vibrato flags, added to the rendered pitch curve over the sustained part of the note:
+/// - `HVd`: depth in cents, 0~200 (0 or absent disables it)
+/// - `HVr`: rate in Hz, 0.1~20, default 5.5
+/// - `HVo`: onset delay after the consonant in ms, 0~5000
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vibrato {
+    pub depth_cents: f64,
+    pub rate_hz: f64,
+    pub delay_secs: f64,
+}
+impl Vibrato {
+    pub fn from_flags(flags: &HashMap<String, Option<f64>>) -> Option<Self> {
+        let get = |key: &str| flags.get(key).and_then(|o| o.as_ref()).copied();
+        let depth_cents = get("HVd")?.clamp(0., 200.);
+        (depth_cents > 0.).then(|| Self {
+            depth_cents,
+            rate_hz: get("HVr").unwrap_or(5.5).clamp(0.1, 20.),
+            delay_secs: get("HVo").unwrap_or(0.).clamp(0., 5000.) / 1000.,
+        })
+    }
+    /// `pitch` is in semitones at `times` (seconds). The phase starts at `note_start`, the depth
+    /// fades in over one cycle once `sustain.start` plus the delay is reached, and nothing is
+    /// added past `sustain.end`.
+    pub fn apply(&self, pitch: &mut [f64], times: &[f64], note_start: f64, sustain: std::ops::Range<f64>) {
+        let onset = sustain.start + self.delay_secs;
+        for (p, &t) in pitch.iter_mut().zip(times) {
+            if t < onset || t > sustain.end {
+                continue;
+            }
+            let envelope = ((t - onset) * self.rate_hz).min(1.);
+            *p += envelope * self.depth_cents / 100. * (TAU * self.rate_hz * (t - note_start)).sin();
+        }
+    }
+}
+/// Radius, in pitchbend points, of the moving average at smoothing strength 100.
+const MAX_SMOOTH_RADIUS: f64 = 12.0;
+/// `Hs` flag (0~100): two moving-average passes over the pitchbend points. The window shrinks
+/// towards the ends so the first and last points, where the note joins its neighbours, stay put.
+pub fn smooth_pitch(pitch: &mut [f64], strength: f64) {
+    let radius = (strength.clamp(0., 100.) / 100. * MAX_SMOOTH_RADIUS).round() as usize;
+    if radius == 0 || pitch.len() < 3 {
+        return;
+    }
+    for _ in 0..2 {
+        let source = pitch.to_vec();
+        let last = source.len() - 1;
+        for (i, p) in pitch.iter_mut().enumerate() {
+            let r = radius.min(i).min(last - i);
+            *p = source[i - r..=i + r].iter().sum::<f64>() / (2 * r + 1) as f64;
+        }
+    }
+}
+/// `Hq` flag (0~100): pulls a MIDI pitch toward the nearest semitone; 100 snaps it outright.
+pub fn quantize_pitch(pitch: f64, strength: f64) -> f64 {
+    pitch + (pitch.round() - pitch) * strength.clamp(0., 100.) / 100.
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_quantize_pitch() {
+        for pitch in [59.7, 60.2, 61.5, 64.49] {
+            let snapped = quantize_pitch(pitch, 100.);
+            assert_eq!(snapped, snapped.round());
+            assert!((snapped - pitch).abs() <= 0.5);
+            assert_eq!(quantize_pitch(pitch, 0.), pitch);
+        }
+        assert!((quantize_pitch(60.4, 50.) - 60.2).abs() < 1e-12);
+    }
+    #[test]
+    fn test_smooth_pitch() {
+        let stepped: Vec<f64> = (0..160).map(|i| if (i / 8) % 2 == 0 { 60. } else { 62. }).collect();
+        let mut smoothed = stepped.clone();
+        smooth_pitch(&mut smoothed, 100.);
+        let max_step = |p: &[f64]| p.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0., f64::max);
+        assert!(max_step(&smoothed) < 0.5 * max_step(&stepped));
+        let mean = |p: &[f64]| p.iter().sum::<f64>() / p.len() as f64;
+        assert!((mean(&smoothed) - mean(&stepped)).abs() < 0.05);
+        assert_eq!((smoothed[0], smoothed[159]), (stepped[0], stepped[159]));
+        let mut untouched = stepped.clone();
+        smooth_pitch(&mut untouched, 0.);
+        assert_eq!(untouched, stepped);
+    }
+    #[test]
+    fn test_vibrato_rate() {
+        let frame = 512. / 44100.;
+        let times: Vec<f64> = (0..512).map(|i| i as f64 * frame).collect();
+        let mut pitch = vec![60.; times.len()];
+        let flags = HashMap::from([("HVd".to_string(), Some(50.)), ("HVr".to_string(), Some(6.)), ("HVo".to_string(), Some(200.))]);
+        let vibrato = Vibrato::from_flags(&flags).unwrap();
+        assert!(Vibrato::from_flags(&HashMap::from([("HVr".to_string(), Some(6.))])).is_none());
+        vibrato.apply(&mut pitch, &times, 0.1, 0.5..5.0);
+        assert!(times.iter().zip(&pitch).all(|(&t, &p)| t >= 0.7 || p == 60.));
+        let peak = pitch.iter().cloned().fold(f64::MIN, f64::max);
+        assert!((peak - 60.5).abs() < 0.01);
+        // Strongest DFT bin of the modulation should sit at the vibrato rate.
+        let len = times.len() as f64;
+        let (best_hz, _) = (1..256)
+            .map(|k| {
+                let (re, im) = pitch.iter().enumerate().fold((0., 0.), |(re, im), (n, &p)| {
+                    let w = TAU * k as f64 * n as f64 / len;
+                    (re + (p - 60.) * w.cos(), im - (p - 60.) * w.sin())
+                });
+                (k as f64 / (len * frame), re * re + im * im)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        assert!((best_hz - 6.).abs() < 1. / (len * frame), "peak at {} Hz", best_hz);
+    }
+}
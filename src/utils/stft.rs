@@ -1,44 +1,174 @@
-use std::sync::Arc;
-use ndarray::{Array2, ArrayView1, s};
+use std::{hash::Hash, num::NonZeroUsize, sync::{Arc, Mutex}};
+use lru::LruCache;
+use ndarray::{Array2, ArrayView1, Zip, s};
 use once_cell::sync::{Lazy, OnceCell};
 use dashmap::DashMap;
 use oxifft::{Complex, Direction, Flags, Plan, streaming::WindowFunction, threading::{get_default_pool, ThreadPool}};
-static HANN_WINDOWS: Lazy<DashMap<usize, Arc<Vec<f64>>>> = Lazy::new(DashMap::new);
-static FFT_PLANS: Lazy<DashMap<(usize, Direction), Arc<Plan<f64>>>> = Lazy::new(DashMap::new);
-static ISTFT_WINDOW_SQ: Lazy<Arc<Vec<f64>>> = Lazy::new(|| {
-    let window = get_hann_window(crate::consts::FFT_SIZE);
-    Arc::new(window.iter().map(|&w| w * w).collect())
-});
-fn get_hann_window(fft_size: usize) -> Arc<Vec<f64>> {
-    HANN_WINDOWS
-        .entry(fft_size)
-        .or_insert_with(|| {
-            Arc::new(WindowFunction::Hann.generate(fft_size))
-        })
-        .clone()
-}
-fn get_fft_plan(fft_size: usize, direction: Direction) -> Arc<Plan<f64>> {
-    FFT_PLANS
-        .entry((fft_size, direction))
-        .or_insert_with(|| {
-            Arc::new(
-                Plan::dft_1d(fft_size, direction, Flags::ESTIMATE)
-                    .expect(&format!("Failed to generate FFT plan for size {} and direction {:?}", fft_size, direction))
-            )
-        })
-        .clone()
+use crate::{consts::{FFT_SIZE, HIFI_CONFIG, WindowKind}, utils::reflect_pad_1d};
+/// Per-size cache. Every key-shifted gender value brings its own FFT size, so the odd ones
+/// live in an LRU of `capacity` entries while `pinned` sizes are kept for good.
+struct SizeCache<K, V> {
+    pinned: DashMap<K, V>,
+    recent: Mutex<LruCache<K, V>>,
+}
+impl<K: Hash + Eq + Clone, V: Clone> SizeCache<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            pinned: DashMap::new(),
+            recent: Mutex::new(LruCache::new(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN))),
+        }
+    }
+    fn get_or_insert(&self, key: K, pin: bool, make: impl FnOnce() -> V) -> V {
+        self.get_or_try_insert(key, pin, || Some(make())).unwrap()
+    }
+    /// `make` runs without any lock held, so a slow or failing build never stalls other sizes.
+    /// When two threads race on the same key, the first value stored wins.
+    fn get_or_try_insert(&self, key: K, pin: bool, make: impl FnOnce() -> Option<V>) -> Option<V> {
+        if pin {
+            if let Some(value) = self.pinned.get(&key) {
+                return Some(value.clone());
+            }
+            let value = make()?;
+            return Some(self.pinned.entry(key).or_insert(value).clone());
+        }
+        if let Some(value) = self.recent.lock().unwrap().get(&key) {
+            return Some(value.clone());
+        }
+        let value = make()?;
+        Some(self.recent.lock().unwrap().get_or_insert(key, || value).clone())
+    }
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.pinned.len() + self.recent.lock().unwrap().len()
+    }
+}
+/// The default size, and the half-length transform behind it, are never evicted.
+fn is_pinned(size: usize) -> bool {
+    size == FFT_SIZE || size == FFT_SIZE / 2
+}
+/// Each window is cached with its square, which ISTFT sums to undo the overlap-add gain.
+struct Window {
+    coeffs: Vec<f64>,
+    squared: Vec<f64>,
+}
+static WINDOWS: Lazy<SizeCache<(usize, WindowKind), Arc<Window>>> = Lazy::new(|| SizeCache::new(HIFI_CONFIG.fft_cache_entries));
+static FFT_PLANS: Lazy<SizeCache<(usize, Direction), Arc<Plan<f64>>>> = Lazy::new(|| SizeCache::new(HIFI_CONFIG.fft_cache_entries));
+fn window_coeffs(fft_size: usize, kind: WindowKind) -> Vec<f64> {
+    match kind {
+        WindowKind::Hann => WindowFunction::Hann.generate(fft_size),
+        WindowKind::Hamming => WindowFunction::Hamming.generate(fft_size),
+        WindowKind::BlackmanHarris => {
+            const A: [f64; 4] = [0.35875, 0.48829, 0.14128, 0.01168];
+            let n = fft_size.saturating_sub(1).max(1) as f64;
+            (0..fft_size)
+                .map(|i| {
+                    let x = std::f64::consts::TAU * i as f64 / n;
+                    A[0] - A[1] * x.cos() + A[2] * (2. * x).cos() - A[3] * (3. * x).cos()
+                })
+                .collect()
+        }
+    }
+}
+fn get_window(fft_size: usize, kind: WindowKind) -> Arc<Window> {
+    WINDOWS.get_or_insert((fft_size, kind), is_pinned(fft_size), || {
+        let coeffs = window_coeffs(fft_size, kind);
+        let squared = coeffs.iter().map(|&w| w * w).collect();
+        Arc::new(Window { coeffs, squared })
+    })
+}
+fn get_fft_plan(fft_size: usize, direction: Direction) -> Option<Arc<Plan<f64>>> {
+    FFT_PLANS.get_or_try_insert((fft_size, direction), is_pinned(fft_size), || {
+        Plan::dft_1d(fft_size, direction, Flags::ESTIMATE).map(Arc::new)
+    })
+}
+/// Real-input FFT of one `n`-sample frame. Even sizes pack the frame into `n / 2` complex
+/// samples and run a half-length transform; odd sizes, which only come from key shifts, fall
+/// back to a full complex transform.
+struct RealFft {
+    n: usize,
+    plan: Arc<Plan<f64>>,
+    /// `e^(-2πik/n)` for `k` in `0..=n/2`; empty on the odd-size fallback.
+    twiddles: Vec<Complex<f64>>,
+}
+impl RealFft {
+    fn new(n: usize, direction: Direction) -> Self {
+        let size = if n % 2 == 1 { n } else { n / 2 };
+        let plan = get_fft_plan(size, direction)
+            .unwrap_or_else(|| panic!("Failed to generate FFT plan for size {} and direction {:?}", size, direction));
+        if n % 2 == 1 {
+            return Self { n, plan, twiddles: Vec::new() };
+        }
+        let twiddles = (0..=n / 2)
+            .map(|k| Complex::cis(-std::f64::consts::TAU * k as f64 / n as f64))
+            .collect();
+        Self { n, plan, twiddles }
+    }
+    /// `n / 2 + 1` bins of the unnormalized DFT of `x`.
+    fn forward(&self, x: &[f64]) -> Vec<Complex<f64>> {
+        let n = self.n;
+        if self.twiddles.is_empty() {
+            let input: Vec<Complex<f64>> = x.iter().map(|&v| Complex::new(v, 0.0)).collect();
+            let mut output = vec![Complex::zero(); n];
+            self.plan.execute(&input, &mut output);
+            output.truncate(n / 2 + 1);
+            return output;
+        }
+        let m = n / 2;
+        let packed: Vec<Complex<f64>> = x.chunks_exact(2).map(|p| Complex::new(p[0], p[1])).collect();
+        let mut z = vec![Complex::zero(); m];
+        self.plan.execute(&packed, &mut z);
+        (0..=m)
+            .map(|k| {
+                let (a, b) = (z[k % m], z[(m - k) % m].conj());
+                let (even, odd) = ((a + b) * 0.5, (a - b) * 0.5);
+                // X[k] = E[k] + W^k O[k], with O[k] = -i * odd.
+                even + Complex::new(odd.im, -odd.re) * self.twiddles[k]
+            })
+            .collect()
+    }
+    /// Inverse of [`Self::forward`], normalized by `1 / n`. The imaginary parts of the DC and
+    /// Nyquist bins are ignored, as a real signal can't have them.
+    fn inverse(&self, bins: &[Complex<f64>]) -> Vec<f64> {
+        let n = self.n;
+        let mut bins = bins.to_vec();
+        bins[0].im = 0.0;
+        if self.twiddles.is_empty() {
+            let mut full = vec![Complex::zero(); n];
+            full[..bins.len()].copy_from_slice(&bins);
+            for i in 1..=(n - 1) / 2 {
+                full[n - i] = bins[i].conj();
+            }
+            let mut output = vec![Complex::zero(); n];
+            self.plan.execute(&full, &mut output);
+            return output.iter().map(|c| c.re / n as f64).collect();
+        }
+        let m = n / 2;
+        bins[m].im = 0.0;
+        let packed: Vec<Complex<f64>> = (0..m)
+            .map(|k| {
+                let (a, b) = (bins[k], bins[m - k].conj());
+                let even = (a + b) * 0.5;
+                let odd = (a - b) * 0.5 * self.twiddles[k].conj();
+                even + Complex::new(-odd.im, odd.re)
+            })
+            .collect();
+        let mut z = vec![Complex::zero(); m];
+        self.plan.execute(&packed, &mut z);
+        z.iter().flat_map(|c| [c.re / m as f64, c.im / m as f64]).collect()
+    }
 }
 pub fn stft_core(
     signal: &[f64],
     fft_size: usize,
     hop_size: usize,
+    window: WindowKind,
 ) -> Array2<Complex<f64>> {
     let freq_bins = fft_size / 2 + 1; 
     if fft_size == 0 || hop_size == 0 || signal.len() < fft_size {
         return Array2::from_shape_vec((freq_bins, 0), Vec::new()).unwrap();
     }
-    let window = get_hann_window(fft_size);
-    let plan = get_fft_plan(fft_size, Direction::Forward);
+    let window = get_window(fft_size, window);
+    let fft = RealFft::new(fft_size, Direction::Forward);
     let n_frames = (signal.len() - fft_size) / hop_size + 1;
     let mut spec = Array2::from_shape_fn((freq_bins, n_frames), |_| Complex::zero()); 
     let pool = get_default_pool();
@@ -49,37 +179,86 @@ pub fn stft_core(
     );
     pool.parallel_for(n_frames, |frame_idx| {
         let start = frame_idx * hop_size;
-        let input: Vec<Complex<f64>> = signal[start..start + fft_size]
+        let input: Vec<f64> = signal[start..start + fft_size]
             .iter()
-            .zip(window.iter())
-            .map(|(&s, &w)| Complex::new(s * w, 0.0))
+            .zip(window.coeffs.iter())
+            .map(|(&s, &w)| s * w)
             .collect();
-        let mut output = vec![Complex::zero(); fft_size];
-        plan.execute(&input, &mut output);
-        output.truncate(freq_bins); 
-        let _ = result[frame_idx].set(output);
+        let _ = result[frame_idx].set(fft.forward(&input));
     });
     for (frame_idx, once_result) in result.iter().enumerate() {
         spec.slice_mut(s![.., frame_idx]).assign(&ArrayView1::from(once_result.get().unwrap()));
     }
     spec
 }
+/// librosa's `center=True`: reflect-pads `fft_size / 2` on both sides so frame `i` is centred
+/// on sample `i * hop_size`, giving `1 + len / hop_size` frames.
+pub fn stft_centered(
+    signal: &[f64],
+    fft_size: usize,
+    hop_size: usize,
+    window: WindowKind,
+) -> Array2<Complex<f64>> {
+    if signal.len() < 2 {
+        return stft_core(signal, fft_size, hop_size, window);
+    }
+    let mut padded = signal.to_vec();
+    reflect_pad_1d(&mut padded, fft_size / 2, fft_size / 2);
+    stft_core(&padded, fft_size, hop_size, window)
+}
+/// Magnitudes only, from [`stft_centered`] with `center` and [`stft_core`] otherwise.
+pub fn stft_magnitude(
+    signal: &[f64],
+    fft_size: usize,
+    hop_size: usize,
+    window: WindowKind,
+    center: bool,
+) -> Array2<f64> {
+    let spec = if center {
+        stft_centered(signal, fft_size, hop_size, window)
+    } else {
+        stft_core(signal, fft_size, hop_size, window)
+    };
+    spec.mapv(|c| c.norm())
+}
+/// `stft_core` split into magnitude and phase (radians).
+pub fn stft_magnitude_phase(
+    signal: &[f64],
+    fft_size: usize,
+    hop_size: usize,
+    window: WindowKind,
+) -> (Array2<f64>, Array2<f64>) {
+    let spec = stft_core(signal, fft_size, hop_size, window);
+    (spec.mapv(|c| c.norm()), spec.mapv(|c| c.arg()))
+}
+/// Inverse of [`stft_magnitude_phase`]; `magnitude` and `phase` must have the same shape.
+pub fn istft_from_mag_phase(
+    magnitude: &Array2<f64>,
+    phase: &Array2<f64>,
+    target_len: usize,
+    fft_size: usize,
+    hop_size: usize,
+    window: WindowKind,
+) -> Vec<f64> {
+    let spec = Zip::from(magnitude).and(phase).map_collect(|&m, &p| Complex::from_polar(m, p));
+    istft_core(&spec, target_len, fft_size, hop_size, window)
+}
 pub fn istft_core(
     spec: &Array2<Complex<f64>>,
     target_len: usize,
     fft_size: usize,
     hop_size: usize,
+    window: WindowKind,
 ) -> Vec<f64> {
     let (freq_bins, n_frames) = (spec.nrows(), spec.ncols());
     if n_frames == 0 || freq_bins == 0 || freq_bins != fft_size / 2 + 1 {
         return vec![0.0; target_len];
     }
-    let window = get_hann_window(fft_size);
-    let plan = get_fft_plan(fft_size, Direction::Backward);
+    let window = get_window(fft_size, window);
+    let fft = RealFft::new(fft_size, Direction::Backward);
     let out_len = fft_size + (n_frames - 1) * hop_size;
     let mut output = vec![0.0; out_len];
     let mut win_sum = vec![0.0; out_len];
-    let scale = 1.0 / fft_size as f64;
     let pool = get_default_pool();
     let result: Arc<Vec<OnceCell<Vec<f64>>>> = Arc::new(
         (0..n_frames)
@@ -87,32 +266,21 @@ pub fn istft_core(
             .collect()
     );
     pool.parallel_for(n_frames, |frame_idx| {
-        let mut full_spec = vec![Complex::zero(); fft_size];
-        let mut frame = vec![Complex::zero(); fft_size];
-        let spec_slc = spec.slice(s![.., frame_idx]);
-        let spec_raw = match spec_slc.as_slice() {
-            Some(s) if s.len() == freq_bins => s,
-            _ => return, 
-        };
-        full_spec[0..freq_bins].copy_from_slice(spec_raw);
-        for i in 1..freq_bins - 1 {
-            full_spec[fft_size - i] = full_spec[i].conj();
-        }
-        plan.execute(&full_spec, &mut frame);
-        let ifft_result: Vec<f64> = frame
+        // Columns of a row-major spectrum aren't contiguous, so collect them.
+        let bins: Vec<Complex<f64>> = spec.column(frame_idx).to_vec();
+        let ifft_result: Vec<f64> = fft.inverse(&bins)
             .iter()
-            .zip(window.iter())
-            .map(|(frame_val, win_val)| frame_val.re * scale * win_val)
+            .zip(window.coeffs.iter())
+            .map(|(frame_val, win_val)| frame_val * win_val)
             .collect();
         let _ = result[frame_idx].set(ifft_result);
     });
-    let window_sq = ISTFT_WINDOW_SQ.as_ref();
     for (frame_idx, once_result) in result.iter().enumerate() {
         let res = once_result.get().unwrap();
         let start = frame_idx * hop_size;
         for i in 0..fft_size {
             output[start + i] += res[i];
-            win_sum[start + i] += window_sq[i];
+            win_sum[start + i] += window.squared[i];
         }
     }
     for i in 0..out_len {
@@ -122,4 +290,94 @@ pub fn istft_core(
     }
     output.resize(target_len, 0.0);
     output
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_round_trip_per_window() {
+        let (fft_size, hop) = (2048, 512);
+        let wave: Vec<f64> = (0..16384)
+            .map(|n| 0.5 * (std::f64::consts::TAU * 440. * n as f64 / 44100.).sin())
+            .collect();
+        for kind in [WindowKind::Hann, WindowKind::Hamming, WindowKind::BlackmanHarris] {
+            let spec = stft_core(&wave, fft_size, hop, kind);
+            let rebuilt = istft_core(&spec, wave.len(), fft_size, hop, kind);
+            // The first and last frames are only partly overlapped; compare the interior.
+            let err = wave[fft_size..wave.len() - fft_size].iter()
+                .zip(&rebuilt[fft_size..wave.len() - fft_size])
+                .map(|(a, b)| (a - b).abs())
+                .fold(0., f64::max);
+            assert!(err < 1e-9, "{:?}: max error {}", kind, err);
+        }
+    }
+    #[test]
+    fn test_real_fft_matches_complex() {
+        for n in [2048, 1934, 1723] {
+            let x: Vec<f64> = (0..n).map(|i| ((i * 7919) % 1000) as f64 / 500. - 1.).collect();
+            let input: Vec<Complex<f64>> = x.iter().map(|&v| Complex::new(v, 0.)).collect();
+            let mut reference = vec![Complex::zero(); n];
+            get_fft_plan(n, Direction::Forward).unwrap().execute(&input, &mut reference);
+            let bins = RealFft::new(n, Direction::Forward).forward(&x);
+            assert_eq!(bins.len(), n / 2 + 1);
+            for (a, b) in bins.iter().zip(&reference) {
+                assert!((*a - *b).norm() < 1e-9, "n={}", n);
+            }
+            let back = RealFft::new(n, Direction::Backward).inverse(&bins);
+            assert!(back.iter().zip(&x).all(|(a, b)| (a - b).abs() < 1e-12), "n={}", n);
+        }
+    }
+    #[test]
+    fn test_size_cache_is_bounded() {
+        let cache = SizeCache::new(4);
+        for size in 1100..1200usize {
+            let value = cache.get_or_insert(size, is_pinned(size), || size * 2);
+            assert_eq!(value, size * 2);
+        }
+        cache.get_or_insert(FFT_SIZE, true, || 0);
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.get_or_insert(FFT_SIZE, true, || 1), 0);
+        // Building runs outside the locks: a builder that reads the cache must not deadlock,
+        // and a failed build stores nothing.
+        assert_eq!(cache.get_or_insert(1, false, || cache.len()), 5);
+        assert_eq!(cache.get_or_insert(FFT_SIZE / 2, true, || cache.len()), 5);
+        assert_eq!(cache.get_or_try_insert(2, false, || None), None);
+        assert_eq!(cache.get_or_insert(2, false, || 7), 7);
+        // Many distinct key shifts through the real caches stay within the configured bound.
+        let wave = vec![0.1; 4096];
+        for size in (1500..2600).step_by(7) {
+            stft_core(&wave, size, 512, WindowKind::Hann);
+        }
+        let capacity = HIFI_CONFIG.fft_cache_entries.max(1);
+        assert!(FFT_PLANS.len() <= capacity + 4);
+        assert!(WINDOWS.len() <= capacity + 3);
+    }
+    #[test]
+    fn test_mag_phase_round_trip() {
+        let (fft_size, hop) = (2048, 512);
+        let wave: Vec<f64> = (0..12000)
+            .map(|n| 0.3 * (n as f64 * 0.05).sin() + 0.2 * (n as f64 * 0.31).cos())
+            .collect();
+        let (magnitude, phase) = stft_magnitude_phase(&wave, fft_size, hop, WindowKind::Hann);
+        assert_eq!(magnitude.dim(), phase.dim());
+        assert!(magnitude.iter().all(|&m| m >= 0.));
+        let rebuilt = istft_from_mag_phase(&magnitude, &phase, wave.len(), fft_size, hop, WindowKind::Hann);
+        let interior = fft_size..wave.len() - fft_size;
+        assert!(wave[interior.clone()].iter().zip(&rebuilt[interior]).all(|(a, b)| (a - b).abs() < 1e-9));
+    }
+    #[test]
+    fn test_centered_frames() {
+        let (fft_size, hop) = (2048, 512);
+        let wave: Vec<f64> = (0..10000).map(|n| (n as f64 * 0.01).sin() + n as f64 * 1e-4).collect();
+        let plain = stft_core(&wave, fft_size, hop, WindowKind::Hann);
+        let centered = stft_centered(&wave, fft_size, hop, WindowKind::Hann);
+        assert_eq!(plain.ncols(), (wave.len() - fft_size) / hop + 1);
+        assert_eq!(centered.ncols(), 1 + wave.len() / hop);
+        // Centred frame `k` starts `fft_size / 2` samples earlier, so it lines up with plain frame
+        // `k - fft_size / (2 * hop)`.
+        let lag = fft_size / (2 * hop);
+        for (c, p) in centered.column(lag).iter().zip(plain.column(0)) {
+            assert!((*c - *p).norm() < 1e-9);
+        }
+    }
+}